@@ -1,4 +1,6 @@
 const { execSync } = require('child_process');
+const crypto = require('crypto');
+const fs = require('fs');
 
 function buildWasm(pkg, buildCommand, history_suffix, target_dir) {
     buildCommand.push(pkg);
@@ -19,6 +21,35 @@ function buildWasm(pkg, buildCommand, history_suffix, target_dir) {
     execSync(optCommand.join(' '));
 }
 
+// The cycles wallet installed by wallet_create_wallet is the one shipped with the dfx version
+// pinned in dfx.json, taken from its cache unless the WALLET_WASM environment variable points to
+// a copy of it. The build fails unless its sha256 matches the one committed in wallet.sha256 for
+// that dfx version, the hash is never taken from the wallet being copied.
+function copyWalletWasm() {
+    const version = JSON.parse(fs.readFileSync('dfx.json')).dfx;
+    const source = process.env.WALLET_WASM
+        || `${execSync('dfx cache show', { env: { ...process.env, DFX_VERSION: version } }).toString().trim()}/wallet.wasm`;
+
+    console.log(`Copying the cycles wallet of dfx ${version} from ${source}`);
+    const wasm = fs.readFileSync(source);
+    const hash = crypto.createHash('sha256').update(wasm).digest('hex');
+
+    const pinned = fs.existsSync('wallet.sha256')
+        ? fs.readFileSync('wallet.sha256').toString().split('\n')
+            .map(line => line.trim().split(/\s+/))
+            .find(([pinnedVersion]) => pinnedVersion === version)
+        : undefined;
+    if (!pinned) {
+        throw new Error(`No cycles wallet is pinned for dfx ${version} in wallet.sha256.`);
+    }
+    if (hash !== pinned[1]) {
+        throw new Error(`The cycles wallet at ${source} has the sha256 ${hash}, expected ${pinned[1]}.`);
+    }
+
+    fs.mkdirSync('target/wasm32-unknown-unknown', { recursive: true });
+    fs.writeFileSync('target/wasm32-unknown-unknown/wallet.wasm', wasm);
+}
+
 let buildType = (process.env.BUILD_TYPE || "Release").toUpperCase();
 console.log(`Building in ** ${buildType} ** mode`);
 
@@ -57,4 +88,7 @@ switch (buildType)
 
 buildWasm('xtc-history-bucket', [...buildCommand], history_suffix, target_dir);
 buildWasm('xtc-history-e2e', [...buildCommand], history_suffix, target_dir);
+// XTC embeds the drain module and the cycles wallet, they must be in place before it is built.
+buildWasm('xtc-drain', [...buildCommand], history_suffix, target_dir);
+copyWalletWasm();
 buildWasm('xtc', [...buildCommand], history_suffix, target_dir);
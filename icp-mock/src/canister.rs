@@ -0,0 +1,44 @@
+//! The exported methods of the mock canister, the arguments are passed through raw so the
+//! protobuf endpoints keep their encoding.
+
+use crate::IcpMock;
+use ic_cdk::api::call::{arg_data_raw, reply_raw};
+use ic_kit::ic;
+
+fn dispatch(method: &str) {
+    let args = arg_data_raw();
+    match ic::get_mut::<IcpMock>().handle(ic::caller(), method, &args, ic::time()) {
+        Ok(reply) => reply_raw(&reply),
+        Err(e) => ic_cdk::api::trap(&e),
+    }
+}
+
+#[export_name = "canister_query block_pb"]
+fn block_pb() {
+    dispatch("block_pb")
+}
+
+#[export_name = "canister_update send_dfx"]
+fn send_dfx() {
+    dispatch("send_dfx")
+}
+
+#[export_name = "canister_query account_balance_dfx"]
+fn account_balance_dfx() {
+    dispatch("account_balance_dfx")
+}
+
+#[export_name = "canister_update notify_top_up"]
+fn notify_top_up() {
+    dispatch("notify_top_up")
+}
+
+#[export_name = "canister_query get_icp_xdr_conversion_rate"]
+fn get_icp_xdr_conversion_rate() {
+    dispatch("get_icp_xdr_conversion_rate")
+}
+
+#[export_name = "canister_update mock_transfer"]
+fn mock_transfer() {
+    dispatch("mock_transfer")
+}
@@ -0,0 +1,239 @@
+//! A stand-in for the ICP ledger and the cycles minting canister, implementing the subset of
+//! their interfaces XTC uses to mint from ICP.
+//!
+//! The same implementation backs the `icp-mock` canister for local deployments and the
+//! `MockContext` handler returned by [`handler`] for unit tests, so both see the exact protobuf
+//! and candid encodings of the real canisters.
+
+use cycles_minting_canister::{
+    IcpXdrConversionRate, IcpXdrConversionRateCertifiedResponse, TokensToCycles,
+    DEFAULT_CYCLES_PER_XDR,
+};
+use dfn_protobuf::ProtoBuf;
+use ic_kit::candid::{decode_args, encode_args, CandidType, Nat, Principal};
+use ic_types::PrincipalId;
+use ledger_canister::{
+    account_identifier::{AccountIdentifier, Subaccount},
+    tokens::Tokens,
+    AccountBalanceArgs, Block, BlockHeight, BlockRes, EncodedBlock, Memo, Operation, SendArgs,
+    TimeStamp,
+};
+use on_wire::{FromWire, IntoWire};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::{Duration, UNIX_EPOCH};
+
+#[cfg(not(target_family = "wasm"))]
+use ic_kit::{Context, RawHandler};
+#[cfg(not(target_family = "wasm"))]
+use std::{cell::RefCell, rc::Rc};
+
+#[cfg(target_family = "wasm")]
+mod canister;
+
+/// The arguments of `mock_transfer`, records an ICP transfer in the mock ledger.
+#[derive(CandidType, Deserialize)]
+pub struct TransferArgs {
+    pub from: Principal,
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Principal,
+    pub to_subaccount: Option<Subaccount>,
+    pub amount: u64,
+}
+
+/// The arguments of `notify_top_up`.
+#[derive(CandidType, Deserialize)]
+pub struct NotifyTopUpArg {
+    pub block_index: BlockHeight,
+    pub canister_id: Principal,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum NotifyError {
+    Refunded {
+        reason: String,
+        block_index: Option<BlockHeight>,
+    },
+    Processing,
+    TransactionTooOld(BlockHeight),
+    InvalidTransaction(String),
+    Other {
+        error_code: u64,
+        error_message: String,
+    },
+}
+
+pub struct IcpMock {
+    blocks: Vec<EncodedBlock>,
+    balances: HashMap<AccountIdentifier, u64>,
+    /// The rate returned by `get_icp_xdr_conversion_rate`.
+    pub xdr_permyriad_per_icp: u64,
+    /// The time the rate was set at, the time of the call if None.
+    pub rate_timestamp_seconds: Option<u64>,
+    /// The number of upcoming `notify_top_up` calls to reject.
+    pub notify_failures: u32,
+    /// The cycles `notify_top_up` reports, the ICP of the block at the rate if None.
+    pub notify_cycles: Option<u64>,
+}
+
+impl Default for IcpMock {
+    fn default() -> Self {
+        Self {
+            blocks: vec![],
+            balances: HashMap::new(),
+            // 1 ICP = 1 XDR = 1T cycles.
+            xdr_permyriad_per_icp: 10_000,
+            rate_timestamp_seconds: None,
+            notify_failures: 0,
+            notify_cycles: None,
+        }
+    }
+}
+
+impl IcpMock {
+    /// Append a transfer block to the ledger and return its height.
+    pub fn transfer(
+        &mut self,
+        from: AccountIdentifier,
+        to: AccountIdentifier,
+        amount: Tokens,
+        fee: Tokens,
+        timestamp: u64,
+    ) -> BlockHeight {
+        let balance = self.balances.entry(from).or_default();
+        *balance = balance.saturating_sub(amount.get_e8s() + fee.get_e8s());
+        *self.balances.entry(to).or_default() += amount.get_e8s();
+
+        self.push(
+            Operation::Transfer {
+                from,
+                to,
+                amount,
+                fee,
+            },
+            timestamp,
+        )
+    }
+
+    /// Append a mint block to the ledger and return its height.
+    pub fn mint(&mut self, to: AccountIdentifier, amount: Tokens, timestamp: u64) -> BlockHeight {
+        *self.balances.entry(to).or_default() += amount.get_e8s();
+        self.push(Operation::Mint { to, amount }, timestamp)
+    }
+
+    pub fn balance(&self, account: &AccountIdentifier) -> Tokens {
+        Tokens::from_e8s(self.balances.get(account).copied().unwrap_or(0))
+    }
+
+    /// Return the cycles topped up for the ICP sent in the given block.
+    fn top_up(&self, block_index: BlockHeight) -> Result<Nat, NotifyError> {
+        let block = self
+            .blocks
+            .get(block_index as usize)
+            .and_then(|block| block.decode().ok())
+            .ok_or_else(|| NotifyError::InvalidTransaction("The block does not exist.".into()))?;
+        let amount = match block.transaction.operation {
+            Operation::Transfer { amount, .. } => amount,
+            _ => {
+                return Err(NotifyError::InvalidTransaction(
+                    "The block is not a transfer.".into(),
+                ))
+            }
+        };
+
+        let cycles = self.notify_cycles.unwrap_or_else(|| {
+            (TokensToCycles {
+                xdr_permyriad_per_icp: self.xdr_permyriad_per_icp,
+                cycles_per_xdr: DEFAULT_CYCLES_PER_XDR.into(),
+            })
+            .to_cycles(amount)
+            .into()
+        });
+        Ok(Nat::from(cycles))
+    }
+
+    fn push(&mut self, operation: Operation, timestamp: u64) -> BlockHeight {
+        let timestamp = TimeStamp::from(UNIX_EPOCH + Duration::from_nanos(timestamp));
+        let block = Block::new(None, operation, Memo(0), timestamp, timestamp)
+            .and_then(Block::encode)
+            .expect("Failed to encode block.");
+        self.blocks.push(block);
+        (self.blocks.len() - 1) as BlockHeight
+    }
+
+    /// Handle a call to one of the mocked methods, `caller` is the principal sending the ICP
+    /// for `send_dfx` and `timestamp` is the time of the new blocks.
+    pub fn handle(
+        &mut self,
+        caller: Principal,
+        method: &str,
+        args: &[u8],
+        timestamp: u64,
+    ) -> Result<Vec<u8>, String> {
+        match method {
+            "block_pb" => {
+                let ProtoBuf(height) = ProtoBuf::<BlockHeight>::from_bytes(args.to_vec())?;
+                let block = self.blocks.get(height as usize).cloned().map(Ok);
+                ProtoBuf(BlockRes(block)).into_bytes()
+            }
+            "send_dfx" => {
+                let (args,): (SendArgs,) = decode_args(args).map_err(|e| e.to_string())?;
+                let from = AccountIdentifier::new(PrincipalId::from(caller), args.from_subaccount);
+                if self.balance(&from).get_e8s() < args.amount.get_e8s() + args.fee.get_e8s() {
+                    return Err("Insufficient funds.".into());
+                }
+                let height = self.transfer(from, args.to, args.amount, args.fee, timestamp);
+                encode_args((height,)).map_err(|e| e.to_string())
+            }
+            "account_balance_dfx" => {
+                let (args,): (AccountBalanceArgs,) =
+                    decode_args(args).map_err(|e| e.to_string())?;
+                encode_args((self.balance(&args.account),)).map_err(|e| e.to_string())
+            }
+            "notify_top_up" => {
+                let (args,): (NotifyTopUpArg,) = decode_args(args).map_err(|e| e.to_string())?;
+                if self.notify_failures > 0 {
+                    self.notify_failures -= 1;
+                    return Err("The cycles minting canister is unavailable.".into());
+                }
+                encode_args((self.top_up(args.block_index),)).map_err(|e| e.to_string())
+            }
+            "get_icp_xdr_conversion_rate" => {
+                let response = IcpXdrConversionRateCertifiedResponse {
+                    data: IcpXdrConversionRate {
+                        timestamp_seconds: self
+                            .rate_timestamp_seconds
+                            .unwrap_or(timestamp / 1_000_000_000),
+                        xdr_permyriad_per_icp: self.xdr_permyriad_per_icp,
+                    },
+                    hash_tree: vec![],
+                    certificate: vec![],
+                };
+                encode_args((response,)).map_err(|e| e.to_string())
+            }
+            "mock_transfer" => {
+                let (args,): (TransferArgs,) = decode_args(args).map_err(|e| e.to_string())?;
+                let height = self.transfer(
+                    AccountIdentifier::new(PrincipalId::from(args.from), args.from_subaccount),
+                    AccountIdentifier::new(PrincipalId::from(args.to), args.to_subaccount),
+                    Tokens::from_e8s(args.amount),
+                    Tokens::from_e8s(10_000),
+                    timestamp,
+                );
+                encode_args((height,)).map_err(|e| e.to_string())
+            }
+            _ => Err(format!("Method {} is not supported by the mock.", method)),
+        }
+    }
+}
+
+/// Create a `MockContext` handler answering the ledger and cycles minting canister calls
+/// from the given mock, the calls are made by the canister of the context.
+#[cfg(not(target_family = "wasm"))]
+pub fn handler(mock: Rc<RefCell<IcpMock>>) -> RawHandler {
+    RawHandler::raw(Box::new(move |ctx, args, _, method| {
+        mock.borrow_mut()
+            .handle(ctx.id(), method, args, ctx.time())
+            .map_err(|e| (ic_kit::RejectionCode::CanisterReject, e))
+    }))
+}
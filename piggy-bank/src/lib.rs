@@ -8,6 +8,8 @@ struct PerformMintArgs {
     canister: Principal,
     account: Option<Principal>,
     cycles: u64,
+    /// The amount to mint from the cycles, the rest is refunded. Mints all the cycles if None.
+    amount: Option<u64>,
 }
 
 #[derive(CandidType, Debug, Deserialize, Eq, PartialEq)]
@@ -20,7 +22,13 @@ pub enum TxError {
     ErrorTo,
     Other,
     BlockUsed,
+    FetchRateFailed,
+    NotifyDfxFailed,
+    UnexpectedCyclesResponse,
     AmountTooSmall,
+    InsufficientXTCFee,
+    StaleRate,
+    InsufficientCycles,
 }
 
 pub type TxReceipt = Result<Nat, TxError>;
@@ -39,7 +47,12 @@ async fn perform_mint(args: PerformMintArgs) -> TxReceipt {
     }
 
     match ic
-        .call_with_payment(args.canister, "mint", (account, Nat::from(0)), args.cycles)
+        .call_with_payment(
+            args.canister,
+            "mint",
+            (account, Nat::from(args.amount.unwrap_or(0))),
+            args.cycles,
+        )
         .await
     {
         Ok((r,)) => r,
@@ -85,7 +98,8 @@ mod tests {
             perform_mint(PerformMintArgs {
                 canister: Principal::management_canister(),
                 account: None,
-                cycles: 300
+                cycles: 300,
+                amount: None
             })
             .await,
             Ok(Nat::from(17))
@@ -105,11 +119,33 @@ mod tests {
             perform_mint(PerformMintArgs {
                 canister: Principal::management_canister(),
                 account: Some(bob),
-                cycles: 140
+                cycles: 140,
+                amount: None
             })
             .await,
             Ok(Nat::from(18))
         );
+
+        MockContext::new()
+            .with_caller(alice.clone())
+            .with_handler(
+                Method::new()
+                    .expect_cycles(500)
+                    .response::<TxReceipt>(Ok(Nat::from(19)))
+                    .expect_arguments((alice, Nat::from(200))),
+            )
+            .inject();
+
+        assert_eq!(
+            perform_mint(PerformMintArgs {
+                canister: Principal::management_canister(),
+                account: None,
+                cycles: 500,
+                amount: Some(200)
+            })
+            .await,
+            Ok(Nat::from(19))
+        );
     }
 
     #[async_test]
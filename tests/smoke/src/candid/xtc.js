@@ -13,6 +13,8 @@ const idlFactory = ({ IDL }) => {
     'FetchRateFailed' : IDL.Null,
     'BlockUsed' : IDL.Null,
     'AmountTooSmall' : IDL.Null,
+    'StaleRate' : IDL.Null,
+    'InsufficientCycles' : IDL.Null,
   });
   const TxReceipt = IDL.Variant({ 'Ok' : IDL.Nat, 'Err' : TxError });
   const TransactionId = IDL.Nat64;
@@ -75,6 +77,7 @@ const idlFactory = ({ IDL }) => {
     'canisterCalled' : IDL.Null,
     'transfer' : IDL.Null,
     'canisterCreated' : IDL.Null,
+    'feeCollected' : IDL.Null,
   });
   const Time = IDL.Int;
   const TxRecord = IDL.Record({
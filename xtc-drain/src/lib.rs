@@ -0,0 +1,59 @@
+//! The module XTC installs on a canister right before deleting it. It sends the cycles of the
+//! canister to the `wallet_receive` method of XTC, so they are credited to the XTC balance of
+//! the controller deleting the canister instead of being lost with it.
+
+use ic_cdk::api::call::{arg_data_raw, call_raw, msg_cycles_refunded};
+use ic_cdk::api::{caller, canister_balance, trap};
+use ic_cdk::export::Principal;
+use ic_cdk::storage;
+use ic_cdk_macros::*;
+
+/// The cycles kept by the canister to pay for the call to XTC, they are burned when the
+/// canister is deleted.
+const RESERVE: u64 = 1_000_000_000;
+
+#[derive(Default)]
+struct Drain {
+    /// The canister that installed the module.
+    installer: Option<Principal>,
+    /// The encoded arguments of the `wallet_receive` call, passed as the install argument.
+    receive_args: Vec<u8>,
+}
+
+#[export_name = "canister_init"]
+fn init() {
+    let drain = storage::get_mut::<Drain>();
+    drain.installer = Some(caller());
+    drain.receive_args = arg_data_raw();
+}
+
+/// Send the cycles of the canister above the reserve to its installer, returns the balance of
+/// the canister before the drain and the cycles accepted by the installer. Fails if the installer
+/// rejects them, which happens when they do not cover its fee or minting is paused, the cycles
+/// then stay in the canister.
+#[update]
+async fn drain() -> Result<(u64, u64), String> {
+    let drain = storage::get::<Drain>();
+    let installer = match &drain.installer {
+        Some(installer) if *installer == caller() => installer.clone(),
+        _ => trap("Only the installer can drain the canister."),
+    };
+
+    let balance = canister_balance();
+    let cycles = balance.saturating_sub(RESERVE);
+    if cycles == 0 {
+        return Ok((balance, 0));
+    }
+
+    match call_raw(
+        installer,
+        "wallet_receive",
+        drain.receive_args.clone(),
+        cycles,
+    )
+    .await
+    {
+        Ok(_) => Ok((balance, cycles - msg_cycles_refunded())),
+        Err((code, msg)) => Err(format!("XTC rejected the cycles: {}: {}", code as u8, msg)),
+    }
+}
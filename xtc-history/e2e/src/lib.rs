@@ -37,6 +37,7 @@ async fn insert(count: u32, progress: bool) {
             fee: 0,
             kind: TransactionKind::Mint {
                 to: Principal::anonymous(),
+                to_subaccount: None,
             },
             status: TransactionStatus::SUCCEEDED,
         };
@@ -58,7 +59,7 @@ async fn get_transaction(id: TransactionId) -> Option<Transaction> {
 }
 
 #[query]
-fn events(args: EventsArgs) -> EventsConnection<'static> {
+fn events(args: EventsArgs) -> EventsConnection {
     let offset = args.offset;
     let limit = args.limit.min(512);
 
@@ -71,12 +71,12 @@ fn append(mut events: Vec<Transaction>) {
 }
 
 #[query]
-fn get_transaction(id: TransactionId) -> Option<&'static Transaction> {
+fn get_transaction(id: TransactionId) -> Option<Transaction> {
     storage::get::<Data>().bucket.get_transaction(id)
 }
 
 #[query]
-fn events(args: EventsArgs) -> EventsConnection<'static> {
+fn events(args: EventsArgs) -> EventsConnection {
     storage::get::<Data>()
         .bucket
         .events(args.offset, args.limit as usize, || id())
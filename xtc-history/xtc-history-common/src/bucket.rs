@@ -1,5 +1,64 @@
 use crate::types::*;
 use ic_cdk::export::candid::Principal;
+use std::marker::PhantomData;
+
+/// The storage behind the events of a bucket, the indexes are local to the bucket and start
+/// from zero for the oldest event.
+pub trait EventStorage<Event> {
+    /// Return the number of events in the storage.
+    fn len(&self) -> usize;
+
+    /// Return the event at the given index.
+    fn get(&self, index: usize) -> Option<Event>;
+
+    /// Return the events in the range start..end, sorted from oldest to newest.
+    fn range(&self, start: usize, end: usize) -> Vec<Event>;
+
+    /// Push a single event to the end of the storage.
+    fn push(&mut self, event: Event);
+
+    /// Append a vector of events sorted from oldest to newest, leaving the vector empty.
+    fn append(&mut self, other: &mut Vec<Event>) {
+        for event in other.drain(..) {
+            self.push(event);
+        }
+    }
+
+    /// Remove the first n events.
+    fn remove_first(&mut self, n: usize);
+}
+
+impl<Event: Clone> EventStorage<Event> for Vec<Event> {
+    #[inline]
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+
+    #[inline]
+    fn get(&self, index: usize) -> Option<Event> {
+        <[Event]>::get(self, index).cloned()
+    }
+
+    #[inline]
+    fn range(&self, start: usize, end: usize) -> Vec<Event> {
+        self[start..end].to_vec()
+    }
+
+    #[inline]
+    fn push(&mut self, event: Event) {
+        Vec::push(self, event);
+    }
+
+    #[inline]
+    fn append(&mut self, other: &mut Vec<Event>) {
+        Vec::append(self, other);
+    }
+
+    #[inline]
+    fn remove_first(&mut self, n: usize) {
+        self.drain(0..n);
+    }
+}
 
 /// A single knot in the history chain. This structure is responsible for storing a list of
 /// events that start from a constant index called the bucket's offset, and provide API to
@@ -8,11 +67,12 @@ use ic_cdk::export::candid::Principal;
 /// uses it to direct callers to the next bucket when paginating through the events and hit
 /// the end of the buffer.
 /// It is used to both store the events on the main canister as well as the archive canisters.
-pub struct BucketData<Address = Principal, Event = Transaction> {
+pub struct BucketData<Address = Principal, Event = Transaction, Events = Vec<Event>> {
     /// The events in this bucket, smaller index means older data.
-    events: Vec<Event>,
+    events: Events,
     /// The metadata for this bucket.
     metadata: Option<BucketMetadata<Address>>,
+    event: PhantomData<Event>,
 }
 
 pub struct BucketMetadata<Address> {
@@ -23,30 +83,34 @@ pub struct BucketMetadata<Address> {
     pub next: Option<Address>,
 }
 
-impl<Address, Event> Default for BucketData<Address, Event> {
+impl<Address, Event, Events: Default> Default for BucketData<Address, Event, Events> {
     fn default() -> Self {
         Self {
-            events: Vec::new(),
+            events: Events::default(),
             metadata: None,
+            event: PhantomData,
         }
     }
 }
 
-impl<Address, Event> BucketData<Address, Event> {
+impl<Address, Event> BucketData<Address, Event, Vec<Event>> {
+    /// Pre-reserve space for the given number of transactions.
+    #[inline]
+    pub fn reserve(&mut self, capacity: usize) {
+        self.events.reserve(capacity);
+    }
+}
+
+impl<Address, Event, Events: EventStorage<Event>> BucketData<Address, Event, Events> {
     /// Create a new bucket with the given data.
-    pub fn new(offset: TransactionId, events: Vec<Event>) -> Self {
+    pub fn new(offset: TransactionId, events: Events) -> Self {
         BucketData {
             events,
             metadata: Some(BucketMetadata { offset, next: None }),
+            event: PhantomData,
         }
     }
 
-    /// Pre-reserve space for the given number of transactions.
-    #[inline]
-    pub fn reserve(&mut self, capacity: usize) {
-        self.events.reserve(capacity);
-    }
-
     /// Set the metadata for this bucket.
     /// # Panics
     /// If the metadata is already set.
@@ -83,7 +147,7 @@ impl<Address, Event> BucketData<Address, Event> {
     /// Return the given transaction from this bucket, None is returned when the transaction
     /// is not found in this bucket.
     #[inline]
-    pub fn get_transaction(&self, id: TransactionId) -> Option<&Event> {
+    pub fn get_transaction(&self, id: TransactionId) -> Option<Event> {
         let index = match id.checked_sub(self.get_offset()) {
             Some(index) => index,
             None => return None,
@@ -133,10 +197,10 @@ impl<Address, Event> BucketData<Address, Event> {
         let take = limit + 1;
         let end = (offset - bucket_offset) as usize;
         let start = end.checked_sub(take).unwrap_or(0);
-        let mut data: &[Event] = &self.events[start..end];
+        let mut data = self.events.range(start, end);
 
         let has_more = if data.len() > limit {
-            data = &data[1..];
+            data.remove(0);
             true
         } else {
             false
@@ -151,8 +215,10 @@ impl<Address, Event> BucketData<Address, Event> {
             (None, 0)
         };
 
+        data.reverse();
+
         EventsConnection {
-            data: data.into_iter().rev().collect(),
+            data,
             next_offset,
             next_canister_id,
         }
@@ -195,12 +261,19 @@ impl<Address, Event> BucketData<Address, Event> {
         n = n.min(self.events.len());
         let metadata = self.metadata.as_mut().unwrap();
         metadata.offset += n as u64;
-        self.events.drain(0..n);
+        self.events.remove_first(n);
+    }
+
+    /// Consume the bucket and return the storage of its events.
+    pub fn into_events(self) -> Events {
+        self.events
     }
 
-    /// Return the events in this bucket.
-    pub fn get_events(&self) -> &Vec<Event> {
-        &self.events
+    /// Return the events in the range start..end of this bucket, the indexes are local to
+    /// this bucket.
+    pub fn get_events(&self, start: usize, end: usize) -> Vec<Event> {
+        let end = end.min(self.events.len());
+        self.events.range(start.min(end), end)
     }
 }
 
@@ -211,10 +284,10 @@ mod tests {
     #[test]
     fn get_transaction_from_offset_zero() {
         let bucket = BucketData::<u32, u32>::new(0, vec![0, 1, 2, 3]);
-        assert_eq!(bucket.get_transaction(0), Some(&0));
-        assert_eq!(bucket.get_transaction(1), Some(&1));
-        assert_eq!(bucket.get_transaction(2), Some(&2));
-        assert_eq!(bucket.get_transaction(3), Some(&3));
+        assert_eq!(bucket.get_transaction(0), Some(0));
+        assert_eq!(bucket.get_transaction(1), Some(1));
+        assert_eq!(bucket.get_transaction(2), Some(2));
+        assert_eq!(bucket.get_transaction(3), Some(3));
         assert_eq!(bucket.get_transaction(4), None);
     }
 
@@ -222,9 +295,9 @@ mod tests {
     fn get_transaction() {
         let bucket = BucketData::<u32, u32>::new(1, vec![1, 2, 3]);
         assert_eq!(bucket.get_transaction(0), None);
-        assert_eq!(bucket.get_transaction(1), Some(&1));
-        assert_eq!(bucket.get_transaction(2), Some(&2));
-        assert_eq!(bucket.get_transaction(3), Some(&3));
+        assert_eq!(bucket.get_transaction(1), Some(1));
+        assert_eq!(bucket.get_transaction(2), Some(2));
+        assert_eq!(bucket.get_transaction(3), Some(3));
         assert_eq!(bucket.get_transaction(4), None);
     }
 
@@ -234,37 +307,37 @@ mod tests {
         let bucket = BucketData::<u32, u32>::new(0, events);
 
         let res = bucket.events(None, 3, || 17);
-        assert_eq!(res.data, vec![&10, &9, &8]);
+        assert_eq!(res.data, vec![10, 9, 8]);
         assert_eq!(res.next_offset, 8);
         assert_eq!(res.next_canister_id, Some(17));
 
         let res = bucket.events(Some(11), 3, || 17);
-        assert_eq!(res.data, vec![&10, &9, &8]);
+        assert_eq!(res.data, vec![10, 9, 8]);
         assert_eq!(res.next_offset, 8);
         assert_eq!(res.next_canister_id, Some(17));
 
         let res = bucket.events(Some(res.next_offset), 3, || 17);
-        assert_eq!(res.data, vec![&7, &6, &5]);
+        assert_eq!(res.data, vec![7, 6, 5]);
         assert_eq!(res.next_offset, 5);
         assert_eq!(res.next_canister_id, Some(17));
 
         let res = bucket.events(Some(res.next_offset), 3, || 17);
-        assert_eq!(res.data, vec![&4, &3, &2]);
+        assert_eq!(res.data, vec![4, 3, 2]);
         assert_eq!(res.next_offset, 2);
         assert_eq!(res.next_canister_id, Some(17));
 
         let res = bucket.events(Some(res.next_offset), 3, || 17);
-        assert_eq!(res.data, vec![&1, &0]);
+        assert_eq!(res.data, vec![1, 0]);
         assert_eq!(res.next_offset, 0);
         assert_eq!(res.next_canister_id, None);
 
         let res = bucket.events(Some(1), 3, || 17);
-        assert_eq!(res.data, vec![&0]);
+        assert_eq!(res.data, vec![0]);
         assert_eq!(res.next_offset, 0);
         assert_eq!(res.next_canister_id, None);
 
         let res = bucket.events(Some(0), 3, || 17);
-        assert_eq!(res.data, Vec::<&u32>::new());
+        assert_eq!(res.data, Vec::<u32>::new());
         assert_eq!(res.next_offset, 0);
         assert_eq!(res.next_canister_id, None);
     }
@@ -275,30 +348,30 @@ mod tests {
         let mut bucket = BucketData::<u32, u32>::new(11, events);
         bucket.update_next(Some(16));
 
-        assert_eq!(bucket.get_transaction(11), Some(&11));
+        assert_eq!(bucket.get_transaction(11), Some(11));
 
         let res = bucket.events(None, 3, || 17);
-        assert_eq!(res.data, vec![&20, &19, &18]);
+        assert_eq!(res.data, vec![20, 19, 18]);
         assert_eq!(res.next_offset, 18);
         assert_eq!(res.next_canister_id, Some(17));
 
         let res = bucket.events(Some(res.next_offset), 3, || 17);
-        assert_eq!(res.data, vec![&17, &16, &15]);
+        assert_eq!(res.data, vec![17, 16, 15]);
         assert_eq!(res.next_offset, 15);
         assert_eq!(res.next_canister_id, Some(17));
 
         let res = bucket.events(Some(res.next_offset), 3, || 17);
-        assert_eq!(res.data, vec![&14, &13, &12]);
+        assert_eq!(res.data, vec![14, 13, 12]);
         assert_eq!(res.next_offset, 12);
         assert_eq!(res.next_canister_id, Some(17));
 
         let res = bucket.events(Some(res.next_offset), 3, || 17);
-        assert_eq!(res.data, vec![&11]);
+        assert_eq!(res.data, vec![11]);
         assert_eq!(res.next_offset, 11);
         assert_eq!(res.next_canister_id, Some(16));
 
         let res = bucket.events(Some(13), 3, || 17);
-        assert_eq!(res.data, vec![&12, &11]);
+        assert_eq!(res.data, vec![12, 11]);
         assert_eq!(res.next_offset, 11);
         assert_eq!(res.next_canister_id, Some(16));
 
@@ -308,7 +381,7 @@ mod tests {
         let bucket = BucketData::<u32, u32>::new(0, events);
 
         let res = bucket.events(Some(11), 3, || 16);
-        assert_eq!(res.data, vec![&10, &9, &8]);
+        assert_eq!(res.data, vec![10, 9, 8]);
         assert_eq!(res.next_offset, 8);
         assert_eq!(res.next_canister_id, Some(16));
     }
@@ -328,21 +401,21 @@ mod tests {
     fn remove_first() {
         let events = (0..=20).into_iter().collect();
         let mut bucket = BucketData::<u32, u32>::new(0, events);
-        assert_eq!(bucket.get_transaction(0), Some(&0));
-        assert_eq!(bucket.get_transaction(1), Some(&1));
+        assert_eq!(bucket.get_transaction(0), Some(0));
+        assert_eq!(bucket.get_transaction(1), Some(1));
         bucket.remove_first(5);
         assert_eq!(bucket.get_transaction(0), None);
         assert_eq!(bucket.get_transaction(1), None);
         assert_eq!(bucket.get_transaction(4), None);
-        assert_eq!(bucket.get_transaction(5), Some(&5));
-        assert_eq!(bucket.get_transaction(6), Some(&6));
+        assert_eq!(bucket.get_transaction(5), Some(5));
+        assert_eq!(bucket.get_transaction(6), Some(6));
         bucket.remove_first(5);
         assert_eq!(bucket.get_transaction(0), None);
         assert_eq!(bucket.get_transaction(1), None);
         assert_eq!(bucket.get_transaction(4), None);
         assert_eq!(bucket.get_transaction(5), None);
         assert_eq!(bucket.get_transaction(6), None);
-        assert_eq!(bucket.get_transaction(10), Some(&10));
+        assert_eq!(bucket.get_transaction(10), Some(10));
     }
 
     #[test]
@@ -351,17 +424,17 @@ mod tests {
         let bucket = BucketData::<u32, u32>::new(0, events);
 
         let res = bucket.events(Some(5), 3, || 17);
-        assert_eq!(res.data, vec![&4, &3, &2]);
+        assert_eq!(res.data, vec![4, 3, 2]);
         assert_eq!(res.next_offset, 2);
         assert_eq!(res.next_canister_id, Some(17));
 
         let res = bucket.events(Some(6), 3, || 17);
-        assert_eq!(res.data, vec![&4, &3]);
+        assert_eq!(res.data, vec![4, 3]);
         assert_eq!(res.next_offset, 3);
         assert_eq!(res.next_canister_id, Some(17));
 
         let res = bucket.events(Some(7), 3, || 17);
-        assert_eq!(res.data, vec![&4]);
+        assert_eq!(res.data, vec![4]);
         assert_eq!(res.next_offset, 4);
         assert_eq!(res.next_canister_id, Some(17));
 
@@ -2,37 +2,84 @@ use ic_cdk::export::candid::CandidType;
 use ic_cdk::export::Principal;
 use serde::Deserialize;
 
+/// A 32-byte subaccount of a principal on the ledger, a missing subaccount refers to the
+/// default subaccount.
+#[derive(
+    CandidType, Clone, Copy, Deserialize, Hash, Eq, PartialEq, Ord, PartialOrd, Debug, Default,
+)]
+pub struct Subaccount(pub [u8; 32]);
+
 #[derive(CandidType, Clone, Deserialize, PartialOrd, PartialEq, Debug)]
 pub enum TransactionKind {
     Transfer {
         from: Principal,
         to: Principal,
+        from_subaccount: Option<Subaccount>,
+        to_subaccount: Option<Subaccount>,
     },
     Mint {
         to: Principal,
+        to_subaccount: Option<Subaccount>,
     },
     Burn {
         from: Principal,
         to: Principal,
+        from_subaccount: Option<Subaccount>,
     },
     CanisterCalled {
         from: Principal,
         canister: Principal,
         method_name: String,
+        from_subaccount: Option<Subaccount>,
     },
     CanisterCreated {
         from: Principal,
         canister: Principal,
+        from_subaccount: Option<Subaccount>,
     },
     TransferFrom {
         caller: Principal,
         from: Principal,
         to: Principal,
+        from_subaccount: Option<Subaccount>,
+        to_subaccount: Option<Subaccount>,
     },
     Approve {
         from: Principal,
         to: Principal,
+        from_subaccount: Option<Subaccount>,
+        to_subaccount: Option<Subaccount>,
+    },
+    /// The fee of the previous event credited to the fee collector account.
+    FeeCollected {
+        to: Principal,
+        to_subaccount: Option<Subaccount>,
+    },
+    /// A management canister call made by XTC for one of the controllers of the canister.
+    CanisterManaged {
+        from: Principal,
+        canister: Principal,
+        action: CanisterAction,
+        from_subaccount: Option<Subaccount>,
     },
+    /// A canister created through XTC with the code of the module installed on it.
+    CanisterDeployed {
+        from: Principal,
+        canister: Principal,
+        module_hash: [u8; 32],
+        from_subaccount: Option<Subaccount>,
+    },
+}
+
+/// The management canister methods XTC calls on behalf of the controllers of a canister.
+#[derive(CandidType, Clone, Copy, Deserialize, PartialOrd, PartialEq, Debug)]
+pub enum CanisterAction {
+    InstallCode,
+    UpdateSettings,
+    CanisterStatus,
+    StartCanister,
+    StopCanister,
+    DeleteCanister,
 }
 
 #[derive(CandidType, Clone, Deserialize, PartialOrd, PartialEq, Debug)]
@@ -78,8 +125,8 @@ pub struct EventsArgs {
 }
 
 #[derive(CandidType)]
-pub struct EventsConnection<'a, Address = Principal, Event: 'a = Transaction> {
-    pub data: Vec<&'a Event>,
+pub struct EventsConnection<Address = Principal, Event = Transaction> {
+    pub data: Vec<Event>,
     pub next_offset: TransactionId,
     pub next_canister_id: Option<Address>,
 }
@@ -1,28 +1,34 @@
 use crate::backend::Backend;
+use crate::stable::StableEvents;
 use ic_cdk::export::candid::{CandidType, Principal};
 use serde::Deserialize;
 use std::convert::From;
 use xtc_history_common::bucket::*;
 use xtc_history_common::types::*;
+use xtc_stable::Memory;
 
 /// All of the data inside the main canister's history. This structure combines a bucket to manage
 /// the events living in the main canister with a mapping of all the buckets created to provide
 /// fast lookups to any transaction in existence and keep the state of bucket in sync with the
 /// list of buckets, so the system is in a valid state at anytime.
+///
+/// The events of the main canister are kept in the stable memory, only the list of buckets
+/// lives on the heap and has to be saved on upgrades, see HistoryState.
 pub struct HistoryData<Address = Principal> {
-    bucket: BucketData<Address, Transaction>,
+    bucket: BucketData<Address, Transaction, StableEvents>,
     buckets: Vec<(TransactionId, Address)>,
 }
 
-/// A borrow of all the data required to reconstruct the HistoryData efficient to be serialized.
-#[derive(CandidType, Debug)]
-pub struct HistoryArchiveBorrowed<'e, 'b, Address: 'b = Principal> {
+/// The part of the HistoryData that lives on the heap, together with the memory holding the
+/// events it is enough to restore the HistoryData after an upgrade.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct HistoryState<Address = Principal> {
     pub offset: TransactionId,
-    pub events: &'e Vec<Transaction>,
-    pub buckets: &'b Vec<(TransactionId, Address)>,
+    pub buckets: Vec<(TransactionId, Address)>,
 }
 
-/// The result of deserializing a HistoryArchiveBorrowed.
+/// The archive of the entire history written to the stable storage by the older versions of
+/// XTC, which serialized the events on each upgrade.
 #[derive(CandidType, Deserialize)]
 pub struct HistoryArchive<Address = Principal> {
     pub offset: TransactionId,
@@ -54,21 +60,51 @@ impl From<HistoryArchiveV0> for HistoryArchive {
 }
 
 impl<T> Default for HistoryData<T> {
+    /// Create an empty history which keeps its events on the heap.
     fn default() -> Self {
-        let mut bucket = BucketData::default();
-        bucket.set_metadata(SetBucketMetadataArgs {
-            from: 0,
-            next: None,
-        });
-
         HistoryData {
-            bucket,
+            bucket: BucketData::new(0, StableEvents::default()),
             buckets: Vec::new(),
         }
     }
 }
 
 impl<Address> HistoryData<Address> {
+    /// Create an empty history that stores its events in the given memory, any data already
+    /// stored in the memory is dropped.
+    pub fn new(memory: Box<dyn Memory>) -> Self {
+        HistoryData {
+            bucket: BucketData::new(0, StableEvents::new(memory)),
+            buckets: Vec::new(),
+        }
+    }
+
+    /// Restore the history from the events stored in the given memory and the state returned
+    /// by a previous call to `state`.
+    pub fn restore(memory: Box<dyn Memory>, state: HistoryState<Address>) -> Self
+    where
+        Address: Clone,
+    {
+        let mut bucket = BucketData::new(state.offset, StableEvents::load(memory));
+        bucket.update_next(state.buckets.last().map(|(_, address)| address.clone()));
+
+        HistoryData {
+            bucket,
+            buckets: state.buckets,
+        }
+    }
+
+    /// Return the part of the data that should be saved on upgrades.
+    pub fn state(&self) -> HistoryState<Address>
+    where
+        Address: Clone,
+    {
+        HistoryState {
+            offset: self.bucket.get_offset(),
+            buckets: self.buckets.clone(),
+        }
+    }
+
     /// Push an event to the history buffer and return the transaction id for that event.
     #[inline]
     pub fn push(&mut self, event: Transaction) -> TransactionId {
@@ -95,13 +131,14 @@ impl<Address> HistoryData<Address> {
     /// Return true if the history is empty.
     #[inline]
     pub fn is_empty(&self) -> bool {
-        self.buckets.is_empty() && self.bucket.get_events().is_empty()
+        self.buckets.is_empty() && self.bucket.len() == 0
     }
 
-    /// Return the events in the local canister.
+    /// Return the events in the range start..end of the local canister, the indexes are
+    /// relative to the oldest event in the local canister.
     #[inline]
-    pub fn get_events(&self) -> &Vec<Transaction> {
-        self.bucket.get_events()
+    pub fn get_events(&self, start: usize, end: usize) -> Vec<Transaction> {
+        self.bucket.get_events(start, end)
     }
 
     /// Return the current active bucket canister.
@@ -166,7 +203,7 @@ impl<Address> HistoryData<Address> {
         S: Backend<Address>,
     {
         if id >= self.bucket.get_offset() {
-            self.bucket.get_transaction(id).cloned()
+            self.bucket.get_transaction(id)
         } else if let Some(canister_id) = self.get_bucket_for(id) {
             S::lookup_transaction(canister_id, id).await.unwrap()
         } else {
@@ -195,20 +232,11 @@ impl<Address> HistoryData<Address> {
 }
 
 impl<Address> HistoryData<Address> {
-    /// Create an archive for the current data.
-    pub fn archive(&self) -> HistoryArchiveBorrowed<Address> {
-        HistoryArchiveBorrowed {
-            offset: self.bucket.get_offset(),
-            events: self.bucket.get_events(),
-            buckets: &self.buckets,
-        }
-    }
-
     /// Load the data from an archive.
     ///
     /// # Panics
     /// If the history is not currently empty.
-    pub fn load(&mut self, archive: HistoryArchive<Address>)
+    pub fn load(&mut self, mut archive: HistoryArchive<Address>)
     where
         Address: Clone,
     {
@@ -223,7 +251,9 @@ impl<Address> HistoryData<Address> {
             Some(archive.buckets[archive.buckets.len() - 1].1.clone())
         };
 
-        self.bucket = BucketData::new(archive.offset, archive.events);
+        let mut events = std::mem::take(&mut self.bucket).into_events();
+        events.append(&mut archive.events);
+        self.bucket = BucketData::new(archive.offset, events);
         self.bucket.update_next(next);
         self.buckets = archive.buckets;
     }
@@ -246,6 +276,7 @@ impl<Address> HistoryData<Address> {
 mod test {
     use super::*;
     use crate::mock::MockBackend;
+    use xtc_stable::VecMemory;
 
     /// Generate a fake transaction with the given id, the id is inserted as the timestamp
     /// for the transaction.
@@ -257,6 +288,7 @@ mod test {
             fee: 0,
             kind: TransactionKind::Mint {
                 to: Principal::management_canister(),
+                to_subaccount: None,
             },
             status: TransactionStatus::SUCCEEDED,
         }
@@ -273,7 +305,7 @@ mod test {
     }
 
     #[async_std::test]
-    async fn archive() {
+    async fn state() {
         let mut data = HistoryData::<u32>::default();
 
         // Insert 10 items.
@@ -300,14 +332,33 @@ mod test {
         });
 
         let transactions = (20..30).map(tx).collect::<Vec<Transaction>>();
-        let archive = data.archive();
-        assert_eq!(archive.offset, 20);
-        assert_eq!(archive.events, &transactions);
-        assert_eq!(archive.buckets, &vec![(0, 17), (10, 18)]);
+        let state = data.state();
+        assert_eq!(state.offset, 20);
+        assert_eq!(state.buckets, vec![(0, 17), (10, 18)]);
+        assert_eq!(data.get_events(0, 10), transactions);
 
         assert_eq!(data.get_transaction::<MockBackend>(25).await, Some(tx(25)));
     }
 
+    #[async_std::test]
+    async fn restore() {
+        let memory = VecMemory::default();
+        let mut data = HistoryData::<u32>::new(Box::new(memory.clone()));
+
+        (0..10).map(tx).for_each(|event| {
+            data.push(event);
+        });
+        data.insert_bucket(17);
+        data.remove_first(5);
+
+        let data = HistoryData::<u32>::restore(Box::new(memory), data.state());
+        assert_eq!(data.size(), 10);
+        assert_eq!(data.get_bucket(), &17);
+        assert_eq!(data.get_bucket_for(0), Some(&17));
+        assert_eq!(data.get_transaction::<MockBackend>(5).await, Some(tx(5)));
+        assert_eq!(data.get_events(0, 10), (5..10).map(tx).collect::<Vec<_>>());
+    }
+
     #[async_std::test]
     async fn load() {
         let mut data = HistoryData::<u32>::default();
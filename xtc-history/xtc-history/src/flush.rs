@@ -113,11 +113,11 @@ impl<Address: Clone + std::cmp::PartialEq, Storage: Backend<Address>>
             }
             State::PushChunk => {
                 // Data we need to write.
-                let chunk = &data.get_events()[0..self.chunk_size];
+                let chunk = data.get_events(0, self.chunk_size);
                 // The bucket canister we need to write the data to.
                 let canister_id = data.get_bucket();
 
-                self.state = match Storage::append_transactions(canister_id, chunk).await {
+                self.state = match Storage::append_transactions(canister_id, &chunk).await {
                     Ok(()) => {
                         data.remove_first(self.chunk_size);
 
@@ -10,6 +10,7 @@ pub mod data;
 pub mod flush;
 pub mod ic;
 pub mod mock;
+pub mod stable;
 
 /// A smart history buffer which wraps the bucket and flusher together to provide a bucket
 /// implementation that can automatically scale up and flush its data to other canisters to
@@ -28,13 +29,25 @@ impl<Address: Clone + std::cmp::PartialEq, Storage: Backend<Address>> History<Ad
     /// # Panics
     /// If flush threshold is smaller than the chunk size.
     pub fn new(flush_threshold: usize, chunk_size: usize) -> Self {
+        History::with_data(HistoryData::default(), flush_threshold, chunk_size)
+    }
+
+    /// Create a new history instance on top of the given data.
+    ///
+    /// # Panics
+    /// If flush threshold is smaller than the chunk size.
+    pub fn with_data(
+        data: HistoryData<Address>,
+        flush_threshold: usize,
+        chunk_size: usize,
+    ) -> Self {
         assert!(
             flush_threshold > chunk_size,
             "Flush threshold should be larger than the chunk size"
         );
 
         History {
-            data: HistoryData::default(),
+            data,
             flusher: None,
             chunk_size,
             flush_threshold,
@@ -101,15 +114,16 @@ impl<Address: Clone + std::cmp::PartialEq, Storage: Backend<Address>> History<Ad
 }
 
 impl<Address: Clone, Storage: Backend<Address>> History<Address, Storage> {
+    /// Return the part of the history that should be saved on upgrades.
     #[inline]
-    pub fn archive(&self) -> HistoryArchiveBorrowed<Address> {
+    pub fn state(&self) -> HistoryState<Address> {
         // Prevent upgrades during an active flush.
         assert!(
             self.flusher.is_none(),
             "History flush in progress, try again later."
         );
 
-        self.data.archive()
+        self.data.state()
     }
 
     #[inline]
@@ -138,6 +152,7 @@ mod tests {
             fee: 0,
             kind: TransactionKind::Mint {
                 to: Principal::management_canister(),
+                to_subaccount: None,
             },
             status: TransactionStatus::SUCCEEDED,
         }
@@ -155,11 +170,11 @@ mod tests {
 
         while history.progress().await {}
 
-        let archive = history.archive();
-        assert_eq!(archive.offset, 20);
-        assert_eq!(archive.events.len(), 5);
-        assert_eq!(archive.buckets.len(), 1);
-        assert_eq!(archive.buckets[0].0, 0);
+        let state = history.state();
+        assert_eq!(state.offset, 20);
+        assert_eq!(history.data.len(), 5);
+        assert_eq!(state.buckets.len(), 1);
+        assert_eq!(state.buckets[0].0, 0);
     }
 
     /// An aggressive test which tries to prove that the flusher is always able to return the correct
@@ -187,7 +202,7 @@ mod tests {
         // 11 * 50 = 550
         // 10 * 50 = 500
         // so we should have at least 10 buckets.
-        assert!(history.archive().buckets.len() > 9);
+        assert!(history.state().buckets.len() > 9);
 
         for j in 0..500 {
             assert_eq!(history.get_transaction(j).await, Some(tx(j)));
@@ -82,7 +82,7 @@ impl Backend<MockCanisterId> for MockBackend {
             .expect("Canister not found.")
             .as_mut()
             .expect("Canister code not installed.");
-        let tx = bucket.get_transaction(id).cloned();
+        let tx = bucket.get_transaction(id);
         Box::pin(async move { Ok(tx) })
     }
 
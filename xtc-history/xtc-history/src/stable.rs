@@ -0,0 +1,504 @@
+use ic_cdk::export::Principal;
+use xtc_history_common::bucket::EventStorage;
+use xtc_history_common::types::*;
+use xtc_stable::{Memory, StableQueue, Storable, VecMemory};
+
+/// The number of events the ring buffer of the main canister holds before it grows, it leaves
+/// enough room for the events pushed during a flush so it only grows when the flushes stall.
+pub const EVENTS_CAPACITY: u64 = 1 << 20;
+
+/// The longest method name a CanisterCalled event can hold, so every event fits in a fixed
+/// size slot. The canister rejects calls to methods with longer names.
+pub const MAX_METHOD_NAME_LEN: usize = 128;
+
+/// The events of the main canister stored in a ring buffer in the stable memory.
+pub struct StableEvents {
+    queue: StableQueue<StoredTransaction, Box<dyn Memory>>,
+}
+
+impl StableEvents {
+    /// Create an empty event storage in the given memory, dropping any data in it.
+    pub fn new(memory: Box<dyn Memory>) -> Self {
+        StableEvents {
+            queue: StableQueue::new(memory, EVENTS_CAPACITY),
+        }
+    }
+
+    /// Load the events stored in the given memory, an empty memory is treated as an empty
+    /// event storage.
+    pub fn load(memory: Box<dyn Memory>) -> Self {
+        StableEvents {
+            queue: StableQueue::init(memory, EVENTS_CAPACITY),
+        }
+    }
+}
+
+impl Default for StableEvents {
+    /// Create an event storage that lives on the heap, used when the events do not need to
+    /// survive an upgrade.
+    fn default() -> Self {
+        StableEvents::new(Box::new(VecMemory::default()))
+    }
+}
+
+impl EventStorage<Transaction> for StableEvents {
+    #[inline]
+    fn len(&self) -> usize {
+        self.queue.len() as usize
+    }
+
+    #[inline]
+    fn get(&self, index: usize) -> Option<Transaction> {
+        self.queue.get(index as u64).map(|stored| stored.0)
+    }
+
+    #[inline]
+    fn range(&self, start: usize, end: usize) -> Vec<Transaction> {
+        self.queue
+            .range(start as u64, end as u64)
+            .into_iter()
+            .map(|stored| stored.0)
+            .collect()
+    }
+
+    #[inline]
+    fn push(&mut self, event: Transaction) {
+        self.queue.push(&StoredTransaction(event));
+    }
+
+    #[inline]
+    fn remove_first(&mut self, n: usize) {
+        self.queue.pop_front(n as u64);
+    }
+}
+
+/// The fixed size encoding of a transaction.
+struct StoredTransaction(Transaction);
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    position: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf[self.position..self.position + bytes.len()].copy_from_slice(bytes);
+        self.position += bytes.len();
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.bytes(&[value]);
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn principal(&mut self, principal: &Principal) {
+        let bytes = principal.as_slice();
+        self.u8(bytes.len() as u8);
+        self.bytes(bytes);
+    }
+
+    fn subaccount(&mut self, subaccount: &Option<Subaccount>) {
+        match subaccount {
+            Some(subaccount) => {
+                self.u8(1);
+                self.bytes(&subaccount.0);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn action(&mut self, action: CanisterAction) {
+        self.u8(match action {
+            CanisterAction::InstallCode => 0,
+            CanisterAction::UpdateSettings => 1,
+            CanisterAction::CanisterStatus => 2,
+            CanisterAction::StartCanister => 3,
+            CanisterAction::StopCanister => 4,
+            CanisterAction::DeleteCanister => 5,
+        });
+    }
+
+    fn text(&mut self, text: &str) {
+        assert!(
+            text.len() <= MAX_METHOD_NAME_LEN,
+            "The method name is too long to be stored."
+        );
+        self.u8(text.len() as u8);
+        self.bytes(text.as_bytes());
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> &'a [u8] {
+        let bytes = &self.buf[self.position..self.position + len];
+        self.position += len;
+        bytes
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.bytes(1)[0]
+    }
+
+    fn u64(&mut self) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8));
+        u64::from_le_bytes(bytes)
+    }
+
+    fn principal(&mut self) -> Principal {
+        let len = self.u8() as usize;
+        Principal::from_slice(self.bytes(len))
+    }
+
+    fn subaccount(&mut self) -> Option<Subaccount> {
+        match self.u8() {
+            0 => None,
+            _ => {
+                let mut subaccount = [0; 32];
+                subaccount.copy_from_slice(self.bytes(32));
+                Some(Subaccount(subaccount))
+            }
+        }
+    }
+
+    fn hash(&mut self) -> [u8; 32] {
+        let mut hash = [0; 32];
+        hash.copy_from_slice(self.bytes(32));
+        hash
+    }
+
+    fn action(&mut self) -> CanisterAction {
+        match self.u8() {
+            0 => CanisterAction::InstallCode,
+            1 => CanisterAction::UpdateSettings,
+            2 => CanisterAction::CanisterStatus,
+            3 => CanisterAction::StartCanister,
+            4 => CanisterAction::StopCanister,
+            5 => CanisterAction::DeleteCanister,
+            tag => panic!("Unknown canister action {}.", tag),
+        }
+    }
+
+    fn text(&mut self) -> String {
+        let len = self.u8() as usize;
+        String::from_utf8_lossy(self.bytes(len)).into_owned()
+    }
+}
+
+impl Storable for StoredTransaction {
+    // The largest event is CanisterCalled with a method name of MAX_METHOD_NAME_LEN bytes,
+    // which takes 248 bytes.
+    const SIZE: usize = 256;
+
+    fn write_to(&self, buf: &mut [u8]) {
+        let transaction = &self.0;
+        let mut w = Writer { buf, position: 0 };
+
+        w.u8(match transaction.status {
+            TransactionStatus::SUCCEEDED => 0,
+            TransactionStatus::FAILED => 1,
+        });
+        w.u64(transaction.timestamp);
+        w.u64(transaction.cycles);
+        w.u64(transaction.fee);
+
+        match &transaction.kind {
+            TransactionKind::Transfer {
+                from,
+                to,
+                from_subaccount,
+                to_subaccount,
+            } => {
+                w.u8(0);
+                w.principal(from);
+                w.principal(to);
+                w.subaccount(from_subaccount);
+                w.subaccount(to_subaccount);
+            }
+            TransactionKind::Mint { to, to_subaccount } => {
+                w.u8(1);
+                w.principal(to);
+                w.subaccount(to_subaccount);
+            }
+            TransactionKind::Burn {
+                from,
+                to,
+                from_subaccount,
+            } => {
+                w.u8(2);
+                w.principal(from);
+                w.principal(to);
+                w.subaccount(from_subaccount);
+            }
+            TransactionKind::CanisterCalled {
+                from,
+                canister,
+                method_name,
+                from_subaccount,
+            } => {
+                w.u8(3);
+                w.principal(from);
+                w.principal(canister);
+                w.text(method_name);
+                w.subaccount(from_subaccount);
+            }
+            TransactionKind::CanisterCreated {
+                from,
+                canister,
+                from_subaccount,
+            } => {
+                w.u8(4);
+                w.principal(from);
+                w.principal(canister);
+                w.subaccount(from_subaccount);
+            }
+            TransactionKind::TransferFrom {
+                caller,
+                from,
+                to,
+                from_subaccount,
+                to_subaccount,
+            } => {
+                w.u8(5);
+                w.principal(caller);
+                w.principal(from);
+                w.principal(to);
+                w.subaccount(from_subaccount);
+                w.subaccount(to_subaccount);
+            }
+            TransactionKind::Approve {
+                from,
+                to,
+                from_subaccount,
+                to_subaccount,
+            } => {
+                w.u8(6);
+                w.principal(from);
+                w.principal(to);
+                w.subaccount(from_subaccount);
+                w.subaccount(to_subaccount);
+            }
+            TransactionKind::FeeCollected { to, to_subaccount } => {
+                w.u8(7);
+                w.principal(to);
+                w.subaccount(to_subaccount);
+            }
+            TransactionKind::CanisterManaged {
+                from,
+                canister,
+                action,
+                from_subaccount,
+            } => {
+                w.u8(8);
+                w.principal(from);
+                w.principal(canister);
+                w.action(*action);
+                w.subaccount(from_subaccount);
+            }
+            TransactionKind::CanisterDeployed {
+                from,
+                canister,
+                module_hash,
+                from_subaccount,
+            } => {
+                w.u8(9);
+                w.principal(from);
+                w.principal(canister);
+                w.bytes(module_hash);
+                w.subaccount(from_subaccount);
+            }
+        }
+    }
+
+    fn read_from(buf: &[u8]) -> Self {
+        let mut r = Reader { buf, position: 0 };
+
+        let status = match r.u8() {
+            0 => TransactionStatus::SUCCEEDED,
+            _ => TransactionStatus::FAILED,
+        };
+        let timestamp = r.u64();
+        let cycles = r.u64();
+        let fee = r.u64();
+
+        let kind = match r.u8() {
+            0 => TransactionKind::Transfer {
+                from: r.principal(),
+                to: r.principal(),
+                from_subaccount: r.subaccount(),
+                to_subaccount: r.subaccount(),
+            },
+            1 => TransactionKind::Mint {
+                to: r.principal(),
+                to_subaccount: r.subaccount(),
+            },
+            2 => TransactionKind::Burn {
+                from: r.principal(),
+                to: r.principal(),
+                from_subaccount: r.subaccount(),
+            },
+            3 => TransactionKind::CanisterCalled {
+                from: r.principal(),
+                canister: r.principal(),
+                method_name: r.text(),
+                from_subaccount: r.subaccount(),
+            },
+            4 => TransactionKind::CanisterCreated {
+                from: r.principal(),
+                canister: r.principal(),
+                from_subaccount: r.subaccount(),
+            },
+            5 => TransactionKind::TransferFrom {
+                caller: r.principal(),
+                from: r.principal(),
+                to: r.principal(),
+                from_subaccount: r.subaccount(),
+                to_subaccount: r.subaccount(),
+            },
+            6 => TransactionKind::Approve {
+                from: r.principal(),
+                to: r.principal(),
+                from_subaccount: r.subaccount(),
+                to_subaccount: r.subaccount(),
+            },
+            7 => TransactionKind::FeeCollected {
+                to: r.principal(),
+                to_subaccount: r.subaccount(),
+            },
+            8 => TransactionKind::CanisterManaged {
+                from: r.principal(),
+                canister: r.principal(),
+                action: r.action(),
+                from_subaccount: r.subaccount(),
+            },
+            9 => TransactionKind::CanisterDeployed {
+                from: r.principal(),
+                canister: r.principal(),
+                module_hash: r.hash(),
+                from_subaccount: r.subaccount(),
+            },
+            tag => panic!("Unknown transaction kind {}.", tag),
+        };
+
+        StoredTransaction(Transaction {
+            timestamp,
+            cycles,
+            fee,
+            kind,
+            status,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(transaction: Transaction) -> Transaction {
+        let mut buf = vec![0; StoredTransaction::SIZE];
+        StoredTransaction(transaction).write_to(&mut buf);
+        StoredTransaction::read_from(&buf).0
+    }
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    #[test]
+    fn encode_all_kinds() {
+        let subaccount = Some(Subaccount([7; 32]));
+        let kinds = vec![
+            TransactionKind::Transfer {
+                from: principal(1),
+                to: principal(2),
+                from_subaccount: subaccount,
+                to_subaccount: None,
+            },
+            TransactionKind::Mint {
+                to: Principal::anonymous(),
+                to_subaccount: subaccount,
+            },
+            TransactionKind::Burn {
+                from: principal(1),
+                to: Principal::management_canister(),
+                from_subaccount: None,
+            },
+            TransactionKind::CanisterCalled {
+                from: principal(1),
+                canister: principal(2),
+                method_name: "x".repeat(MAX_METHOD_NAME_LEN),
+                from_subaccount: subaccount,
+            },
+            TransactionKind::CanisterCreated {
+                from: principal(1),
+                canister: principal(2),
+                from_subaccount: subaccount,
+            },
+            TransactionKind::TransferFrom {
+                caller: principal(1),
+                from: principal(2),
+                to: principal(3),
+                from_subaccount: subaccount,
+                to_subaccount: subaccount,
+            },
+            TransactionKind::Approve {
+                from: principal(1),
+                to: principal(2),
+                from_subaccount: subaccount,
+                to_subaccount: subaccount,
+            },
+            TransactionKind::FeeCollected {
+                to: principal(1),
+                to_subaccount: subaccount,
+            },
+            TransactionKind::CanisterManaged {
+                from: principal(1),
+                canister: principal(2),
+                action: CanisterAction::DeleteCanister,
+                from_subaccount: subaccount,
+            },
+            TransactionKind::CanisterDeployed {
+                from: principal(1),
+                canister: principal(2),
+                module_hash: [9; 32],
+                from_subaccount: subaccount,
+            },
+        ];
+
+        for kind in kinds {
+            let transaction = Transaction {
+                timestamp: 1,
+                cycles: u64::MAX,
+                fee: 3,
+                kind,
+                status: TransactionStatus::FAILED,
+            };
+            assert_eq!(roundtrip(transaction.clone()), transaction);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn method_name_too_long() {
+        roundtrip(Transaction {
+            timestamp: 0,
+            cycles: 0,
+            fee: 0,
+            kind: TransactionKind::CanisterCalled {
+                from: principal(1),
+                canister: principal(2),
+                method_name: "é".repeat(MAX_METHOD_NAME_LEN / 2 + 1),
+                from_subaccount: None,
+            },
+            status: TransactionStatus::SUCCEEDED,
+        });
+    }
+}
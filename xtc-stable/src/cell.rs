@@ -0,0 +1,70 @@
+use crate::memory::{ensure_size, read_u64, write_u64, Memory};
+
+const MAGIC: &[u8; 4] = b"XCEL";
+const LEN_OFFSET: u64 = 8;
+const DATA_OFFSET: u64 = 16;
+
+/// A single variable sized blob stored in a region of the stable memory, it is used to keep
+/// the small parts of the state that are serialized in full on each upgrade.
+pub struct StableCell<M: Memory> {
+    memory: M,
+}
+
+impl<M: Memory> StableCell<M> {
+    pub fn new(memory: M) -> Self {
+        StableCell { memory }
+    }
+
+    /// Return the stored blob, or None if nothing was ever stored in this cell.
+    pub fn get(&self) -> Option<Vec<u8>> {
+        if self.memory.size() == 0 {
+            return None;
+        }
+
+        let mut magic = [0; 4];
+        self.memory.read(0, &mut magic);
+        if &magic != MAGIC {
+            return None;
+        }
+
+        let mut data = vec![0; read_u64(&self.memory, LEN_OFFSET) as usize];
+        self.memory.read(DATA_OFFSET, &mut data);
+        Some(data)
+    }
+
+    /// Replace the stored blob.
+    ///
+    /// # Panics
+    /// If the memory could not be grown to fit the data.
+    pub fn set(&mut self, data: &[u8]) {
+        assert!(
+            ensure_size(&self.memory, DATA_OFFSET + data.len() as u64),
+            "Failed to grow the stable memory."
+        );
+        self.memory.write(0, MAGIC);
+        write_u64(&self.memory, LEN_OFFSET, data.len() as u64);
+        self.memory.write(DATA_OFFSET, data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::VecMemory;
+
+    #[test]
+    fn get_and_set() {
+        let memory = VecMemory::default();
+        let mut cell = StableCell::new(memory.clone());
+        assert_eq!(cell.get(), None);
+
+        cell.set(b"hello");
+        assert_eq!(
+            StableCell::new(memory.clone()).get(),
+            Some(b"hello".to_vec())
+        );
+
+        cell.set(b"hi");
+        assert_eq!(StableCell::new(memory).get(), Some(b"hi".to_vec()));
+    }
+}
@@ -0,0 +1,22 @@
+//! Data structures that live directly in the stable memory of a canister, instead of being
+//! serialized in pre_upgrade and deserialized in post_upgrade. The cost of an upgrade does not
+//! depend on the amount of data stored in them.
+//!
+//! The stable memory is split into regions using a [VirtualMemory] for each [MemoryId], and each
+//! region holds a single structure.
+
+pub mod cell;
+pub mod log;
+pub mod manager;
+pub mod map;
+pub mod memory;
+pub mod queue;
+pub mod storable;
+
+pub use cell::StableCell;
+pub use log::StableLog;
+pub use manager::{MemoryId, VirtualMemory};
+pub use map::StableHashMap;
+pub use memory::{Memory, VecMemory, WASM_PAGE_SIZE};
+pub use queue::StableQueue;
+pub use storable::Storable;
@@ -0,0 +1,154 @@
+use crate::memory::{ensure_size, read_u64, write_u64, Memory};
+
+const INDEX_MAGIC: &[u8; 4] = b"XLGI";
+const DATA_MAGIC: &[u8; 4] = b"XLGD";
+const HEADER_SIZE: u64 = 16;
+const LEN_OFFSET: u64 = 8;
+
+/// An append only list of variable sized entries stored in two regions of the stable memory.
+/// The entries are concatenated in the data region, and the index region holds the offset
+/// each entry ends at, so any entry is read without going through the ones before it.
+pub struct StableLog<M: Memory> {
+    index: M,
+    data: M,
+    len: u64,
+}
+
+impl<M: Memory> StableLog<M> {
+    /// Create an empty log in the given memories, any data already stored in them is dropped.
+    pub fn new(index: M, data: M) -> Self {
+        assert!(
+            ensure_size(&index, HEADER_SIZE) && ensure_size(&data, HEADER_SIZE),
+            "Failed to grow the stable memory."
+        );
+        index.write(0, INDEX_MAGIC);
+        data.write(0, DATA_MAGIC);
+        write_u64(&index, LEN_OFFSET, 0);
+
+        StableLog {
+            index,
+            data,
+            len: 0,
+        }
+    }
+
+    /// Load the log stored in the given memories, or create an empty log if they are empty.
+    pub fn init(index: M, data: M) -> Self {
+        if index.size() == 0 {
+            StableLog::new(index, data)
+        } else {
+            StableLog::load(index, data)
+        }
+    }
+
+    /// Load a log previously created with new from the given memories.
+    ///
+    /// # Panics
+    /// If the memories do not contain a log.
+    pub fn load(index: M, data: M) -> Self {
+        let mut magic = [0; 4];
+        if index.size() > 0 {
+            index.read(0, &mut magic);
+        }
+        assert_eq!(&magic, INDEX_MAGIC, "The memory does not contain a log.");
+
+        StableLog {
+            len: read_u64(&index, LEN_OFFSET),
+            index,
+            data,
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return the offset in the data region the entry at the given index ends at.
+    #[inline]
+    fn end_of(&self, index: u64) -> u64 {
+        read_u64(&self.index, HEADER_SIZE + index * 8)
+    }
+
+    /// Return the offset in the data region the entry at the given index starts at.
+    #[inline]
+    fn start_of(&self, index: u64) -> u64 {
+        match index {
+            0 => HEADER_SIZE,
+            _ => self.end_of(index - 1),
+        }
+    }
+
+    /// Append an entry to the log, returns its index.
+    ///
+    /// # Panics
+    /// If the memories could not be grown to fit the entry.
+    pub fn push(&mut self, entry: &[u8]) -> u64 {
+        let index = self.len;
+        let start = self.start_of(index);
+        let end = start + entry.len() as u64;
+        assert!(
+            ensure_size(&self.data, end) && ensure_size(&self.index, HEADER_SIZE + (index + 1) * 8),
+            "Failed to grow the stable memory."
+        );
+
+        self.data.write(start, entry);
+        write_u64(&self.index, HEADER_SIZE + index * 8, end);
+        self.len += 1;
+        write_u64(&self.index, LEN_OFFSET, self.len);
+        index
+    }
+
+    /// Return the entry at the given index.
+    pub fn get(&self, index: u64) -> Option<Vec<u8>> {
+        if index >= self.len {
+            return None;
+        }
+
+        let start = self.start_of(index);
+        let mut entry = vec![0; (self.end_of(index) - start) as usize];
+        self.data.read(start, &mut entry);
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::VecMemory;
+
+    #[test]
+    fn push_and_get() {
+        let mut log = StableLog::new(VecMemory::default(), VecMemory::default());
+        assert!(log.is_empty());
+        assert_eq!(log.push(b"hello"), 0);
+        assert_eq!(log.push(b""), 1);
+        assert_eq!(log.push(b"world!"), 2);
+
+        assert_eq!(log.len(), 3);
+        assert_eq!(log.get(0), Some(b"hello".to_vec()));
+        assert_eq!(log.get(1), Some(Vec::new()));
+        assert_eq!(log.get(2), Some(b"world!".to_vec()));
+        assert_eq!(log.get(3), None);
+    }
+
+    #[test]
+    fn load() {
+        let index = VecMemory::default();
+        let data = VecMemory::default();
+        let mut log = StableLog::init(index.clone(), data.clone());
+        let entry = vec![7; 100_000];
+        log.push(&entry);
+        log.push(b"next");
+
+        let log = StableLog::init(index, data);
+        assert_eq!(log.len(), 2);
+        assert_eq!(log.get(0), Some(entry));
+        assert_eq!(log.get(1), Some(b"next".to_vec()));
+    }
+}
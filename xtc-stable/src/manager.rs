@@ -0,0 +1,237 @@
+use crate::memory::{ensure_size, read_u64, write_u64, zero, Memory, WASM_PAGE_SIZE};
+
+const MAGIC: &[u8; 4] = b"XTCM";
+const LAYOUT_VERSION: u8 = 1;
+
+/// The maximum number of regions the stable memory can be split into.
+pub const MAX_MEMORIES: u8 = 32;
+
+/// Memory is handed out to the regions in buckets of this many pages (8MiB).
+const BUCKET_SIZE_IN_PAGES: u64 = 128;
+const BUCKET_SIZE: u64 = BUCKET_SIZE_IN_PAGES * WASM_PAGE_SIZE;
+
+/// The number of buckets that fit in a 32-bit stable memory after the header page.
+const MAX_BUCKETS: u64 = 511;
+
+// The header occupies the first page of the stable memory:
+//   0..4     magic
+//   4        layout version
+//   8..16    number of buckets handed out so far
+//   64..     size of each region in pages, one u64 per memory id
+//   1024..   the buckets owned by each region in order, 512 u16 entries per memory id
+const ALLOCATED_OFFSET: u64 = 8;
+const SIZES_OFFSET: u64 = 64;
+const TABLE_OFFSET: u64 = 1024;
+const TABLE_ENTRIES: u64 = 512;
+const HEADER_SIZE: u64 = WASM_PAGE_SIZE;
+
+/// Identifies a region of the stable memory.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct MemoryId(u8);
+
+impl MemoryId {
+    pub const fn new(id: u8) -> Self {
+        MemoryId(id)
+    }
+}
+
+/// Return true if the memory starts with the header written by [format].
+pub fn is_formatted<M: Memory + ?Sized>(memory: &M) -> bool {
+    if memory.size() == 0 {
+        return false;
+    }
+
+    let mut magic = [0; 4];
+    memory.read(0, &mut magic);
+    &magic == MAGIC
+}
+
+/// Write an empty header to the memory, this drops all of the regions and any data that was
+/// previously stored in the memory.
+///
+/// # Panics
+/// If the memory could not be grown to fit the header.
+pub fn format<M: Memory + ?Sized>(memory: &M) {
+    assert!(
+        ensure_size(memory, HEADER_SIZE),
+        "Failed to grow the stable memory."
+    );
+    zero(
+        memory,
+        0,
+        TABLE_OFFSET + MAX_MEMORIES as u64 * TABLE_ENTRIES * 2,
+    );
+    memory.write(0, MAGIC);
+    memory.write(4, &[LAYOUT_VERSION]);
+}
+
+/// A region of the underlying memory that acts as an independent growable memory. The regions
+/// grow in buckets which are allocated as they are needed, so a region only occupies as much
+/// of the underlying memory as it uses.
+///
+/// All of the bookkeeping lives in the header of the underlying memory, so any number of
+/// VirtualMemory values can be created for the same region and they all see the same data.
+#[derive(Clone)]
+pub struct VirtualMemory<M: Memory> {
+    base: M,
+    id: MemoryId,
+}
+
+impl<M: Memory> VirtualMemory<M> {
+    /// Open the region with the given id, an empty underlying memory is formatted first.
+    ///
+    /// # Panics
+    /// If the underlying memory holds data that was not written through the VirtualMemory.
+    pub fn new(base: M, id: MemoryId) -> Self {
+        assert!(id.0 < MAX_MEMORIES, "Invalid memory id.");
+
+        if base.size() == 0 {
+            format(&base);
+        }
+
+        assert!(
+            is_formatted(&base),
+            "The stable memory does not contain a region header."
+        );
+
+        VirtualMemory { base, id }
+    }
+
+    #[inline]
+    fn size_offset(&self) -> u64 {
+        SIZES_OFFSET + self.id.0 as u64 * 8
+    }
+
+    #[inline]
+    fn table_offset(&self, index: u64) -> u64 {
+        TABLE_OFFSET + (self.id.0 as u64 * TABLE_ENTRIES + index) * 2
+    }
+
+    /// Return the offset of the nth bucket of this region in the underlying memory.
+    #[inline]
+    fn bucket_address(&self, index: u64) -> u64 {
+        let mut buf = [0; 2];
+        self.base.read(self.table_offset(index), &mut buf);
+        HEADER_SIZE + u16::from_le_bytes(buf) as u64 * BUCKET_SIZE
+    }
+}
+
+impl<M: Memory> Memory for VirtualMemory<M> {
+    fn size(&self) -> u64 {
+        read_u64(&self.base, self.size_offset())
+    }
+
+    fn grow(&self, pages: u64) -> bool {
+        let size = self.size();
+        let owned = size.div_ceil(BUCKET_SIZE_IN_PAGES);
+        let needed = (size + pages).div_ceil(BUCKET_SIZE_IN_PAGES);
+        let allocated = read_u64(&self.base, ALLOCATED_OFFSET);
+
+        if needed > TABLE_ENTRIES || allocated + (needed - owned) > MAX_BUCKETS {
+            return false;
+        }
+
+        let end = HEADER_SIZE + (allocated + needed - owned) * BUCKET_SIZE;
+        if !ensure_size(&self.base, end) {
+            return false;
+        }
+
+        for (i, bucket) in (owned..needed).zip(allocated..) {
+            self.base
+                .write(self.table_offset(i), &(bucket as u16).to_le_bytes());
+        }
+
+        write_u64(&self.base, ALLOCATED_OFFSET, allocated + needed - owned);
+        write_u64(&self.base, self.size_offset(), size + pages);
+        true
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        assert!(
+            offset + dst.len() as u64 <= self.size() * WASM_PAGE_SIZE,
+            "Read out of the bounds of the region."
+        );
+
+        let mut done = 0;
+        while done < dst.len() {
+            let position = offset + done as u64;
+            let within = position % BUCKET_SIZE;
+            let n = ((BUCKET_SIZE - within) as usize).min(dst.len() - done);
+            let address = self.bucket_address(position / BUCKET_SIZE) + within;
+            self.base.read(address, &mut dst[done..done + n]);
+            done += n;
+        }
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        assert!(
+            offset + src.len() as u64 <= self.size() * WASM_PAGE_SIZE,
+            "Write out of the bounds of the region."
+        );
+
+        let mut done = 0;
+        while done < src.len() {
+            let position = offset + done as u64;
+            let within = position % BUCKET_SIZE;
+            let n = ((BUCKET_SIZE - within) as usize).min(src.len() - done);
+            let address = self.bucket_address(position / BUCKET_SIZE) + within;
+            self.base.write(address, &src[done..done + n]);
+            done += n;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::VecMemory;
+
+    #[test]
+    fn regions_are_independent() {
+        let base = VecMemory::default();
+        let a = VirtualMemory::new(base.clone(), MemoryId::new(0));
+        let b = VirtualMemory::new(base.clone(), MemoryId::new(1));
+        assert!(is_formatted(&base));
+
+        assert!(a.grow(1));
+        assert!(b.grow(1));
+        write_u64(&a, 0, 1);
+        write_u64(&b, 0, 2);
+        assert_eq!(read_u64(&a, 0), 1);
+        assert_eq!(read_u64(&b, 0), 2);
+
+        // A new handle to the same region sees the same data.
+        let a = VirtualMemory::new(base, MemoryId::new(0));
+        assert_eq!(a.size(), 1);
+        assert_eq!(read_u64(&a, 0), 1);
+    }
+
+    #[test]
+    fn access_across_buckets() {
+        let base = VecMemory::default();
+        let a = VirtualMemory::new(base.clone(), MemoryId::new(0));
+        let b = VirtualMemory::new(base.clone(), MemoryId::new(1));
+
+        // Interleave the buckets of the two regions.
+        assert!(a.grow(BUCKET_SIZE_IN_PAGES));
+        assert!(b.grow(BUCKET_SIZE_IN_PAGES));
+        assert!(a.grow(BUCKET_SIZE_IN_PAGES));
+
+        let data: Vec<u8> = (0..16).collect();
+        a.write(BUCKET_SIZE - 8, &data);
+        b.write(0, &[0xff; 16]);
+
+        let mut buf = [0; 16];
+        a.read(BUCKET_SIZE - 8, &mut buf);
+        assert_eq!(buf.to_vec(), data);
+    }
+
+    #[test]
+    #[should_panic]
+    fn legacy_data() {
+        let base = VecMemory::default();
+        base.grow(1);
+        base.write(0, b"DIDL");
+        VirtualMemory::new(base, MemoryId::new(0));
+    }
+}
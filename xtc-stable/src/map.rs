@@ -0,0 +1,731 @@
+use crate::memory::{ensure_size, read_u64, write_u64, zero, Memory};
+use crate::storable::Storable;
+use std::convert::TryInto;
+use std::marker::PhantomData;
+
+const MAGIC: &[u8; 4] = b"XMAP";
+const HEADER_SIZE: u64 = 128;
+const LEN_OFFSET: u64 = 8;
+const CAPACITY_OFFSET: u64 = 16;
+const TOMBSTONES_OFFSET: u64 = 24;
+const TABLE_OFFSET: u64 = 32;
+const OLD_TABLE_OFFSET: u64 = 40;
+const OLD_CAPACITY_OFFSET: u64 = 48;
+const OLD_LEN_OFFSET: u64 = 56;
+const MIGRATED_OFFSET: u64 = 64;
+const SEED_OFFSET: u64 = 72;
+const OLD_SEED_OFFSET: u64 = 88;
+
+const INITIAL_CAPACITY: u64 = 1024;
+
+/// The number of slots of the old table moved to the new one on every insert and remove while
+/// the map is rehashed. The old table is empty before the new one fills up as long as this is
+/// more than 4, see `insert`.
+const MIGRATE_STEP: u64 = 8;
+
+const EMPTY: u8 = 0;
+const OCCUPIED: u8 = 1;
+const DELETED: u8 = 2;
+
+/// A hash map with open addressing that keeps all of its entries in a region of the stable
+/// memory, the heap only holds a copy of the header.
+///
+/// Keys are compared and hashed by their encoding. The hash is SipHash-2-4 keyed with a seed
+/// stored in the header, so the position of the entries does not change between different
+/// builds of the canister but can not be predicted without the seed. A new map is keyed with
+/// the zero seed until `reseed` is called with a random one.
+///
+/// The map is rehashed incrementally: a new table is allocated next to the current one and
+/// every insert and remove moves a few entries to it, the lookups check both tables until the
+/// old one is empty.
+pub struct StableHashMap<K: Storable, V: Storable, M: Memory> {
+    memory: M,
+    /// The number of entries in both tables.
+    len: u64,
+    capacity: u64,
+    tombstones: u64,
+    /// The offset of the table new entries are inserted to.
+    table: u64,
+    /// The table being rehashed, its capacity is zero once it is empty.
+    old_table: u64,
+    old_capacity: u64,
+    old_len: u64,
+    /// The slots of the old table below this one were already moved.
+    migrated: u64,
+    /// The seeds of the hashes of the table and of the old table.
+    seed: [u8; 16],
+    old_seed: [u8; 16],
+    data: PhantomData<(K, V)>,
+}
+
+#[inline]
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+/// SipHash-2-4 of the bytes keyed with the seed.
+fn hash(seed: &[u8; 16], bytes: &[u8]) -> u64 {
+    let k0 = u64::from_le_bytes(seed[..8].try_into().unwrap());
+    let k1 = u64::from_le_bytes(seed[8..].try_into().unwrap());
+    let mut v = [
+        k0 ^ 0x736f6d6570736575,
+        k1 ^ 0x646f72616e646f6d,
+        k0 ^ 0x6c7967656e657261,
+        k1 ^ 0x7465646279746573,
+    ];
+
+    let mut compress = |m: u64| {
+        v[3] ^= m;
+        sip_round(&mut v);
+        sip_round(&mut v);
+        v[0] ^= m;
+    };
+
+    let mut chunks = bytes.chunks_exact(8);
+    for chunk in &mut chunks {
+        compress(u64::from_le_bytes(chunk.try_into().unwrap()));
+    }
+    let mut last = [0; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    last[7] = bytes.len() as u8;
+    compress(u64::from_le_bytes(last));
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+#[inline]
+fn read_seed<M: Memory>(memory: &M, offset: u64) -> [u8; 16] {
+    let mut seed = [0; 16];
+    memory.read(offset, &mut seed);
+    seed
+}
+
+impl<K: Storable, V: Storable, M: Memory> StableHashMap<K, V, M> {
+    /// Create an empty map in the given memory, any data already stored in the memory is
+    /// dropped.
+    pub fn new(memory: M) -> Self {
+        let mut map = StableHashMap {
+            memory,
+            len: 0,
+            capacity: 0,
+            tombstones: 0,
+            table: HEADER_SIZE,
+            old_table: 0,
+            old_capacity: 0,
+            old_len: 0,
+            migrated: 0,
+            seed: [0; 16],
+            old_seed: [0; 16],
+            data: PhantomData,
+        };
+        map.reset(INITIAL_CAPACITY);
+        map
+    }
+
+    /// Load the map stored in the given memory, or create an empty map if the memory is
+    /// empty.
+    pub fn init(memory: M) -> Self {
+        if memory.size() == 0 {
+            StableHashMap::new(memory)
+        } else {
+            StableHashMap::load(memory)
+        }
+    }
+
+    /// Load a map previously created with new from the given memory.
+    ///
+    /// # Panics
+    /// If the memory does not contain a map.
+    pub fn load(memory: M) -> Self {
+        let mut magic = [0; 4];
+        if memory.size() > 0 {
+            memory.read(0, &mut magic);
+        }
+        assert_eq!(&magic, MAGIC, "The memory does not contain a map.");
+
+        StableHashMap {
+            len: read_u64(&memory, LEN_OFFSET),
+            capacity: read_u64(&memory, CAPACITY_OFFSET),
+            tombstones: read_u64(&memory, TOMBSTONES_OFFSET),
+            table: read_u64(&memory, TABLE_OFFSET),
+            old_table: read_u64(&memory, OLD_TABLE_OFFSET),
+            old_capacity: read_u64(&memory, OLD_CAPACITY_OFFSET),
+            old_len: read_u64(&memory, OLD_LEN_OFFSET),
+            migrated: read_u64(&memory, MIGRATED_OFFSET),
+            seed: read_seed(&memory, SEED_OFFSET),
+            old_seed: read_seed(&memory, OLD_SEED_OFFSET),
+            memory,
+            data: PhantomData,
+        }
+    }
+
+    #[inline]
+    fn slot_size() -> u64 {
+        1 + K::SIZE as u64 + V::SIZE as u64
+    }
+
+    #[inline]
+    fn slot_offset(table: u64, index: u64) -> u64 {
+        table + index * Self::slot_size()
+    }
+
+    fn write_header(&self) {
+        write_u64(&self.memory, LEN_OFFSET, self.len);
+        write_u64(&self.memory, CAPACITY_OFFSET, self.capacity);
+        write_u64(&self.memory, TOMBSTONES_OFFSET, self.tombstones);
+        write_u64(&self.memory, TABLE_OFFSET, self.table);
+        write_u64(&self.memory, OLD_TABLE_OFFSET, self.old_table);
+        write_u64(&self.memory, OLD_CAPACITY_OFFSET, self.old_capacity);
+        write_u64(&self.memory, OLD_LEN_OFFSET, self.old_len);
+        write_u64(&self.memory, MIGRATED_OFFSET, self.migrated);
+        self.memory.write(SEED_OFFSET, &self.seed);
+        self.memory.write(OLD_SEED_OFFSET, &self.old_seed);
+    }
+
+    /// Drop all of the entries and start over with the given number of slots.
+    fn reset(&mut self, capacity: u64) {
+        let size = capacity * Self::slot_size();
+        assert!(
+            ensure_size(&self.memory, HEADER_SIZE + size),
+            "Failed to grow the stable memory."
+        );
+        zero(&self.memory, HEADER_SIZE, size);
+        self.memory.write(0, MAGIC);
+        self.len = 0;
+        self.capacity = capacity;
+        self.tombstones = 0;
+        self.table = HEADER_SIZE;
+        self.old_table = 0;
+        self.old_capacity = 0;
+        self.old_len = 0;
+        self.migrated = 0;
+        self.write_header();
+    }
+
+    #[inline]
+    fn read_tag(&self, table: u64, index: u64) -> u8 {
+        let mut tag = [0; 1];
+        self.memory.read(Self::slot_offset(table, index), &mut tag);
+        tag[0]
+    }
+
+    #[inline]
+    fn write_tag(&self, table: u64, index: u64, tag: u8) {
+        self.memory.write(Self::slot_offset(table, index), &[tag]);
+    }
+
+    #[inline]
+    fn read_key(&self, table: u64, index: u64) -> Vec<u8> {
+        let mut key = vec![0; K::SIZE];
+        self.memory
+            .read(Self::slot_offset(table, index) + 1, &mut key);
+        key
+    }
+
+    #[inline]
+    fn read_value(&self, table: u64, index: u64) -> V {
+        let mut value = vec![0; V::SIZE];
+        self.memory.read(
+            Self::slot_offset(table, index) + 1 + K::SIZE as u64,
+            &mut value,
+        );
+        V::read_from(&value)
+    }
+
+    #[inline]
+    fn write_value(&self, table: u64, index: u64, value: &V) {
+        let mut buf = vec![0; V::SIZE];
+        value.write_to(&mut buf);
+        self.memory
+            .write(Self::slot_offset(table, index) + 1 + K::SIZE as u64, &buf);
+    }
+
+    /// Find the slot for the given key in a table, returns Ok with the index of the slot
+    /// holding the key or Err with the index of the slot where the key should be inserted.
+    fn find_in(&self, table: u64, capacity: u64, key: &[u8]) -> Result<u64, u64> {
+        let seed = if table == self.table {
+            &self.seed
+        } else {
+            &self.old_seed
+        };
+        let mask = capacity - 1;
+        let mut index = hash(seed, key) & mask;
+        let mut insert_at = None;
+
+        loop {
+            match self.read_tag(table, index) {
+                EMPTY => return Err(insert_at.unwrap_or(index)),
+                DELETED => {
+                    insert_at.get_or_insert(index);
+                }
+                _ => {
+                    if self.read_key(table, index) == key {
+                        return Ok(index);
+                    }
+                }
+            }
+
+            index = (index + 1) & mask;
+        }
+    }
+
+    /// Find the table and the slot holding the given key.
+    fn find(&self, key: &[u8]) -> Option<(u64, u64)> {
+        if let Ok(index) = self.find_in(self.table, self.capacity, key) {
+            return Some((self.table, index));
+        }
+
+        if self.old_capacity > 0 {
+            if let Ok(index) = self.find_in(self.old_table, self.old_capacity, key) {
+                return Some((self.old_table, index));
+            }
+        }
+
+        None
+    }
+
+    #[inline]
+    fn encode_key(key: &K) -> Vec<u8> {
+        let mut buf = vec![0; K::SIZE];
+        key.write_to(&mut buf);
+        buf
+    }
+
+    /// Return the number of entries in the map.
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let (table, index) = self.find(&Self::encode_key(key))?;
+        Some(self.read_value(table, index))
+    }
+
+    #[inline]
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.find(&Self::encode_key(key)).is_some()
+    }
+
+    /// Insert the entry to the map, returns the previous value for the key.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.migrate(MIGRATE_STEP);
+        let key = Self::encode_key(&key);
+
+        if let Ok(index) = self.find_in(self.table, self.capacity, &key) {
+            let previous = self.read_value(self.table, index);
+            self.write_value(self.table, index, &value);
+            return Some(previous);
+        }
+
+        // An entry of the old table is replaced by an entry of the new one.
+        let previous = self.remove_old(&key);
+
+        // Keep the load factor including the deleted slots under 3/4, so a probe always ends.
+        // The new table starts with less than half of its slots used by the entries of the old
+        // one, and the old one is empty after capacity / MIGRATE_STEP inserts and removes, so
+        // the old table is always empty by the time the new one is rehashed.
+        if (self.len - self.old_len + self.tombstones + 1) * 4 > self.capacity * 3 {
+            self.migrate(self.old_capacity);
+            let capacity = if (self.len + 1) * 2 > self.capacity {
+                self.capacity * 2
+            } else {
+                self.capacity
+            };
+            self.rehash(capacity, self.seed);
+        }
+
+        let index = self.find_in(self.table, self.capacity, &key).unwrap_err();
+        self.write_slot(index, &key, &value);
+        self.len += 1;
+        self.write_header();
+        previous
+    }
+
+    /// Write an entry to a free slot of the table new entries are inserted to.
+    fn write_slot(&mut self, index: u64, key: &[u8], value: &V) {
+        if self.read_tag(self.table, index) == DELETED {
+            self.tombstones -= 1;
+        }
+
+        let mut slot = vec![OCCUPIED];
+        slot.extend_from_slice(key);
+        self.memory
+            .write(Self::slot_offset(self.table, index), &slot);
+        self.write_value(self.table, index, value);
+    }
+
+    /// Remove the key from the old table, returns the value that was removed.
+    fn remove_old(&mut self, key: &[u8]) -> Option<V> {
+        if self.old_capacity == 0 {
+            return None;
+        }
+
+        let index = self.find_in(self.old_table, self.old_capacity, key).ok()?;
+        let value = self.read_value(self.old_table, index);
+        self.write_tag(self.old_table, index, DELETED);
+        self.old_len -= 1;
+        self.len -= 1;
+        self.write_header();
+        Some(value)
+    }
+
+    /// Remove the entry from the map, returns the value that was removed.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.migrate(MIGRATE_STEP);
+        let key = Self::encode_key(key);
+
+        match self.find_in(self.table, self.capacity, &key) {
+            Ok(index) => {
+                let value = self.read_value(self.table, index);
+                self.write_tag(self.table, index, DELETED);
+                self.len -= 1;
+                self.tombstones += 1;
+                self.write_header();
+                Some(value)
+            }
+            Err(_) => self.remove_old(&key),
+        }
+    }
+
+    /// Remove all of the entries.
+    pub fn clear(&mut self) {
+        self.reset(INITIAL_CAPACITY);
+    }
+
+    /// Return the seed the hashes of new entries are keyed with.
+    #[inline]
+    pub fn seed(&self) -> [u8; 16] {
+        self.seed
+    }
+
+    /// Return false if the map is still keyed with the zero seed of a new map.
+    #[inline]
+    pub fn is_seeded(&self) -> bool {
+        self.seed != [0; 16]
+    }
+
+    /// Start moving the entries to a new table keyed with the given seed. Returns false and
+    /// keeps the current seed while the map is still being rehashed.
+    pub fn reseed(&mut self, seed: [u8; 16]) -> bool {
+        self.migrate(MIGRATE_STEP);
+        if self.old_capacity > 0 {
+            return false;
+        }
+
+        let capacity = if (self.len + 1) * 2 > self.capacity {
+            self.capacity * 2
+        } else {
+            self.capacity
+        };
+        self.rehash(capacity, seed);
+        self.migrate(MIGRATE_STEP);
+        true
+    }
+
+    /// Start moving the entries to a new table with the given capacity keyed with the given
+    /// seed, the current table becomes the old one and has to be empty.
+    fn rehash(&mut self, capacity: u64, seed: [u8; 16]) {
+        debug_assert_eq!(self.old_capacity, 0);
+
+        // The new table goes before the current one if there is room for it there, so the
+        // region does not keep growing when the map is rehashed without growing.
+        let size = capacity * Self::slot_size();
+        let table = if HEADER_SIZE + size <= self.table {
+            HEADER_SIZE
+        } else {
+            self.table + self.capacity * Self::slot_size()
+        };
+        assert!(
+            ensure_size(&self.memory, table + size),
+            "Failed to grow the stable memory."
+        );
+        zero(&self.memory, table, size);
+
+        self.old_table = self.table;
+        self.old_capacity = self.capacity;
+        self.old_len = self.len;
+        self.old_seed = self.seed;
+        self.migrated = 0;
+        self.table = table;
+        self.capacity = capacity;
+        self.seed = seed;
+        self.tombstones = 0;
+        self.write_header();
+    }
+
+    /// Move the entries in the next n slots of the old table to the new one.
+    fn migrate(&mut self, n: u64) {
+        if self.old_capacity == 0 {
+            return;
+        }
+
+        let slot_size = Self::slot_size() as usize;
+        let end = self.migrated.saturating_add(n).min(self.old_capacity);
+        while self.migrated < end && self.old_len > 0 {
+            let index = self.migrated;
+            self.migrated += 1;
+
+            if self.read_tag(self.old_table, index) != OCCUPIED {
+                continue;
+            }
+
+            let mut slot = vec![0; slot_size];
+            self.memory
+                .read(Self::slot_offset(self.old_table, index), &mut slot);
+            let new_index = self
+                .find_in(self.table, self.capacity, &slot[1..1 + K::SIZE])
+                .unwrap_err();
+            if self.read_tag(self.table, new_index) == DELETED {
+                self.tombstones -= 1;
+            }
+            self.memory
+                .write(Self::slot_offset(self.table, new_index), &slot);
+
+            // The slot stays in the probe sequences of the keys that were not moved yet.
+            self.write_tag(self.old_table, index, DELETED);
+            self.old_len -= 1;
+        }
+
+        if self.old_len == 0 {
+            self.old_table = 0;
+            self.old_capacity = 0;
+            self.migrated = 0;
+        }
+        self.write_header();
+    }
+
+    /// Iterate over the entries of the map in an unspecified order.
+    pub fn iter(&self) -> Iter<'_, K, V, M> {
+        Iter {
+            map: self,
+            table: self.table,
+            capacity: self.capacity,
+            index: 0,
+        }
+    }
+}
+
+pub struct Iter<'a, K: Storable, V: Storable, M: Memory> {
+    map: &'a StableHashMap<K, V, M>,
+    /// The table being iterated, the new table is followed by the old one.
+    table: u64,
+    capacity: u64,
+    index: u64,
+}
+
+impl<'a, K: Storable, V: Storable, M: Memory> Iterator for Iter<'a, K, V, M> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            while self.index < self.capacity {
+                let index = self.index;
+                self.index += 1;
+
+                if self.map.read_tag(self.table, index) == OCCUPIED {
+                    let key = K::read_from(&self.map.read_key(self.table, index));
+                    return Some((key, self.map.read_value(self.table, index)));
+                }
+            }
+
+            if self.table != self.map.table || self.map.old_capacity == 0 {
+                return None;
+            }
+
+            self.table = self.map.old_table;
+            self.capacity = self.map.old_capacity;
+            self.index = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::VecMemory;
+
+    #[test]
+    fn insert_get_remove() {
+        let mut map = StableHashMap::<u64, u64, _>::new(VecMemory::default());
+        assert_eq!(map.insert(1, 10), None);
+        assert_eq!(map.insert(2, 20), None);
+        assert_eq!(map.insert(1, 11), Some(10));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(&1), Some(11));
+        assert_eq!(map.get(&3), None);
+        assert_eq!(map.remove(&1), Some(11));
+        assert_eq!(map.remove(&1), None);
+        assert_eq!(map.get(&1), None);
+        assert_eq!(map.get(&2), Some(20));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn grow() {
+        let mut map = StableHashMap::<u64, u64, _>::new(VecMemory::default());
+        for i in 0..5_000 {
+            map.insert(i, i * 2);
+        }
+        for i in (0..5_000).step_by(2) {
+            map.remove(&i);
+        }
+
+        assert_eq!(map.len(), 2_500);
+        for i in 0..5_000 {
+            let expected = if i % 2 == 0 { None } else { Some(i * 2) };
+            assert_eq!(map.get(&i), expected);
+        }
+
+        let mut entries: Vec<(u64, u64)> = map.iter().collect();
+        entries.sort();
+        assert_eq!(entries.len(), 2_500);
+        assert_eq!(entries[0], (1, 2));
+    }
+
+    #[test]
+    fn incremental_rehash() {
+        let memory = VecMemory::default();
+        let mut map = StableHashMap::<u64, u64, _>::new(memory.clone());
+        for i in 0..800 {
+            map.insert(i, i);
+        }
+
+        // The table grew but most of the entries are still in the old one.
+        assert_eq!(map.capacity, 2 * INITIAL_CAPACITY);
+        assert!(map.old_len > 0);
+        assert_eq!(map.len(), 800);
+        assert_eq!(map.iter().count(), 800);
+
+        // Entries of the old table can be replaced, removed and iterated while it is moved.
+        let old_key = (0..800)
+            .find(|i| {
+                let key = StableHashMap::<u64, u64, VecMemory>::encode_key(i);
+                map.find_in(map.table, map.capacity, &key).is_err()
+            })
+            .unwrap();
+        assert_eq!(map.insert(old_key, 7), Some(old_key));
+        assert_eq!(map.get(&old_key), Some(7));
+        assert_eq!(map.remove(&old_key), Some(7));
+        assert_eq!(map.get(&old_key), None);
+        assert_eq!(map.len(), 799);
+
+        let map = StableHashMap::<u64, u64, _>::load(memory);
+        for i in 0..800 {
+            let expected = if i == old_key { None } else { Some(i) };
+            assert_eq!(map.get(&i), expected);
+        }
+        let mut entries: Vec<(u64, u64)> = map.iter().collect();
+        entries.sort();
+        entries.dedup();
+        assert_eq!(entries.len(), 799);
+    }
+
+    #[test]
+    fn rehash_reuses_memory() {
+        let memory = VecMemory::default();
+        let mut map = StableHashMap::<u64, u64, _>::new(memory.clone());
+
+        // Every key is removed right after it is inserted, so the table is only rehashed to
+        // clear the deleted slots.
+        for i in 0..20_000 {
+            map.insert(i, i);
+            map.remove(&i);
+        }
+
+        assert!(map.is_empty());
+        assert_eq!(map.capacity, INITIAL_CAPACITY);
+        assert!(memory.size() < 3);
+    }
+
+    #[test]
+    fn load() {
+        let memory = VecMemory::default();
+        let mut map = StableHashMap::<u64, (), _>::new(memory.clone());
+        map.insert(7, ());
+
+        let map = StableHashMap::<u64, (), _>::load(memory);
+        assert_eq!(map.len(), 1);
+        assert!(map.contains_key(&7));
+        assert!(!map.contains_key(&8));
+    }
+
+    #[test]
+    fn init() {
+        let memory = VecMemory::default();
+        let mut map = StableHashMap::<u64, u64, _>::init(memory.clone());
+        assert!(map.is_empty());
+        map.insert(1, 2);
+
+        let map = StableHashMap::<u64, u64, _>::init(memory);
+        assert_eq!(map.get(&1), Some(2));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn siphash() {
+        use std::hash::{Hasher, SipHasher};
+
+        let seed: [u8; 16] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+        for len in 0..20 {
+            let bytes: Vec<u8> = (0..len).collect();
+            let mut hasher = SipHasher::new_with_keys(
+                u64::from_le_bytes(seed[..8].try_into().unwrap()),
+                u64::from_le_bytes(seed[8..].try_into().unwrap()),
+            );
+            hasher.write(&bytes);
+            assert_eq!(hash(&seed, &bytes), hasher.finish());
+        }
+    }
+
+    #[test]
+    fn reseed() {
+        let memory = VecMemory::default();
+        let mut map = StableHashMap::<u64, u64, _>::new(memory.clone());
+        assert!(!map.is_seeded());
+        for i in 0..500 {
+            map.insert(i, i);
+        }
+
+        assert!(map.reseed([7; 16]));
+        assert!(map.is_seeded());
+        assert_eq!(map.seed(), [7; 16]);
+        // The entries are moved to the new table incrementally, and the map can not be
+        // reseeded again before they all are.
+        assert!(map.old_len > 0);
+        assert!(!map.reseed([8; 16]));
+        assert_eq!(map.seed(), [7; 16]);
+
+        let mut map = StableHashMap::<u64, u64, _>::load(memory.clone());
+        assert_eq!(map.seed(), [7; 16]);
+        for i in 500..1_000 {
+            map.insert(i, i);
+        }
+        assert_eq!(map.old_len, 0);
+        assert_eq!(map.len(), 1_000);
+        for i in 0..1_000 {
+            assert_eq!(map.get(&i), Some(i));
+        }
+
+        map.clear();
+        assert_eq!(map.seed(), [7; 16]);
+    }
+}
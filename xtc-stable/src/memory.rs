@@ -0,0 +1,126 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// The size of a WebAssembly page in bytes.
+pub const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+/// A growable linear memory, this is the interface of the stable memory of a canister.
+pub trait Memory {
+    /// Return the current size of the memory in pages.
+    fn size(&self) -> u64;
+
+    /// Grow the memory by the given number of pages, returns false if the memory could not be
+    /// grown.
+    fn grow(&self, pages: u64) -> bool;
+
+    /// Fill the buffer with the data starting at the given offset.
+    ///
+    /// # Panics
+    /// If the read is out of the bounds of the memory.
+    fn read(&self, offset: u64, dst: &mut [u8]);
+
+    /// Write the buffer at the given offset.
+    ///
+    /// # Panics
+    /// If the write is out of the bounds of the memory.
+    fn write(&self, offset: u64, src: &[u8]);
+}
+
+impl<M: Memory + ?Sized> Memory for Box<M> {
+    fn size(&self) -> u64 {
+        (**self).size()
+    }
+
+    fn grow(&self, pages: u64) -> bool {
+        (**self).grow(pages)
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        (**self).read(offset, dst)
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        (**self).write(offset, src)
+    }
+}
+
+/// A memory backed by a vector on the heap, used in the tests and anywhere the data does not
+/// need to survive an upgrade. Clones share the same underlying buffer.
+#[derive(Clone, Default)]
+pub struct VecMemory(Rc<RefCell<Vec<u8>>>);
+
+impl Memory for VecMemory {
+    fn size(&self) -> u64 {
+        self.0.borrow().len() as u64 / WASM_PAGE_SIZE
+    }
+
+    fn grow(&self, pages: u64) -> bool {
+        let mut data = self.0.borrow_mut();
+        let size = data.len() + (pages * WASM_PAGE_SIZE) as usize;
+        data.resize(size, 0);
+        true
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        let offset = offset as usize;
+        dst.copy_from_slice(&self.0.borrow()[offset..offset + dst.len()]);
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        let offset = offset as usize;
+        self.0.borrow_mut()[offset..offset + src.len()].copy_from_slice(src);
+    }
+}
+
+/// Grow the memory so it is at least the given number of bytes, returns false if the memory
+/// could not be grown.
+pub fn ensure_size<M: Memory + ?Sized>(memory: &M, bytes: u64) -> bool {
+    let pages = bytes.div_ceil(WASM_PAGE_SIZE);
+    let size = memory.size();
+    pages <= size || memory.grow(pages - size)
+}
+
+#[inline]
+pub(crate) fn read_u64<M: Memory + ?Sized>(memory: &M, offset: u64) -> u64 {
+    let mut buf = [0; 8];
+    memory.read(offset, &mut buf);
+    u64::from_le_bytes(buf)
+}
+
+#[inline]
+pub(crate) fn write_u64<M: Memory + ?Sized>(memory: &M, offset: u64, value: u64) {
+    memory.write(offset, &value.to_le_bytes());
+}
+
+/// Write zeros to the given range of the memory.
+pub(crate) fn zero<M: Memory + ?Sized>(memory: &M, offset: u64, len: u64) {
+    let chunk = vec![0; WASM_PAGE_SIZE as usize];
+    let mut written = 0;
+    while written < len {
+        let n = (len - written).min(WASM_PAGE_SIZE);
+        memory.write(offset + written, &chunk[..n as usize]);
+        written += n;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vec_memory() {
+        let memory = VecMemory::default();
+        assert_eq!(memory.size(), 0);
+        assert!(ensure_size(&memory, 1));
+        assert_eq!(memory.size(), 1);
+        assert!(ensure_size(&memory, WASM_PAGE_SIZE + 1));
+        assert_eq!(memory.size(), 2);
+
+        write_u64(&memory, WASM_PAGE_SIZE - 4, 17);
+        assert_eq!(read_u64(&memory, WASM_PAGE_SIZE - 4), 17);
+
+        // Clones share the same buffer.
+        let clone = memory.clone();
+        assert_eq!(read_u64(&clone, WASM_PAGE_SIZE - 4), 17);
+    }
+}
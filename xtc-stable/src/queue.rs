@@ -0,0 +1,253 @@
+use crate::memory::{ensure_size, read_u64, write_u64, Memory, WASM_PAGE_SIZE};
+use crate::storable::Storable;
+use std::marker::PhantomData;
+
+const MAGIC: &[u8; 4] = b"XQUE";
+const HEADER_SIZE: u64 = 64;
+const CAPACITY_OFFSET: u64 = 8;
+const HEAD_OFFSET: u64 = 16;
+const LEN_OFFSET: u64 = 24;
+
+/// A FIFO queue of fixed size items stored in a ring buffer in a region of the stable memory.
+/// The memory is only grown as the slots are used, and the ring doubles its capacity when an
+/// item is pushed to a full queue.
+pub struct StableQueue<T: Storable, M: Memory> {
+    memory: M,
+    capacity: u64,
+    /// The number of items that were ever popped from the queue, the front of the queue is
+    /// in the slot `head % capacity`.
+    head: u64,
+    len: u64,
+    data: PhantomData<T>,
+}
+
+impl<T: Storable, M: Memory> StableQueue<T, M> {
+    /// Create an empty queue in the given memory with room for capacity items before the ring
+    /// has to grow, any data already stored in the memory is dropped.
+    pub fn new(memory: M, capacity: u64) -> Self {
+        assert!(
+            ensure_size(&memory, HEADER_SIZE),
+            "Failed to grow the stable memory."
+        );
+        memory.write(0, MAGIC);
+
+        let queue = StableQueue {
+            memory,
+            capacity: capacity.max(1),
+            head: 0,
+            len: 0,
+            data: PhantomData,
+        };
+        queue.write_header();
+        queue
+    }
+
+    /// Load the queue stored in the given memory, or create an empty queue with the given
+    /// capacity if the memory is empty.
+    pub fn init(memory: M, capacity: u64) -> Self {
+        if memory.size() == 0 {
+            StableQueue::new(memory, capacity)
+        } else {
+            StableQueue::load(memory)
+        }
+    }
+
+    /// Load a queue previously created with new from the given memory.
+    ///
+    /// # Panics
+    /// If the memory does not contain a queue.
+    pub fn load(memory: M) -> Self {
+        let mut magic = [0; 4];
+        if memory.size() > 0 {
+            memory.read(0, &mut magic);
+        }
+        assert_eq!(&magic, MAGIC, "The memory does not contain a queue.");
+
+        StableQueue {
+            capacity: read_u64(&memory, CAPACITY_OFFSET),
+            head: read_u64(&memory, HEAD_OFFSET),
+            len: read_u64(&memory, LEN_OFFSET),
+            memory,
+            data: PhantomData,
+        }
+    }
+
+    fn write_header(&self) {
+        write_u64(&self.memory, CAPACITY_OFFSET, self.capacity);
+        write_u64(&self.memory, HEAD_OFFSET, self.head);
+        write_u64(&self.memory, LEN_OFFSET, self.len);
+    }
+
+    #[inline]
+    fn slot_offset(&self, position: u64) -> u64 {
+        HEADER_SIZE + (position % self.capacity) * T::SIZE as u64
+    }
+
+    /// Return the number of items in the queue.
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return the number of items ever popped from the queue, which is also the position of
+    /// the front item among all the items ever pushed.
+    #[inline]
+    pub fn head(&self) -> u64 {
+        self.head
+    }
+
+    /// Double the capacity of the ring. The positions of the items do not change, so only
+    /// the items wrapping around the end of the old ring have to move to their new slots,
+    /// which are all past the end of the old ring.
+    fn grow(&mut self) {
+        let capacity = self.capacity * 2;
+        let head = self.head % capacity;
+
+        let (from, to, n) = if head < self.capacity {
+            (0, self.capacity, head)
+        } else {
+            (head - self.capacity, head, capacity - head)
+        };
+
+        let size = T::SIZE as u64;
+        assert!(
+            ensure_size(&self.memory, HEADER_SIZE + (to + n) * size),
+            "Failed to grow the stable memory."
+        );
+
+        let mut buf = vec![0; WASM_PAGE_SIZE as usize];
+        let mut copied = 0;
+        while copied < n * size {
+            let chunk = (n * size - copied).min(WASM_PAGE_SIZE) as usize;
+            self.memory
+                .read(HEADER_SIZE + from * size + copied, &mut buf[..chunk]);
+            self.memory
+                .write(HEADER_SIZE + to * size + copied, &buf[..chunk]);
+            copied += chunk as u64;
+        }
+
+        self.capacity = capacity;
+        self.write_header();
+    }
+
+    /// Add an item to the end of the queue, the ring grows if the queue is full.
+    ///
+    /// # Panics
+    /// If the memory could not be grown.
+    pub fn push(&mut self, item: &T) {
+        if self.len == self.capacity {
+            self.grow();
+        }
+
+        let offset = self.slot_offset(self.head + self.len);
+        assert!(
+            ensure_size(&self.memory, offset + T::SIZE as u64),
+            "Failed to grow the stable memory."
+        );
+
+        let mut buf = vec![0; T::SIZE];
+        item.write_to(&mut buf);
+        self.memory.write(offset, &buf);
+        self.len += 1;
+        write_u64(&self.memory, LEN_OFFSET, self.len);
+    }
+
+    /// Return the item at the given index, the front of the queue is at index zero.
+    pub fn get(&self, index: u64) -> Option<T> {
+        if index >= self.len {
+            return None;
+        }
+
+        let mut buf = vec![0; T::SIZE];
+        self.memory
+            .read(self.slot_offset(self.head + index), &mut buf);
+        Some(T::read_from(&buf))
+    }
+
+    /// Return the items in the given range of indexes, the range is clamped to the length of
+    /// the queue.
+    pub fn range(&self, start: u64, end: u64) -> Vec<T> {
+        (start..end.min(self.len))
+            .filter_map(|index| self.get(index))
+            .collect()
+    }
+
+    /// Remove up to n items from the front of the queue.
+    pub fn pop_front(&mut self, n: u64) {
+        let n = n.min(self.len);
+        self.head += n;
+        self.len -= n;
+        self.write_header();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::VecMemory;
+
+    #[test]
+    fn push_and_pop() {
+        let mut queue = StableQueue::<u64, _>::new(VecMemory::default(), 4);
+        for i in 0..4 {
+            queue.push(&i);
+        }
+        assert_eq!(queue.range(0, 10), vec![0, 1, 2, 3]);
+
+        queue.pop_front(3);
+        queue.push(&4);
+        queue.push(&5);
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.head(), 3);
+        assert_eq!(queue.get(0), Some(3));
+        assert_eq!(queue.range(1, 3), vec![4, 5]);
+        assert_eq!(queue.get(3), None);
+    }
+
+    #[test]
+    fn grow() {
+        let memory = VecMemory::default();
+
+        // The items wrap around the end of the ring before it grows.
+        let mut queue = StableQueue::<u64, _>::new(memory.clone(), 4);
+        for i in 0..4 {
+            queue.push(&i);
+        }
+        queue.pop_front(3);
+        for i in 4..11 {
+            queue.push(&i);
+        }
+        assert_eq!(queue.range(0, 20), (3..11).collect::<Vec<_>>());
+
+        // The head is past the middle of the grown ring.
+        queue.pop_front(7);
+        for i in 11..30 {
+            queue.push(&i);
+        }
+        assert_eq!(queue.range(0, 30), (10..30).collect::<Vec<_>>());
+
+        let queue = StableQueue::<u64, _>::load(memory);
+        assert_eq!(queue.len(), 20);
+        assert_eq!(queue.get(0), Some(10));
+        assert_eq!(queue.get(19), Some(29));
+    }
+
+    #[test]
+    fn load() {
+        let memory = VecMemory::default();
+        let mut queue = StableQueue::<u64, _>::new(memory.clone(), 8);
+        queue.push(&1);
+        queue.push(&2);
+        queue.pop_front(1);
+
+        let queue = StableQueue::<u64, _>::load(memory);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.head(), 1);
+        assert_eq!(queue.get(0), Some(2));
+    }
+}
@@ -0,0 +1,34 @@
+/// A value with a fixed size encoding, so it can be stored in the slots of the stable
+/// structures.
+pub trait Storable: Sized {
+    /// The number of bytes used to encode any value of this type.
+    const SIZE: usize;
+
+    /// Encode the value into the buffer, the buffer is exactly SIZE bytes.
+    fn write_to(&self, buf: &mut [u8]);
+
+    /// Decode a value from a buffer of exactly SIZE bytes.
+    fn read_from(buf: &[u8]) -> Self;
+}
+
+impl Storable for u64 {
+    const SIZE: usize = 8;
+
+    fn write_to(&self, buf: &mut [u8]) {
+        buf.copy_from_slice(&self.to_le_bytes());
+    }
+
+    fn read_from(buf: &[u8]) -> Self {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(buf);
+        u64::from_le_bytes(bytes)
+    }
+}
+
+impl Storable for () {
+    const SIZE: usize = 0;
+
+    fn write_to(&self, _: &mut [u8]) {}
+
+    fn read_from(_: &[u8]) -> Self {}
+}
@@ -0,0 +1,906 @@
+//! The canisters created through XTC and the typed proxies of the management canister methods,
+//! so the canisters that have XTC among their controllers can be managed by their other
+//! controllers and paid for with XTC.
+
+use crate::common_types::{Account, Subaccount};
+use crate::cycles_wallet::{ReceiveOptions, WalletCanisterSettings};
+use crate::fee::{compute_fee, FeeCollector, FeeOperation};
+use crate::history::{
+    CanisterAction, HistoryBuffer, Transaction, TransactionKind, TransactionStatus,
+};
+use crate::ledger::Ledger;
+use crate::management::{PauseFlags, PauseTarget};
+use crate::memory::{self, Region};
+use ic_kit::candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use ic_kit::candid::{encode_args, CandidType, Nat};
+use ic_kit::interfaces::management::{
+    CanisterSettings, CreateCanister, CreateCanisterArgument, WithCanisterId,
+};
+use ic_kit::interfaces::Method;
+use ic_kit::macros::*;
+use ic_kit::{get_context, Context, Principal, RejectionCode};
+use serde::*;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::future::Future;
+use xtc_stable::{StableHashMap, Storable};
+
+/// The drain module, `build.js` builds it before XTC. The native builds only run against the mock
+/// management canister and never install it, so they do not depend on the build order.
+#[cfg(all(target_family = "wasm", debug_cfg))]
+const DRAIN_WASM: &[u8] =
+    include_bytes!("../../target/wasm32-unknown-unknown/debug/xtc_drain-deb-opt.wasm");
+
+#[cfg(all(target_family = "wasm", not(debug_cfg)))]
+const DRAIN_WASM: &[u8] =
+    include_bytes!("../../target/wasm32-unknown-unknown/release/xtc_drain-rel-opt.wasm");
+
+#[cfg(not(target_family = "wasm"))]
+const DRAIN_WASM: &[u8] = b"\0asm\x01\0\0\0";
+
+/// The cycles the drain module keeps to pay for its call to `wallet_receive`, they are burned
+/// along with the canister. Matches the reserve of xtc-drain.
+pub const DRAIN_RESERVE: u64 = 1_000_000_000;
+
+/// The canisters being drained by `drain_and_delete`, with the account credited with their
+/// cycles and the cycles XTC accepted from them so far. Not persisted since upgrades require
+/// the canister to be stopped.
+#[derive(Default)]
+pub struct Drains(HashMap<Principal, Drain>);
+
+struct Drain {
+    to: Account,
+    accepted: u64,
+}
+
+impl Drains {
+    /// Credit the cycles the canister sends to `wallet_receive` to the given account from now on.
+    pub fn start(canister_id: Principal, to: Account) {
+        get_context()
+            .get_mut::<Drains>()
+            .0
+            .insert(canister_id, Drain { to, accepted: 0 });
+    }
+
+    /// Stop crediting the cycles of the canister, returns the cycles accepted from it.
+    pub fn finish(canister_id: &Principal) -> u64 {
+        get_context()
+            .get_mut::<Drains>()
+            .0
+            .remove(canister_id)
+            .map_or(0, |drain| drain.accepted)
+    }
+
+    #[inline]
+    pub fn contains(canister_id: &Principal) -> bool {
+        get_context().get::<Drains>().0.contains_key(canister_id)
+    }
+
+    /// Credit the cycles accepted from a canister being drained to the account of the drain,
+    /// they are not charged the mint fee.
+    pub fn credit(canister_id: &Principal, cycles: u64) {
+        let ic = get_context();
+        if let Some(drain) = ic.get_mut::<Drains>().0.get_mut(canister_id) {
+            drain.accepted += cycles;
+            ic.get_mut::<Ledger>().deposit(&drain.to, cycles);
+        }
+    }
+}
+
+/// A canister created through XTC together with the cycles spent on it.
+#[derive(CandidType, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct CreatedCanister {
+    pub canister_id: Principal,
+    pub creator: Principal,
+    pub created_at: u64,
+    pub initial_cycles: u64,
+    /// The cycles sent to the canister after its creation through burn, wallet_send and
+    /// wallet_call.
+    pub cycles_sent: u64,
+}
+
+#[inline]
+fn write_principal(buf: &mut [u8], principal: &Principal) {
+    let bytes = principal.as_slice();
+    buf[0] = bytes.len() as u8;
+    buf[1..1 + bytes.len()].copy_from_slice(bytes);
+}
+
+#[inline]
+fn read_principal(buf: &[u8]) -> Principal {
+    Principal::from_slice(&buf[1..1 + buf[0] as usize])
+}
+
+/// A principal as it is stored in the stable memory.
+#[derive(Clone, Copy)]
+struct StoredPrincipal(Principal);
+
+impl Storable for StoredPrincipal {
+    // The length of the principal and up to 29 bytes of principal.
+    const SIZE: usize = 30;
+
+    fn write_to(&self, buf: &mut [u8]) {
+        buf.iter_mut().for_each(|byte| *byte = 0);
+        write_principal(buf, &self.0);
+    }
+
+    fn read_from(buf: &[u8]) -> Self {
+        StoredPrincipal(read_principal(buf))
+    }
+}
+
+/// The position of a canister in the list of the canisters of its creator.
+struct CreatorIndex {
+    creator: Principal,
+    index: u64,
+}
+
+impl Storable for CreatorIndex {
+    const SIZE: usize = 38;
+
+    fn write_to(&self, buf: &mut [u8]) {
+        StoredPrincipal(self.creator).write_to(&mut buf[..30]);
+        buf[30..].copy_from_slice(&self.index.to_le_bytes());
+    }
+
+    fn read_from(buf: &[u8]) -> Self {
+        CreatorIndex {
+            creator: read_principal(buf),
+            index: u64::read_from(&buf[30..]),
+        }
+    }
+}
+
+/// A canister as it is stored in the registry, along with its position in the list of its
+/// creator.
+struct StoredCanister {
+    canister: CreatedCanister,
+    index: u64,
+}
+
+impl Storable for StoredCanister {
+    // Two principals followed by the three amounts and the index.
+    const SIZE: usize = 92;
+
+    fn write_to(&self, buf: &mut [u8]) {
+        StoredPrincipal(self.canister.canister_id).write_to(&mut buf[..30]);
+        StoredPrincipal(self.canister.creator).write_to(&mut buf[30..60]);
+        buf[60..68].copy_from_slice(&self.canister.created_at.to_le_bytes());
+        buf[68..76].copy_from_slice(&self.canister.initial_cycles.to_le_bytes());
+        buf[76..84].copy_from_slice(&self.canister.cycles_sent.to_le_bytes());
+        buf[84..92].copy_from_slice(&self.index.to_le_bytes());
+    }
+
+    fn read_from(buf: &[u8]) -> Self {
+        StoredCanister {
+            canister: CreatedCanister {
+                canister_id: read_principal(&buf[..30]),
+                creator: read_principal(&buf[30..60]),
+                created_at: u64::read_from(&buf[60..68]),
+                initial_cycles: u64::read_from(&buf[68..76]),
+                cycles_sent: u64::read_from(&buf[76..84]),
+            },
+            index: u64::read_from(&buf[84..92]),
+        }
+    }
+}
+
+/// The canisters created through XTC, indexed by the principal that created them. The
+/// registry lives in the stable memory.
+pub struct CanisterRegistry {
+    canisters: StableHashMap<StoredPrincipal, StoredCanister, Region>,
+    /// The canisters of each creator in the order they were created, the removed canisters
+    /// leave a gap.
+    by_creator: StableHashMap<CreatorIndex, StoredPrincipal, Region>,
+    /// The number of canisters ever registered for each creator.
+    counts: StableHashMap<StoredPrincipal, u64, Region>,
+}
+
+impl Default for CanisterRegistry {
+    /// Create an empty registry, this drops the canisters stored in the stable memory.
+    fn default() -> Self {
+        CanisterRegistry {
+            canisters: StableHashMap::new(memory::region(memory::CANISTERS)),
+            by_creator: StableHashMap::new(memory::region(memory::CANISTERS_BY_CREATOR)),
+            counts: StableHashMap::new(memory::region(memory::CANISTER_COUNTS)),
+        }
+    }
+}
+
+impl CanisterRegistry {
+    /// Load the canisters stored in the stable memory.
+    pub fn restore() -> Self {
+        CanisterRegistry {
+            canisters: StableHashMap::init(memory::region(memory::CANISTERS)),
+            by_creator: StableHashMap::init(memory::region(memory::CANISTERS_BY_CREATOR)),
+            counts: StableHashMap::init(memory::region(memory::CANISTER_COUNTS)),
+        }
+    }
+
+    /// Return false while one of the maps is keyed with the zero seed, see `crate::seed`.
+    #[inline]
+    pub fn is_seeded(&self) -> bool {
+        self.canisters.is_seeded() && self.by_creator.is_seeded() && self.counts.is_seeded()
+    }
+
+    /// Key the hashes of the maps that do not have a seed yet with the given seed, see
+    /// `StableHashMap::reseed`.
+    pub fn reseed(&mut self, seed: [u8; 16]) {
+        if !self.canisters.is_seeded() {
+            self.canisters.reseed(seed);
+        }
+        if !self.by_creator.is_seeded() {
+            self.by_creator.reseed(seed);
+        }
+        if !self.counts.is_seeded() {
+            self.counts.reseed(seed);
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        creator: Principal,
+        canister_id: Principal,
+        cycles: u64,
+        created_at: u64,
+    ) {
+        let index = self.counts.get(&StoredPrincipal(creator)).unwrap_or(0);
+        self.counts.insert(StoredPrincipal(creator), index + 1);
+        self.by_creator.insert(
+            CreatorIndex { creator, index },
+            StoredPrincipal(canister_id),
+        );
+        self.canisters.insert(
+            StoredPrincipal(canister_id),
+            StoredCanister {
+                canister: CreatedCanister {
+                    canister_id,
+                    creator,
+                    created_at,
+                    initial_cycles: cycles,
+                    cycles_sent: 0,
+                },
+                index,
+            },
+        );
+    }
+
+    /// Forget a deleted canister, the canisters not created through XTC are ignored.
+    pub fn remove(&mut self, canister_id: &Principal) {
+        if let Some(stored) = self.canisters.remove(&StoredPrincipal(*canister_id)) {
+            self.by_creator.remove(&CreatorIndex {
+                creator: stored.canister.creator,
+                index: stored.index,
+            });
+        }
+    }
+
+    /// Count the cycles sent to the canister, the canisters not created through XTC are ignored.
+    pub fn add_cycles_sent(&mut self, canister_id: &Principal, cycles: u64) {
+        if let Some(mut stored) = self.canisters.get(&StoredPrincipal(*canister_id)) {
+            stored.canister.cycles_sent = stored.canister.cycles_sent.saturating_add(cycles);
+            self.canisters.insert(StoredPrincipal(*canister_id), stored);
+        }
+    }
+
+    #[inline]
+    pub fn canister(&self, canister_id: &Principal) -> Option<CreatedCanister> {
+        self.canisters
+            .get(&StoredPrincipal(*canister_id))
+            .map(|stored| stored.canister)
+    }
+
+    /// The canisters created by the given principal, in the order they were created.
+    pub fn created_by(&self, creator: &Principal) -> Vec<CreatedCanister> {
+        let count = self.counts.get(&StoredPrincipal(*creator)).unwrap_or(0);
+        (0..count)
+            .filter_map(|index| {
+                self.by_creator.get(&CreatorIndex {
+                    creator: *creator,
+                    index,
+                })
+            })
+            .filter_map(|canister_id| self.canister(&canister_id.0))
+            .collect()
+    }
+}
+
+#[derive(CandidType, Debug, Deserialize, Eq, PartialEq)]
+pub struct CanisterCostReport {
+    pub canister_id: Principal,
+    pub creator: Principal,
+    pub created_at: u64,
+    pub initial_cycles: u64,
+    pub cycles_sent: u64,
+    /// The initial cycles together with the cycles sent since.
+    pub total_cycles: u64,
+}
+
+#[query]
+pub fn my_canisters() -> Vec<CreatedCanister> {
+    let ic = get_context();
+    ic.get::<CanisterRegistry>().created_by(&ic.caller())
+}
+
+#[query]
+pub fn canister_cost_report(canister_id: Principal) -> Option<CanisterCostReport> {
+    let ic = get_context();
+    let canister = ic.get::<CanisterRegistry>().canister(&canister_id)?;
+
+    Some(CanisterCostReport {
+        canister_id,
+        creator: canister.creator,
+        created_at: canister.created_at,
+        initial_cycles: canister.initial_cycles,
+        cycles_sent: canister.cycles_sent,
+        total_cycles: canister.initial_cycles.saturating_add(canister.cycles_sent),
+    })
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InstallMode {
+    #[serde(rename = "install")]
+    Install,
+    #[serde(rename = "reinstall")]
+    Reinstall,
+    #[serde(rename = "upgrade")]
+    Upgrade,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CanisterStatus {
+    #[serde(rename = "running")]
+    Running,
+    #[serde(rename = "stopping")]
+    Stopping,
+    #[serde(rename = "stopped")]
+    Stopped,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DefiniteCanisterSettings {
+    pub controllers: Vec<Principal>,
+    pub compute_allocation: Nat,
+    pub memory_allocation: Nat,
+    pub freezing_threshold: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CanisterStatusResult {
+    pub status: CanisterStatus,
+    pub settings: DefiniteCanisterSettings,
+    pub module_hash: Option<Vec<u8>>,
+    pub memory_size: Nat,
+    pub cycles: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ManageCanisterArgs {
+    pub canister_id: Principal,
+    pub from_subaccount: Option<Subaccount>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct InstallCodeArgs {
+    pub canister_id: Principal,
+    pub mode: InstallMode,
+    #[serde(with = "serde_bytes")]
+    pub wasm_module: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub arg: Vec<u8>,
+    pub from_subaccount: Option<Subaccount>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct UpdateSettingsArgs {
+    pub canister_id: Principal,
+    pub settings: CanisterSettings,
+    pub from_subaccount: Option<Subaccount>,
+}
+
+#[derive(CandidType)]
+struct CanisterIdRecord {
+    canister_id: Principal,
+}
+
+#[derive(CandidType)]
+struct CanisterInstall<'a> {
+    mode: InstallMode,
+    canister_id: Principal,
+    #[serde(with = "serde_bytes")]
+    wasm_module: &'a [u8],
+    #[serde(with = "serde_bytes")]
+    arg: Vec<u8>,
+}
+
+#[derive(CandidType)]
+struct UpdateSettings {
+    canister_id: Principal,
+    settings: CanisterSettings,
+}
+
+async fn call_management<T: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(
+    method: &str,
+    args: T,
+) -> Result<R, String> {
+    get_context()
+        .call(Principal::management_canister(), method, args)
+        .await
+        .map_err(|(code, msg)| {
+            format!("An error happened during the call: {}: {}", code as u8, msg)
+        })
+}
+
+/// Fetch the status of the canister and check that the caller is one of its controllers, the
+/// management canister only answers while XTC is a controller too.
+async fn controlled_status(
+    canister_id: Principal,
+    caller: Principal,
+) -> Result<CanisterStatusResult, String> {
+    let (status,): (CanisterStatusResult,) =
+        call_management("canister_status", (CanisterIdRecord { canister_id },)).await?;
+
+    if !status.settings.controllers.contains(&caller) {
+        return Err("Only the controllers of the canister can manage it.".to_string());
+    }
+
+    Ok(status)
+}
+
+/// Check that the caller controls the canister, then charge the fee of a management call, run it
+/// and record it in the history. The call returns its result and the cycles it moved, which
+/// may be moved by a failed call too.
+async fn manage<T, F, Fut>(
+    canister_id: Principal,
+    from_subaccount: Option<Subaccount>,
+    action: CanisterAction,
+    call: F,
+) -> Result<T, String>
+where
+    F: FnOnce(Account, CanisterStatusResult) -> Fut,
+    Fut: Future<Output = (Result<T, String>, u64)>,
+{
+    PauseFlags::guard(PauseTarget::ManageCanister);
+
+    let ic = get_context();
+    let caller = ic.caller();
+    let from = Account::new(caller, from_subaccount);
+
+    // The callers that do not control the canister are rejected before they are charged.
+    let status = controlled_status(canister_id, caller).await?;
+
+    let fee = compute_fee(FeeOperation::ManageCanister, 0);
+    ic.get_mut::<Ledger>()
+        .withdraw(&from, fee)
+        .map_err(|_| "Insufficient Balance".to_string())?;
+
+    let (result, cycles) = call(from, status).await;
+    let status = match &result {
+        Ok(_) => TransactionStatus::SUCCEEDED,
+        Err(_) => TransactionStatus::FAILED,
+    };
+
+    ic.get_mut::<HistoryBuffer>().push(Transaction {
+        timestamp: ic.time(),
+        cycles,
+        fee,
+        kind: TransactionKind::CanisterManaged {
+            from: caller,
+            canister: canister_id,
+            action,
+            from_subaccount: from.subaccount,
+        },
+        status,
+    });
+    if result.is_ok() {
+        FeeCollector::collect(fee);
+    }
+
+    result
+}
+
+#[update]
+pub async fn wallet_install_code(args: InstallCodeArgs) -> Result<(), String> {
+    let canister_id = args.canister_id;
+    let install = CanisterInstall {
+        mode: args.mode,
+        canister_id,
+        wasm_module: &args.wasm_module,
+        arg: args.arg,
+    };
+
+    manage(
+        canister_id,
+        args.from_subaccount,
+        CanisterAction::InstallCode,
+        |_, _| async move { (call_management("install_code", (install,)).await, 0) },
+    )
+    .await
+}
+
+#[update]
+pub async fn wallet_update_settings(args: UpdateSettingsArgs) -> Result<(), String> {
+    let canister_id = args.canister_id;
+    let update = UpdateSettings {
+        canister_id,
+        settings: args.settings,
+    };
+
+    manage(
+        canister_id,
+        args.from_subaccount,
+        CanisterAction::UpdateSettings,
+        |_, _| async move { (call_management("update_settings", (update,)).await, 0) },
+    )
+    .await
+}
+
+#[update]
+pub async fn wallet_canister_status(
+    args: ManageCanisterArgs,
+) -> Result<CanisterStatusResult, String> {
+    manage(
+        args.canister_id,
+        args.from_subaccount,
+        CanisterAction::CanisterStatus,
+        |_, status| async move { (Ok(status), 0) },
+    )
+    .await
+}
+
+#[update]
+pub async fn wallet_start_canister(args: ManageCanisterArgs) -> Result<(), String> {
+    let canister_id = args.canister_id;
+    manage(
+        canister_id,
+        args.from_subaccount,
+        CanisterAction::StartCanister,
+        |_, _| async move {
+            let record = CanisterIdRecord { canister_id };
+            (call_management("start_canister", (record,)).await, 0)
+        },
+    )
+    .await
+}
+
+#[update]
+pub async fn wallet_stop_canister(args: ManageCanisterArgs) -> Result<(), String> {
+    let canister_id = args.canister_id;
+    manage(
+        canister_id,
+        args.from_subaccount,
+        CanisterAction::StopCanister,
+        |_, _| async move {
+            let record = CanisterIdRecord { canister_id };
+            (call_management("stop_canister", (record,)).await, 0)
+        },
+    )
+    .await
+}
+
+/// Delete a canister controlled by XTC and credit its remaining cycles to the given account,
+/// returns the credited cycles along with the result. The cycles are recovered by reinstalling
+/// the canister with the drain module, which sends them to `wallet_receive` before it is
+/// deleted, and only the cycles XTC accepted there are credited.
+///
+/// The canister is only deleted once XTC accepted all of its cycles except DRAIN_RESERVE, it is
+/// kept with the drain module installed if the drain fails.
+async fn drain_and_delete(
+    canister_id: Principal,
+    to: Account,
+    running: bool,
+) -> (u64, Result<(), String>) {
+    let drained = drain(canister_id, to, running).await;
+    let accepted = Drains::finish(&canister_id);
+
+    let result = match drained {
+        Ok(balance) if accepted < balance.saturating_sub(DRAIN_RESERVE) => Err(format!(
+            "Only {} of the {} cycles of the canister were drained, it was not deleted.",
+            accepted, balance
+        )),
+        Ok(_) => delete(canister_id).await,
+        Err(e) => Err(e),
+    };
+    (accepted, result)
+}
+
+/// Install the drain module on the canister and run it, returns the balance the canister had
+/// before the drain.
+async fn drain(canister_id: Principal, to: Account, running: bool) -> Result<u64, String> {
+    let ic = get_context();
+
+    // A stopped canister does not accept the drain call.
+    if !running {
+        call_management::<_, ()>("start_canister", (CanisterIdRecord { canister_id },)).await?;
+    }
+
+    let receive = Some(ReceiveOptions { to: Some(to) });
+    let install = CanisterInstall {
+        mode: InstallMode::Reinstall,
+        canister_id,
+        wasm_module: DRAIN_WASM,
+        arg: encode_args((receive,)).map_err(|e| e.to_string())?,
+    };
+    call_management::<_, ()>("install_code", (install,)).await?;
+
+    Drains::start(canister_id, to);
+    let (drained,): (Result<(u64, u64), String>,) = ic
+        .call(canister_id, "drain", ())
+        .await
+        .map_err(|(code, msg)| format!("Failed to drain the canister: {}: {}", code as u8, msg))?;
+    let (balance, _) =
+        drained.map_err(|e| format!("Failed to drain the canister, it was not deleted: {}", e))?;
+    Ok(balance)
+}
+
+/// Stop and delete the canister and drop it from the registry.
+async fn delete(canister_id: Principal) -> Result<(), String> {
+    call_management::<_, ()>("stop_canister", (CanisterIdRecord { canister_id },)).await?;
+    call_management::<_, ()>("delete_canister", (CanisterIdRecord { canister_id },)).await?;
+    get_context()
+        .get_mut::<CanisterRegistry>()
+        .remove(&canister_id);
+    Ok(())
+}
+
+/// Delete the canister and credit its remaining cycles to the XTC balance of the caller, returns
+/// the credited cycles. The last DRAIN_RESERVE cycles of the canister pay for the drain and are
+/// burned, the canister is not deleted if the rest can not be credited.
+#[update]
+pub async fn wallet_delete_canister(args: ManageCanisterArgs) -> Result<u64, String> {
+    let canister_id = args.canister_id;
+    manage(
+        canister_id,
+        args.from_subaccount,
+        CanisterAction::DeleteCanister,
+        |from, status| async move {
+            let running = status.status == CanisterStatus::Running;
+            let (credited, result) = drain_and_delete(canister_id, from, running).await;
+            (result.map(|()| credited), credited)
+        },
+    )
+    .await
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CreateAndInstallArgs {
+    pub cycles: u64,
+    pub settings: Option<WalletCanisterSettings>,
+    #[serde(with = "serde_bytes")]
+    pub wasm_module: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub arg: Vec<u8>,
+    pub from_subaccount: Option<Subaccount>,
+}
+
+/// Install the code on a canister created with XTC as its only controller, then hand the
+/// canister over to the requested controllers. A cycles wallet makes its installer its only
+/// controller, so for a wallet the controllers it keeps track of are replaced too.
+async fn install_and_hand_over(
+    canister_id: Principal,
+    wasm_module: &[u8],
+    arg: Vec<u8>,
+    controllers: Option<Vec<Principal>>,
+    wallet: bool,
+) -> Result<(), String> {
+    let ic = get_context();
+    let install = CanisterInstall {
+        mode: InstallMode::Install,
+        canister_id,
+        wasm_module,
+        arg,
+    };
+    call_management::<_, ()>("install_code", (install,)).await?;
+
+    if wallet {
+        #[derive(CandidType, Deserialize)]
+        enum WalletResult {
+            Ok(()),
+            Err(String),
+        }
+
+        let wallet_error = |(code, msg): (RejectionCode, String)| {
+            format!("Failed to set up the wallet: {}: {}", code as u8, msg)
+        };
+
+        for controller in controllers.iter().flatten() {
+            let () = ic
+                .call(canister_id, "add_controller", (*controller,))
+                .await
+                .map_err(wallet_error)?;
+        }
+
+        let (result,): (WalletResult,) = ic
+            .call(canister_id, "remove_controller", (ic.id(),))
+            .await
+            .map_err(wallet_error)?;
+        if let WalletResult::Err(e) = result {
+            return Err(format!("Failed to set up the wallet: {}", e));
+        }
+    }
+
+    hand_over(canister_id, controllers).await
+}
+
+/// Replace XTC with the given controllers of a canister it created.
+async fn hand_over(
+    canister_id: Principal,
+    controllers: Option<Vec<Principal>>,
+) -> Result<(), String> {
+    let update = UpdateSettings {
+        canister_id,
+        settings: CanisterSettings {
+            controllers,
+            compute_allocation: None,
+            memory_allocation: None,
+            freezing_threshold: None,
+        },
+    };
+    call_management::<_, ()>("update_settings", (update,)).await
+}
+
+/// Create a canister and install the given code on it, recorded as a single event with the hash
+/// of the module. If the installation fails the canister is deleted, its cycles are credited back
+/// to the caller through `wallet_receive` and only the cycles it consumed are charged with their
+/// fee. If its cycles can not be drained either, the canister is kept with its cycles and handed
+/// over to the controllers.
+#[update]
+pub async fn wallet_create_and_install(
+    args: CreateAndInstallArgs,
+) -> Result<WithCanisterId, String> {
+    deploy(
+        args.cycles,
+        args.settings.unwrap_or_default(),
+        &args.wasm_module,
+        args.arg,
+        args.from_subaccount,
+        false,
+    )
+    .await
+}
+
+/// Create a canister with the given cycles and install the module on it, see
+/// `wallet_create_and_install`. Set wallet when the module is a cycles wallet.
+pub async fn deploy(
+    cycles: u64,
+    settings: WalletCanisterSettings,
+    wasm_module: &[u8],
+    arg: Vec<u8>,
+    from_subaccount: Option<Subaccount>,
+    wallet: bool,
+) -> Result<WithCanisterId, String> {
+    PauseFlags::guard(PauseTarget::CreateCanister);
+
+    let ic = get_context();
+    let caller = ic.caller();
+    let from = Account::new(caller, from_subaccount);
+    let settings = settings.validate(caller)?;
+    let module_hash: [u8; 32] = Sha256::digest(wasm_module).into();
+    let requested = cycles;
+
+    let deduced_fee = compute_fee(FeeOperation::CreateCanister, requested);
+    let ledger = ic.get_mut::<Ledger>();
+    ledger
+        .withdraw(&from, requested + deduced_fee)
+        .map_err(|_| "Insufficient Balance".to_string())?;
+
+    let kind = |canister| TransactionKind::CanisterDeployed {
+        from: caller,
+        canister,
+        module_hash,
+        from_subaccount: from.subaccount,
+    };
+
+    // XTC is the only controller until the code is installed, so it can roll back.
+    let in_args = CreateCanisterArgument {
+        settings: Some(CanisterSettings {
+            controllers: Some(vec![ic.id()]),
+            compute_allocation: settings.compute_allocation,
+            memory_allocation: settings.memory_allocation,
+            freezing_threshold: settings.freezing_threshold,
+        }),
+    };
+
+    let (canister_id, cycles) = match CreateCanister::perform_with_payment(
+        Principal::management_canister(),
+        (in_args,),
+        requested,
+    )
+    .await
+    {
+        Ok((r,)) => (r.canister_id, requested - ic.msg_cycles_refunded()),
+        Err((code, msg)) => {
+            ledger.deposit(&from, requested);
+            ic.get_mut::<HistoryBuffer>().push(Transaction {
+                timestamp: ic.time(),
+                cycles: 0,
+                fee: deduced_fee,
+                kind: kind(caller),
+                status: TransactionStatus::FAILED,
+            });
+
+            return Err(format!(
+                "An error happened during the call: {}: {}",
+                code as u8, msg
+            ));
+        }
+    };
+
+    if cycles < requested {
+        ledger.deposit(&from, requested - cycles);
+    }
+
+    let controllers = settings.controllers;
+    match install_and_hand_over(canister_id, wasm_module, arg, controllers.clone(), wallet).await {
+        Ok(()) => {
+            let actual_fee = compute_fee(FeeOperation::CreateCanister, cycles);
+            if deduced_fee > actual_fee {
+                ledger.deposit(&from, deduced_fee - actual_fee);
+            }
+
+            ic.get_mut::<CanisterRegistry>()
+                .insert(caller, canister_id, cycles, ic.time());
+            ic.get_mut::<HistoryBuffer>().push(Transaction {
+                timestamp: ic.time(),
+                cycles,
+                fee: actual_fee,
+                kind: kind(canister_id),
+                status: TransactionStatus::SUCCEEDED,
+            });
+            FeeCollector::collect(actual_fee);
+
+            Ok(WithCanisterId { canister_id })
+        }
+        Err(e) => {
+            // The canister keeps its cycles if they can not be drained, so it must not be left
+            // with XTC as its only controller.
+            let (drained, rollback) = drain_and_delete(canister_id, from, true).await;
+            let rollback = match rollback {
+                Ok(()) => Ok(()),
+                Err(drain) => match hand_over(canister_id, controllers).await {
+                    Ok(()) => {
+                        ic.get_mut::<CanisterRegistry>().insert(
+                            caller,
+                            canister_id,
+                            cycles,
+                            ic.time(),
+                        );
+                        Err(format!(
+                            "the canister {} was kept and handed over with its cycles: {}",
+                            canister_id, drain
+                        ))
+                    }
+                    Err(update) => Err(format!(
+                        "the canister {} could not be deleted: {}, nor handed over: {}",
+                        canister_id, drain, update
+                    )),
+                },
+            };
+
+            // The drained cycles were credited back without a fee, the caller is only charged
+            // for the cycles the canister consumed, DRAIN_RESERVE included.
+            let consumed = cycles.saturating_sub(drained);
+            let actual_fee = compute_fee(FeeOperation::CreateCanister, consumed);
+            if deduced_fee > actual_fee {
+                ledger.deposit(&from, deduced_fee - actual_fee);
+            }
+
+            ic.get_mut::<HistoryBuffer>().push(Transaction {
+                timestamp: ic.time(),
+                cycles: consumed,
+                fee: actual_fee,
+                kind: kind(canister_id),
+                status: TransactionStatus::FAILED,
+            });
+            FeeCollector::collect(actual_fee);
+
+            Err(match rollback {
+                Ok(()) => format!(
+                    "Failed to install the code, the canister was deleted: {}",
+                    e
+                ),
+                Err(rollback) => format!("Failed to install the code: {}, {}", e, rollback),
+            })
+        }
+    }
+}
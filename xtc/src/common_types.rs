@@ -6,9 +6,72 @@ use ic_kit::{
 };
 use std::convert::{TryFrom, TryInto};
 use xtc_history_common::types::*;
+use xtc_stable::Storable;
+
+pub use xtc_history_common::types::Subaccount;
 
 type Time = Int;
 
+/// An ICRC-1 account, the owner principal together with an optional subaccount. A missing
+/// subaccount refers to the default subaccount of the owner.
+#[derive(CandidType, Debug, Deserialize, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+impl Account {
+    /// Create a new account, the all-zero subaccount is treated the same as the default
+    /// subaccount so there is only one representation for each account.
+    #[inline]
+    pub fn new(owner: Principal, subaccount: Option<Subaccount>) -> Self {
+        Account {
+            owner,
+            subaccount: subaccount.filter(|s| s.0 != [0; 32]),
+        }
+    }
+}
+
+impl From<Principal> for Account {
+    fn from(owner: Principal) -> Self {
+        Account {
+            owner,
+            subaccount: None,
+        }
+    }
+}
+
+impl Storable for Account {
+    // The length of the principal, up to 29 bytes of principal, a flag for the subaccount
+    // and the subaccount.
+    const SIZE: usize = 63;
+
+    fn write_to(&self, buf: &mut [u8]) {
+        buf.iter_mut().for_each(|byte| *byte = 0);
+        let owner = self.owner.as_slice();
+        buf[0] = owner.len() as u8;
+        buf[1..1 + owner.len()].copy_from_slice(owner);
+        if let Some(subaccount) = &self.subaccount {
+            buf[30] = 1;
+            buf[31..63].copy_from_slice(&subaccount.0);
+        }
+    }
+
+    fn read_from(buf: &[u8]) -> Self {
+        let owner = Principal::from_slice(&buf[1..1 + buf[0] as usize]);
+        let subaccount = match buf[30] {
+            0 => None,
+            _ => {
+                let mut subaccount = [0; 32];
+                subaccount.copy_from_slice(&buf[31..63]);
+                Some(Subaccount(subaccount))
+            }
+        };
+
+        Account { owner, subaccount }
+    }
+}
+
 #[derive(CandidType, Clone)]
 pub enum Operation {
     approve,
@@ -18,6 +81,7 @@ pub enum Operation {
     burn,
     canisterCalled,
     canisterCreated,
+    feeCollected,
 }
 
 #[derive(CandidType)]
@@ -46,6 +110,9 @@ pub enum TxError {
     UnexpectedCyclesResponse,
     AmountTooSmall,
     InsufficientXTCFee,
+    StaleRate,
+    /// The attached cycles do not cover the amount and the fee.
+    InsufficientCycles,
 }
 
 pub type TxReceipt = Result<Nat, TxError>;
@@ -82,7 +149,7 @@ impl TryFrom<Transaction> for TxRecord {
     type Error = ();
     fn try_from(transaction: Transaction) -> Result<TxRecord, ()> {
         Ok(match transaction.kind {
-            TransactionKind::Approve { from, to } => TxRecord::new(
+            TransactionKind::Approve { from, to, .. } => TxRecord::new(
                 None,
                 from,
                 to,
@@ -93,7 +160,7 @@ impl TryFrom<Transaction> for TxRecord {
                 Nat::from(0),
                 transaction.status,
             ),
-            TransactionKind::Transfer { from, to } => TxRecord::new(
+            TransactionKind::Transfer { from, to, .. } => TxRecord::new(
                 None,
                 from,
                 to,
@@ -104,7 +171,9 @@ impl TryFrom<Transaction> for TxRecord {
                 Nat::from(0),
                 transaction.status,
             ),
-            TransactionKind::TransferFrom { caller, from, to } => TxRecord::new(
+            TransactionKind::TransferFrom {
+                caller, from, to, ..
+            } => TxRecord::new(
                 Some(caller),
                 from,
                 to,
@@ -115,7 +184,7 @@ impl TryFrom<Transaction> for TxRecord {
                 Nat::from(0),
                 transaction.status,
             ),
-            TransactionKind::Mint { to } => TxRecord::new(
+            TransactionKind::Mint { to, .. } => TxRecord::new(
                 None,
                 to,
                 to,
@@ -126,7 +195,7 @@ impl TryFrom<Transaction> for TxRecord {
                 Nat::from(0),
                 transaction.status,
             ),
-            TransactionKind::Burn { from, to } => TxRecord::new(
+            TransactionKind::Burn { from, to, .. } => TxRecord::new(
                 None,
                 from,
                 to,
@@ -141,6 +210,7 @@ impl TryFrom<Transaction> for TxRecord {
                 from,
                 canister,
                 method_name,
+                ..
             } => TxRecord::new(
                 None,
                 from,
@@ -152,7 +222,8 @@ impl TryFrom<Transaction> for TxRecord {
                 Nat::from(0),
                 transaction.status,
             ),
-            TransactionKind::CanisterCreated { from, canister } => TxRecord::new(
+            TransactionKind::CanisterCreated { from, canister, .. }
+            | TransactionKind::CanisterDeployed { from, canister, .. } => TxRecord::new(
                 None,
                 from,
                 canister,
@@ -163,6 +234,29 @@ impl TryFrom<Transaction> for TxRecord {
                 Nat::from(0),
                 transaction.status,
             ),
+            TransactionKind::CanisterManaged { from, canister, .. } => TxRecord::new(
+                None,
+                from,
+                canister,
+                Nat::from(transaction.cycles),
+                Nat::from(transaction.fee),
+                Operation::canisterCalled,
+                Int::from(transaction.timestamp),
+                Nat::from(0),
+                transaction.status,
+            ),
+            // The fee credits get their own operation, so they are not mistaken for mints.
+            TransactionKind::FeeCollected { to, .. } => TxRecord::new(
+                None,
+                to,
+                to,
+                Nat::from(transaction.cycles),
+                Nat::from(transaction.fee),
+                Operation::feeCollected,
+                Int::from(transaction.timestamp),
+                Nat::from(0),
+                transaction.status,
+            ),
         })
     }
 }
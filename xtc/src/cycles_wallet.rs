@@ -1,11 +1,13 @@
 //! Contains source codes related to making Dank compatible with cycles wallet so it can be used
 //! by the dfx command line.
 
-use crate::fee::compute_fee;
+use crate::canisters::{deploy, CanisterRegistry, Drains};
+use crate::common_types::{Account, Subaccount};
+use crate::fee::{compute_fee, FeeCollector, FeeOperation};
 use crate::history::{HistoryBuffer, Transaction, TransactionKind, TransactionStatus};
-use crate::ledger::Ledger;
-use crate::management::IsShutDown;
-use ic_kit::candid::CandidType;
+use crate::ledger::{mint, Ledger};
+use crate::management::{PauseFlags, PauseTarget};
+use ic_kit::candid::{encode_args, CandidType, Nat};
 use ic_kit::interfaces::management::{
     CanisterSettings, CreateCanister, CreateCanisterArgument, WithCanisterId,
 };
@@ -13,6 +15,19 @@ use ic_kit::interfaces::Method;
 use ic_kit::macros::*;
 use ic_kit::{get_context, Context, Principal};
 use serde::*;
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use xtc_history::stable::MAX_METHOD_NAME_LEN;
+
+/// The cycles wallet installed by `wallet_create_wallet`, `build.js` copies it from the cache of
+/// the pinned dfx version after checking its sha256 against `wallet.sha256`.
+#[cfg(target_family = "wasm")]
+const WALLET_WASM: &[u8] = include_bytes!("../../target/wasm32-unknown-unknown/wallet.wasm");
+
+#[cfg(not(target_family = "wasm"))]
+const WALLET_WASM: &[u8] = b"\0asm\x01\0\0\0";
 
 #[derive(CandidType, Deserialize)]
 pub struct CallCanisterArgs {
@@ -21,6 +36,7 @@ pub struct CallCanisterArgs {
     #[serde(with = "serde_bytes")]
     pub args: Vec<u8>,
     pub cycles: u64,
+    pub from_subaccount: Option<Subaccount>,
 }
 
 #[derive(CandidType, Deserialize)]
@@ -32,19 +48,28 @@ pub struct CallResult {
 /// Forward a call to another canister.
 #[update(name = "wallet_call")]
 pub async fn call(args: CallCanisterArgs) -> Result<CallResult, String> {
-    IsShutDown::guard();
+    PauseFlags::guard(PauseTarget::WalletCall);
 
     let ic = get_context();
     let caller = ic.caller();
+    let from = Account::new(caller, args.from_subaccount);
 
     if ic.id() == args.canister {
         return Err("Attempted to call forward on self. This is not allowed.".to_string());
     }
 
-    let deduced_fee = compute_fee(args.cycles);
+    // The history only has room for names up to this length.
+    if args.method_name.len() > MAX_METHOD_NAME_LEN {
+        return Err(format!(
+            "The method name can not be longer than {} bytes.",
+            MAX_METHOD_NAME_LEN
+        ));
+    }
+
+    let deduced_fee = compute_fee(FeeOperation::ProxyCall, args.cycles);
     let ledger = ic.get_mut::<Ledger>();
     ledger
-        .withdraw(&caller, args.cycles + deduced_fee)
+        .withdraw(&from, args.cycles + deduced_fee)
         .map_err(|_| "Insufficient Balance".to_string())?;
 
     let method_name = args.method_name.clone();
@@ -56,13 +81,16 @@ pub async fn call(args: CallCanisterArgs) -> Result<CallResult, String> {
         Ok(x) => {
             let refunded = ic.msg_cycles_refunded();
             let cycles = args.cycles - refunded;
-            let actual_fee = compute_fee(cycles);
+            let actual_fee = compute_fee(FeeOperation::ProxyCall, cycles);
             let refunded = refunded + (deduced_fee - actual_fee);
 
             if refunded > 0 {
-                ledger.deposit(&caller, refunded);
+                ledger.deposit(&from, refunded);
             }
 
+            ic.get_mut::<CanisterRegistry>()
+                .add_cycles_sent(&args.canister, cycles);
+
             ic.get_mut::<HistoryBuffer>().push(Transaction {
                 timestamp: ic.time(),
                 cycles,
@@ -71,14 +99,16 @@ pub async fn call(args: CallCanisterArgs) -> Result<CallResult, String> {
                     from: caller.clone(),
                     canister: args.canister.clone(),
                     method_name: args.method_name,
+                    from_subaccount: from.subaccount,
                 },
                 status: TransactionStatus::SUCCEEDED,
             });
+            FeeCollector::collect(actual_fee);
 
             Ok(CallResult { r#return: x })
         }
         Err((code, msg)) => {
-            ledger.deposit(&caller, args.cycles);
+            ledger.deposit(&from, args.cycles);
 
             ic.get_mut::<HistoryBuffer>().push(Transaction {
                 timestamp: ic.time(),
@@ -88,6 +118,7 @@ pub async fn call(args: CallCanisterArgs) -> Result<CallResult, String> {
                     from: caller.clone(),
                     canister: args.canister.clone(),
                     method_name: args.method_name,
+                    from_subaccount: from.subaccount,
                 },
                 status: TransactionStatus::FAILED,
             });
@@ -102,51 +133,145 @@ pub async fn call(args: CallCanisterArgs) -> Result<CallResult, String> {
 
 // Create canister call
 
+/// The most controllers a canister can have.
+const MAX_CONTROLLERS: usize = 10;
+
+/// The largest memory allocation accepted by the management canister, 256 TiB.
+const MAX_MEMORY_ALLOCATION: u64 = 1 << 48;
+
+/// The canister settings of the cycles wallet, controller is the single controller variant
+/// of the older versions.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct WalletCanisterSettings {
+    pub controller: Option<Principal>,
+    pub controllers: Option<Vec<Principal>>,
+    pub compute_allocation: Option<Nat>,
+    pub memory_allocation: Option<Nat>,
+    pub freezing_threshold: Option<Nat>,
+}
+
+impl WalletCanisterSettings {
+    /// Check the settings against the limits of the management canister and resolve the
+    /// controllers, the caller controls the canister if no controller is given.
+    pub fn validate(self, caller: Principal) -> Result<CanisterSettings, String> {
+        let controllers = match (self.controller, self.controllers) {
+            (Some(_), Some(_)) => {
+                return Err("Only one of controller and controllers can be set.".to_string())
+            }
+            (Some(controller), None) => vec![controller],
+            (None, Some(controllers)) => controllers,
+            (None, None) => vec![caller],
+        };
+
+        if controllers.is_empty() || controllers.len() > MAX_CONTROLLERS {
+            return Err(format!(
+                "A canister needs between 1 and {} controllers.",
+                MAX_CONTROLLERS
+            ));
+        }
+        if controllers.iter().collect::<HashSet<_>>().len() != controllers.len() {
+            return Err("The controllers must be unique.".to_string());
+        }
+        if matches!(&self.compute_allocation, Some(x) if *x > Nat::from(100)) {
+            return Err("The compute allocation must be between 0 and 100.".to_string());
+        }
+        if matches!(&self.memory_allocation, Some(x) if *x > Nat::from(MAX_MEMORY_ALLOCATION)) {
+            return Err(format!(
+                "The memory allocation must be between 0 and {}.",
+                MAX_MEMORY_ALLOCATION
+            ));
+        }
+        if matches!(&self.freezing_threshold, Some(x) if *x > Nat::from(u64::MAX)) {
+            return Err("The freezing threshold must fit in 64 bits.".to_string());
+        }
+
+        Ok(CanisterSettings {
+            controllers: Some(controllers),
+            compute_allocation: self.compute_allocation,
+            memory_allocation: self.memory_allocation,
+            freezing_threshold: self.freezing_threshold,
+        })
+    }
+}
+
 #[derive(CandidType, Deserialize)]
 pub struct CreateCanisterArgs {
     pub cycles: u64,
     pub controller: Option<Principal>,
+    pub settings: Option<WalletCanisterSettings>,
+    pub from_subaccount: Option<Subaccount>,
+}
+
+impl CreateCanisterArgs {
+    /// The settings together with the controller given next to them.
+    fn settings(&mut self) -> Result<WalletCanisterSettings, String> {
+        let mut settings = self.settings.take().unwrap_or_default();
+        if self.controller.is_some() {
+            if settings.controller.is_some() || settings.controllers.is_some() {
+                return Err(
+                    "The controller is set both in the arguments and the settings.".to_string(),
+                );
+            }
+            settings.controller = self.controller;
+        }
+        Ok(settings)
+    }
 }
 
 #[update(name = "wallet_create_canister")]
-pub async fn create_canister(args: CreateCanisterArgs) -> Result<WithCanisterId, String> {
-    IsShutDown::guard();
+pub async fn create_canister(mut args: CreateCanisterArgs) -> Result<WithCanisterId, String> {
+    let caller = get_context().caller();
+    let settings = args.settings()?;
+
+    create_canister_with_settings(
+        args.cycles,
+        settings.validate(caller)?,
+        args.from_subaccount,
+    )
+    .await
+}
+
+/// Create a canister with the given cycles. The management canister takes the cost of the
+/// allocations from the attached cycles, so like the rest of the cycles it consumes they are
+/// charged to the caller together with their fee, and whatever it refunds is credited back.
+/// Too few cycles for the allocations are rejected by the management canister and refunded.
+async fn create_canister_with_settings(
+    cycles: u64,
+    settings: CanisterSettings,
+    from_subaccount: Option<Subaccount>,
+) -> Result<WithCanisterId, String> {
+    PauseFlags::guard(PauseTarget::CreateCanister);
 
     let ic = get_context();
     let caller = ic.caller();
+    let from = Account::new(caller, from_subaccount);
 
-    let deduced_fee = compute_fee(args.cycles);
+    let deduced_fee = compute_fee(FeeOperation::CreateCanister, cycles);
     let ledger = ic.get_mut::<Ledger>();
     ledger
-        .withdraw(&caller, args.cycles + deduced_fee)
+        .withdraw(&from, cycles + deduced_fee)
         .map_err(|_| "Insufficient Balance".to_string())?;
 
     let in_args = CreateCanisterArgument {
-        settings: Some(CanisterSettings {
-            controllers: Some(vec![args.controller.unwrap_or(caller)]),
-            compute_allocation: None,
-            memory_allocation: None,
-            freezing_threshold: None,
-        }),
+        settings: Some(settings),
     };
 
-    match CreateCanister::perform_with_payment(
-        Principal::management_canister(),
-        (in_args,),
-        args.cycles,
-    )
-    .await
+    match CreateCanister::perform_with_payment(Principal::management_canister(), (in_args,), cycles)
+        .await
     {
         Ok((r,)) => {
             let refunded = ic.msg_cycles_refunded();
-            let cycles = args.cycles - refunded;
-            let actual_fee = compute_fee(cycles);
+            let cycles = cycles - refunded;
+            let actual_fee = compute_fee(FeeOperation::CreateCanister, cycles);
             let refunded = refunded + (deduced_fee - actual_fee);
 
             if refunded > 0 {
-                ledger.deposit(&caller, refunded);
+                ledger.deposit(&from, refunded);
             }
 
+            ic.get_mut::<CanisterRegistry>()
+                .insert(caller, r.canister_id, cycles, ic.time());
+
             ic.get_mut::<HistoryBuffer>().push(Transaction {
                 timestamp: ic.time(),
                 cycles,
@@ -154,14 +279,16 @@ pub async fn create_canister(args: CreateCanisterArgs) -> Result<WithCanisterId,
                 kind: TransactionKind::CanisterCreated {
                     from: caller.clone(),
                     canister: r.canister_id,
+                    from_subaccount: from.subaccount,
                 },
                 status: TransactionStatus::SUCCEEDED,
             });
+            FeeCollector::collect(actual_fee);
 
             Ok(r)
         }
         Err((code, msg)) => {
-            ledger.deposit(&caller, args.cycles);
+            ledger.deposit(&from, cycles);
 
             ic.get_mut::<HistoryBuffer>().push(Transaction {
                 timestamp: ic.time(),
@@ -170,6 +297,7 @@ pub async fn create_canister(args: CreateCanisterArgs) -> Result<WithCanisterId,
                 kind: TransactionKind::CanisterCreated {
                     from: caller.clone(),
                     canister: caller.clone(),
+                    from_subaccount: from.subaccount,
                 },
                 status: TransactionStatus::FAILED,
             });
@@ -191,7 +319,7 @@ pub struct BalanceResult {
 pub fn wallet_balance() -> BalanceResult {
     let ic = get_context();
     let ledger = ic.get::<Ledger>();
-    let amount = ledger.balance(&ic.caller());
+    let amount = ledger.balance(&ic.caller().into());
     BalanceResult { amount }
 }
 
@@ -199,19 +327,21 @@ pub fn wallet_balance() -> BalanceResult {
 pub struct SendCyclesArgs {
     pub canister: Principal,
     pub amount: u64,
+    pub from_subaccount: Option<Subaccount>,
 }
 
 #[update]
 pub async fn wallet_send(args: SendCyclesArgs) -> Result<(), String> {
-    IsShutDown::guard();
+    PauseFlags::guard(PauseTarget::WalletSend);
 
     let ic = get_context();
     let caller = ic.caller();
+    let from = Account::new(caller, args.from_subaccount);
 
-    let deduced_fee = compute_fee(args.amount);
+    let deduced_fee = compute_fee(FeeOperation::Burn, args.amount);
     let ledger = ic.get_mut::<Ledger>();
     ledger
-        .withdraw(&caller, args.amount + deduced_fee)
+        .withdraw(&from, args.amount + deduced_fee)
         .map_err(|_| String::from("Insufficient balance."))?;
 
     #[derive(CandidType)]
@@ -231,13 +361,16 @@ pub async fn wallet_send(args: SendCyclesArgs) -> Result<(), String> {
         Ok(()) => {
             let refunded = ic.msg_cycles_refunded();
             let cycles = args.amount - refunded;
-            let actual_fee = compute_fee(cycles);
+            let actual_fee = compute_fee(FeeOperation::Burn, cycles);
             let refunded = refunded + (deduced_fee - actual_fee);
 
             if refunded > 0 {
-                ledger.deposit(&caller, refunded);
+                ledger.deposit(&from, refunded);
             }
 
+            ic.get_mut::<CanisterRegistry>()
+                .add_cycles_sent(&args.canister, cycles);
+
             ic.get_mut::<HistoryBuffer>().push(Transaction {
                 timestamp: ic.time(),
                 cycles,
@@ -245,14 +378,16 @@ pub async fn wallet_send(args: SendCyclesArgs) -> Result<(), String> {
                 kind: TransactionKind::Burn {
                     from: caller.clone(),
                     to: args.canister,
+                    from_subaccount: from.subaccount,
                 },
                 status: TransactionStatus::SUCCEEDED,
             });
+            FeeCollector::collect(actual_fee);
 
             Ok(())
         }
         Err(_) => {
-            ledger.deposit(&caller, args.amount);
+            ledger.deposit(&from, args.amount);
 
             ic.get_mut::<HistoryBuffer>().push(Transaction {
                 timestamp: ic.time(),
@@ -261,6 +396,7 @@ pub async fn wallet_send(args: SendCyclesArgs) -> Result<(), String> {
                 kind: TransactionKind::Burn {
                     from: caller.clone(),
                     to: args.canister,
+                    from_subaccount: from.subaccount,
                 },
                 status: TransactionStatus::FAILED,
             });
@@ -270,11 +406,138 @@ pub async fn wallet_send(args: SendCyclesArgs) -> Result<(), String> {
     }
 }
 
+#[derive(CandidType, Deserialize)]
+pub struct ReceiveOptions {
+    /// The account credited with the cycles, the caller if None.
+    pub to: Option<Account>,
+}
+
+/// Accept the cycles sent by another wallet and mint them as XTC after the fee, the call is
+/// rejected and the cycles refunded if they do not cover the fee. The cycles of a canister
+/// drained by XTC are credited to the account of the drain without the fee.
 #[update]
-pub async fn wallet_create_wallet(_: CreateCanisterArgs) -> Result<WithCanisterId, String> {
+pub async fn wallet_receive(options: Option<ReceiveOptions>) {
     let ic = get_context();
-    crate::progress().await;
-    Ok(WithCanisterId {
-        canister_id: ic.id(),
+    let caller = ic.caller();
+    if Drains::contains(&caller) {
+        let cycles = ic.msg_cycles_accept(ic.msg_cycles_available());
+        Drains::credit(&caller, cycles);
+        return;
+    }
+
+    let to = options
+        .and_then(|options| options.to)
+        .unwrap_or_else(|| caller.into());
+
+    if let Err(e) = mint(to.owner, Nat::from(0), to.subaccount).await {
+        panic!("Failed to receive the cycles: {:?}", e);
+    }
+}
+
+/// Create a canister running the cycles wallet, the caller controls it unless other controllers
+/// are given.
+#[update]
+pub async fn wallet_create_wallet(mut args: CreateCanisterArgs) -> Result<WithCanisterId, String> {
+    let settings = args.settings()?;
+    deploy(
+        args.cycles,
+        settings,
+        WALLET_WASM,
+        encode_args(()).map_err(|e| e.to_string())?,
+        args.from_subaccount,
+        true,
+    )
+    .await
+}
+
+/// The sha256 of the cycles wallet installed by `wallet_create_wallet`.
+#[query]
+pub fn wallet_module_hash() -> ByteBuf {
+    ByteBuf::from(Sha256::digest(WALLET_WASM).to_vec())
+}
+
+// 128-bit API of the cycles wallet, used by the newer versions of dfx.
+
+/// Convert the cycles of the 128-bit API, the balances of XTC are 64-bit.
+fn to_cycles64(cycles: u128) -> Result<u64, String> {
+    u64::try_from(cycles).map_err(|_| "The cycles do not fit in 64 bits.".to_string())
+}
+
+/// The version of the cycles wallet API, dfx uses the 128-bit methods since 0.2.0.
+#[query]
+pub fn wallet_api_version() -> String {
+    "0.2.0".to_string()
+}
+
+#[derive(CandidType)]
+pub struct BalanceResult128 {
+    pub amount: u128,
+}
+
+#[query]
+pub fn wallet_balance128() -> BalanceResult128 {
+    BalanceResult128 {
+        amount: wallet_balance().amount as u128,
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SendCyclesArgs128 {
+    pub canister: Principal,
+    pub amount: u128,
+    pub from_subaccount: Option<Subaccount>,
+}
+
+#[update]
+pub async fn wallet_send128(args: SendCyclesArgs128) -> Result<(), String> {
+    wallet_send(SendCyclesArgs {
+        canister: args.canister,
+        amount: to_cycles64(args.amount)?,
+        from_subaccount: args.from_subaccount,
+    })
+    .await
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CallCanisterArgs128 {
+    pub canister: Principal,
+    pub method_name: String,
+    #[serde(with = "serde_bytes")]
+    pub args: Vec<u8>,
+    pub cycles: u128,
+    pub from_subaccount: Option<Subaccount>,
+}
+
+#[update]
+pub async fn wallet_call128(args: CallCanisterArgs128) -> Result<CallResult, String> {
+    call(CallCanisterArgs {
+        canister: args.canister,
+        method_name: args.method_name,
+        args: args.args,
+        cycles: to_cycles64(args.cycles)?,
+        from_subaccount: args.from_subaccount,
     })
+    .await
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CreateCanisterArgs128 {
+    pub cycles: u128,
+    pub settings: WalletCanisterSettings,
+    pub from_subaccount: Option<Subaccount>,
+}
+
+#[update]
+pub async fn wallet_create_canister128(
+    args: CreateCanisterArgs128,
+) -> Result<WithCanisterId, String> {
+    let caller = get_context().caller();
+    let cycles = to_cycles64(args.cycles)?;
+
+    create_canister_with_settings(
+        cycles,
+        args.settings.validate(caller)?,
+        args.from_subaccount,
+    )
+    .await
 }
@@ -1,16 +1,406 @@
-/// Compute the fee for the given transaction amount. Any implementation of this method
-/// should guarantee that for an amount A > B, compute_fee(A) >= compute_fee(B).
+use crate::common_types::Account;
+use crate::history::HistoryBuffer;
+use crate::ledger::Ledger;
+use crate::management::{AdminAction, AuditLog, Role, Roles};
+use crate::proposals::Proposals;
+use crate::stats::StatsData;
+use ic_kit::candid::{CandidType, Nat};
+use ic_kit::macros::*;
+use ic_kit::{get_context, Context};
+use serde::Deserialize;
+
+/// The operations that can be charged a different fee.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FeeOperation {
+    Transfer,
+    Approve,
+    TransferFrom,
+    Mint,
+    Burn,
+    ProxyCall,
+    CreateCanister,
+    MintByIcp,
+    ManageCanister,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct FeeTier {
+    pub min_amount: u64,
+    pub fee: u64,
+}
+
+/// How the fee of an operation is derived from its amount, every rule is monotonic as long as
+/// it passes `validate`.
+#[derive(CandidType, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub enum FeeRule {
+    Flat(u64),
+    /// A fraction of the amount in basis points, clamped to the min..=max range.
+    Percentage {
+        basis_points: u64,
+        min: u64,
+        max: u64,
+    },
+    /// The fee of the tier with the largest min_amount not above the amount, the tiers are
+    /// sorted by min_amount and the first one starts at zero.
+    Tiered(Vec<FeeTier>),
+}
+
+impl FeeRule {
+    pub fn compute(&self, amount: u64) -> u64 {
+        match self {
+            FeeRule::Flat(fee) => *fee,
+            FeeRule::Percentage {
+                basis_points,
+                min,
+                max,
+            } => {
+                let fee = amount as u128 * *basis_points as u128 / 10_000;
+                (fee.min(*max as u128) as u64).max(*min)
+            }
+            FeeRule::Tiered(tiers) => tiers
+                .iter()
+                .take_while(|tier| tier.min_amount <= amount)
+                .last()
+                .map(|tier| tier.fee)
+                .unwrap_or(0),
+        }
+    }
+
+    /// Check that the rule never returns a zero fee and that the fee does not decrease when
+    /// the amount grows.
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            FeeRule::Flat(0) => Err("The fee must be non-zero.".into()),
+            FeeRule::Flat(_) => Ok(()),
+            FeeRule::Percentage {
+                basis_points,
+                min,
+                max,
+            } => {
+                if *basis_points > 10_000 {
+                    Err("The percentage cannot exceed 10000 basis points.".into())
+                } else if *min == 0 || min > max {
+                    Err("The minimum fee must be non-zero and not above the maximum.".into())
+                } else {
+                    Ok(())
+                }
+            }
+            FeeRule::Tiered(tiers) => {
+                match tiers.first() {
+                    Some(tier) if tier.min_amount == 0 && tier.fee > 0 => (),
+                    _ => {
+                        return Err("The first tier must start at zero with a non-zero fee.".into())
+                    }
+                }
+
+                for pair in tiers.windows(2) {
+                    if pair[1].min_amount <= pair[0].min_amount || pair[1].fee < pair[0].fee {
+                        return Err(
+                            "The tiers must have increasing amounts and non-decreasing fees."
+                                .into(),
+                        );
+                    }
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The operations that are charged the fee reported by `icrc1_fee`.
+const FLAT_FEE_OPERATIONS: [FeeOperation; 3] = [
+    FeeOperation::Transfer,
+    FeeOperation::Approve,
+    FeeOperation::TransferFrom,
+];
+
+/// The fees charged by the ledger, the operations without their own rule use the default one.
+#[derive(CandidType, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct FeeSchedule {
+    pub default: FeeRule,
+    pub operations: Vec<(FeeOperation, FeeRule)>,
+}
+
 #[cfg(not(test))]
-pub fn compute_fee(_: u64) -> u64 {
-    2_000_000_000
+impl Default for FeeSchedule {
+    fn default() -> Self {
+        FeeSchedule {
+            default: FeeRule::Flat(2_000_000_000),
+            operations: Vec::new(),
+        }
+    }
 }
 
 // Used for testing.
 #[cfg(test)]
-pub fn compute_fee(cycles: u64) -> u64 {
-    if cycles > 5_000 {
-        3_500_000_000
-    } else {
-        2_000_000_000
+impl Default for FeeSchedule {
+    fn default() -> Self {
+        FeeSchedule {
+            default: FeeRule::Tiered(vec![
+                FeeTier {
+                    min_amount: 0,
+                    fee: 2_000_000_000,
+                },
+                FeeTier {
+                    min_amount: 5_001,
+                    fee: 3_500_000_000,
+                },
+            ]),
+            operations: vec![
+                (FeeOperation::Transfer, FeeRule::Flat(2_000_000_000)),
+                (FeeOperation::Approve, FeeRule::Flat(2_000_000_000)),
+                (FeeOperation::TransferFrom, FeeRule::Flat(2_000_000_000)),
+            ],
+        }
+    }
+}
+
+impl FeeSchedule {
+    #[inline]
+    pub fn load(data: FeeSchedule) {
+        let ic = get_context();
+        *ic.get_mut::<FeeSchedule>() = data;
+    }
+
+    #[inline]
+    pub fn get() -> FeeSchedule {
+        let ic = get_context();
+        ic.get::<FeeSchedule>().clone()
+    }
+
+    /// Replace the schedule with a validated one and record the change in the audit log.
+    pub fn set(schedule: FeeSchedule) {
+        FeeSchedule::load(schedule.clone());
+        AuditLog::record(AdminAction::SetFeeSchedule { schedule });
+    }
+
+    #[inline]
+    pub fn rule(&self, operation: FeeOperation) -> &FeeRule {
+        self.operations
+            .iter()
+            .find(|(op, _)| *op == operation)
+            .map(|(_, rule)| rule)
+            .unwrap_or(&self.default)
+    }
+
+    /// Check every rule, the operations of the ICRC standards must have a flat fee since
+    /// `icrc1_fee` reports a single fee for any amount.
+    pub fn validate(&self) -> Result<(), String> {
+        self.default.validate()?;
+        for (i, (operation, rule)) in self.operations.iter().enumerate() {
+            if self.operations[..i].iter().any(|(op, _)| op == operation) {
+                return Err(format!("Duplicate rule for {:?}.", operation));
+            }
+            rule.validate()?;
+        }
+
+        for operation in &FLAT_FEE_OPERATIONS {
+            match self.rule(*operation) {
+                FeeRule::Flat(_) => (),
+                _ => return Err(format!("The fee of {:?} must be flat.", operation)),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compute the fee for the given operation and transaction amount. Any implementation of this
+/// method should guarantee that for an amount A > B, compute_fee(A) >= compute_fee(B).
+pub fn compute_fee(operation: FeeOperation, amount: u64) -> u64 {
+    let ic = get_context();
+    ic.get::<FeeSchedule>().rule(operation).compute(amount)
+}
+
+#[update]
+pub fn set_fee_schedule(schedule: FeeSchedule) -> Result<(), String> {
+    Roles::guard(Role::FeeManager);
+    Proposals::guard_direct();
+
+    schedule.validate()?;
+    FeeSchedule::set(schedule);
+    Ok(())
+}
+
+#[query]
+pub fn get_fee_schedule() -> FeeSchedule {
+    FeeSchedule::get()
+}
+
+/// The account credited with the fees charged by the successful transactions. The fees charged
+/// while no collector is set and the fees kept by the failed transactions are burned, they are
+/// still counted in StatsData.fee.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct FeeCollector {
+    account: Option<Account>,
+    /// The total amount of fees credited to the collector accounts so far.
+    collected: Nat,
+}
+
+impl FeeCollector {
+    #[inline]
+    pub fn load(data: FeeCollector) {
+        let ic = get_context();
+        *ic.get_mut::<FeeCollector>() = data;
+    }
+
+    #[inline]
+    pub fn get() -> FeeCollector {
+        let ic = get_context();
+        ic.get::<FeeCollector>().clone()
+    }
+
+    /// Change the collector and record the change in the audit log.
+    pub fn set(account: Option<Account>) {
+        let ic = get_context();
+        let account = account.map(|account| Account::new(account.owner, account.subaccount));
+        ic.get_mut::<FeeCollector>().account = account;
+        AuditLog::record(AdminAction::SetFeeCollector { account });
+    }
+
+    /// Credit the fee charged by a successful transaction to the collector, the credit is
+    /// recorded in the history right after the transaction. Returns the collector account or
+    /// None if the fee is burned.
+    pub fn collect(fee: u64) -> Option<Account> {
+        let ic = get_context();
+        let collector = ic.get_mut::<FeeCollector>();
+        let account = collector.account?;
+        if fee == 0 {
+            return None;
+        }
+
+        ic.get_mut::<Ledger>().deposit(&account, fee);
+        collector.collected += fee;
+        ic.get_mut::<HistoryBuffer>().push_fee_credit(account, fee);
+
+        Some(account)
+    }
+}
+
+#[derive(CandidType, Debug, Deserialize, Eq, PartialEq)]
+pub struct FeeReport {
+    pub fee_collector: Option<Account>,
+    pub collector_balance: Nat,
+    /// The fees credited to the collector accounts.
+    pub collected: Nat,
+    /// All of the fees charged by the ledger, as reported by the stats.
+    pub fees: Nat,
+    /// The fees that were not credited to a collector.
+    pub burned: Nat,
+}
+
+#[update]
+pub fn set_fee_collector(account: Option<Account>) {
+    Roles::guard(Role::FeeManager);
+    Proposals::guard_direct();
+
+    FeeCollector::set(account);
+}
+
+#[query]
+pub fn get_fee_collector() -> Option<Account> {
+    FeeCollector::get().account
+}
+
+#[query]
+pub fn fee_report() -> FeeReport {
+    let collector = FeeCollector::get();
+    let fees = StatsData::get().fee;
+    let collector_balance = match &collector.account {
+        Some(account) => get_context().get::<Ledger>().balance(account),
+        None => 0,
+    };
+
+    FeeReport {
+        fee_collector: collector.account,
+        collector_balance: Nat::from(collector_balance),
+        burned: if fees > collector.collected {
+            fees.clone() - collector.collected.clone()
+        } else {
+            Nat::from(0)
+        },
+        collected: collector.collected,
+        fees,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentage() {
+        let rule = FeeRule::Percentage {
+            basis_points: 25,
+            min: 100,
+            max: 1_000,
+        };
+        assert_eq!(rule.validate(), Ok(()));
+        assert_eq!(rule.compute(0), 100);
+        assert_eq!(rule.compute(100_000), 250);
+        assert_eq!(rule.compute(u64::MAX), 1_000);
+    }
+
+    #[test]
+    fn tiers() {
+        let rule = FeeRule::Tiered(vec![
+            FeeTier {
+                min_amount: 0,
+                fee: 10,
+            },
+            FeeTier {
+                min_amount: 1_000,
+                fee: 20,
+            },
+        ]);
+        assert_eq!(rule.validate(), Ok(()));
+        assert_eq!(rule.compute(999), 10);
+        assert_eq!(rule.compute(1_000), 20);
+
+        let decreasing = FeeRule::Tiered(vec![
+            FeeTier {
+                min_amount: 0,
+                fee: 20,
+            },
+            FeeTier {
+                min_amount: 1_000,
+                fee: 10,
+            },
+        ]);
+        assert!(decreasing.validate().is_err());
+        assert!(FeeRule::Tiered(vec![]).validate().is_err());
+    }
+
+    #[test]
+    fn validate_schedule() {
+        assert!(FeeRule::Flat(0).validate().is_err());
+
+        let schedule = FeeSchedule {
+            default: FeeRule::Flat(1),
+            operations: vec![
+                (FeeOperation::Mint, FeeRule::Flat(2)),
+                (FeeOperation::Mint, FeeRule::Flat(3)),
+            ],
+        };
+        assert!(schedule.validate().is_err());
+
+        // The default rule also applies to the transfers.
+        let percentage = FeeRule::Percentage {
+            basis_points: 100,
+            min: 10,
+            max: 500,
+        };
+        let schedule = FeeSchedule {
+            default: percentage.clone(),
+            operations: vec![(FeeOperation::Transfer, FeeRule::Flat(1))],
+        };
+        assert!(schedule.validate().is_err());
+
+        let schedule = FeeSchedule {
+            default: FeeRule::Flat(1),
+            operations: vec![(FeeOperation::Burn, percentage)],
+        };
+        assert_eq!(schedule.validate(), Ok(()));
+        assert_eq!(FeeSchedule::default().validate(), Ok(()));
     }
 }
@@ -1,10 +1,11 @@
-use crate::common_types::TxRecord;
+use crate::common_types::{Account, TxRecord};
+use crate::memory;
 use crate::stats::{CountTarget, StatsData};
 use crate::utils::convert_nat_to_u64;
 use ic_kit::{candid::Nat, get_context, macros::*, Context, Principal};
 use std::cmp::min;
 use std::convert::TryInto;
-use xtc_history::data::{HistoryArchive, HistoryArchiveBorrowed};
+use xtc_history::data::{HistoryArchive, HistoryData, HistoryState};
 use xtc_history::History;
 
 use xtc_history::ic::IcBackend;
@@ -14,18 +15,31 @@ pub struct HistoryBuffer {
     history: History,
 }
 
+const FLUSH_THRESHOLD: usize = 504_000;
+const CHUNK_SIZE: usize = 5_000;
+
 impl Default for HistoryBuffer {
+    /// Create an empty history, this drops the events stored in the stable memory.
     fn default() -> Self {
+        let data = HistoryData::new(Box::new(memory::region(memory::HISTORY)));
         HistoryBuffer {
-            history: History::<Principal, IcBackend>::new(504_000, 5_000),
+            history: History::<Principal, IcBackend>::with_data(data, FLUSH_THRESHOLD, CHUNK_SIZE),
         }
     }
 }
 
 impl HistoryBuffer {
+    /// Load the history from the events stored in the stable memory and the given state.
+    pub fn restore(state: HistoryState) -> Self {
+        let data = HistoryData::restore(Box::new(memory::region(memory::HISTORY)), state);
+        HistoryBuffer {
+            history: History::<Principal, IcBackend>::with_data(data, FLUSH_THRESHOLD, CHUNK_SIZE),
+        }
+    }
+
     #[inline]
-    pub fn archive(&mut self) -> HistoryArchiveBorrowed {
-        self.history.archive()
+    pub fn state(&self) -> HistoryState {
+        self.history.state()
     }
 
     #[inline]
@@ -59,11 +73,32 @@ impl HistoryBuffer {
             TransactionKind::Burn { .. } => CountTarget::Burn,
             TransactionKind::CanisterCalled { .. } => CountTarget::ProxyCall,
             TransactionKind::CanisterCreated { .. } => CountTarget::CanisterCreated,
+            TransactionKind::CanisterManaged { .. } => CountTarget::ProxyCall,
+            TransactionKind::CanisterDeployed { .. } => CountTarget::CanisterCreated,
+            TransactionKind::FeeCollected { .. } => {
+                panic!("fee credits are recorded with push_fee_credit")
+            }
         });
         transaction.timestamp = transaction.timestamp / 1000000;
+
         self.history.push(transaction)
     }
 
+    /// Record the credit of a fee to the collector, see `FeeCollector::collect`. The credit is
+    /// not counted in the stats, the fee was counted along with the transaction that paid it.
+    pub fn push_fee_credit(&mut self, collector: Account, fee: u64) -> TransactionId {
+        self.history.push(Transaction {
+            timestamp: get_context().time() / 1000000,
+            cycles: fee,
+            fee: 0,
+            kind: TransactionKind::FeeCollected {
+                to: collector.owner,
+                to_subaccount: collector.subaccount,
+            },
+            status: TransactionStatus::SUCCEEDED,
+        })
+    }
+
     #[inline]
     pub fn len(&self) -> u64 {
         self.history.len()
@@ -102,12 +137,11 @@ pub fn get_transactions(start: Nat, limit: Nat) -> Vec<TxRecord> {
 
     let start_usize = convert_nat_to_u64(start).unwrap() as usize;
     let limit_usize = convert_nat_to_u64(limit).unwrap() as usize;
-    let events = get_context()
+    let data = get_context()
         .get::<HistoryBuffer>()
         .history()
-        .get_history_data()
-        .get_events();
-    let history_usize = events.len();
+        .get_history_data();
+    let history_usize = data.len();
 
     if (start_usize >= history_usize) {
         ic_cdk::api::trap(&format!(
@@ -116,7 +150,7 @@ pub fn get_transactions(start: Nat, limit: Nat) -> Vec<TxRecord> {
         ))
     }
 
-    (&events[start_usize..min(history_usize, start_usize + limit_usize)])
+    data.get_events(start_usize, min(history_usize, start_usize + limit_usize))
         .into_iter()
         .enumerate()
         .filter_map(|tx_pair| match (tx_pair.1.clone()).try_into().ok() {
@@ -137,7 +171,7 @@ pub async fn get_transaction(id: TransactionId) -> Option<Transaction> {
 }
 
 #[query]
-fn events(args: EventsArgs) -> EventsConnection<'static> {
+fn events(args: EventsArgs) -> EventsConnection {
     let ic = get_context();
     let offset = args.offset;
     let limit = args.limit.min(512);
@@ -0,0 +1,1080 @@
+//! Minting XTC from ICP. The ICP sent to XTC is forwarded to the cycles minting canister which
+//! tops up XTC with cycles, and the cycles are then credited to the sender.
+//!
+//! Every mint is persisted as a pending record that goes through the stages below, so a mint
+//! that failed half way can be resumed by anyone with `resume_icp_mint` instead of leaving the
+//! ICP burned and nothing credited.
+//!
+//! The ICP can either be sent to the default account of XTC and claimed with the block height,
+//! or to the deposit account of the user and claimed with `mint_from_deposit`.
+
+use crate::common_types::{TxError, TxReceipt};
+use crate::fee::{compute_fee, FeeCollector, FeeOperation};
+use crate::history::{HistoryBuffer, Transaction, TransactionKind, TransactionStatus};
+use crate::ledger::{Ledger, UsedBlocks};
+use crate::management::{AdminAction, AuditLog, PauseFlags, PauseTarget, Role, Roles};
+use crate::proposals::Proposals;
+use crate::utils::convert_nat_to_u64;
+use cycles_minting_canister::{
+    IcpXdrConversionRateCertifiedResponse, TokensToCycles, DEFAULT_CYCLES_PER_XDR,
+};
+use dfn_protobuf::{ProtoBuf, ToProto};
+use ic_kit::candid::{CandidType, Nat};
+use ic_kit::macros::*;
+use ic_kit::{get_context, ic, ic::call, Context, Principal};
+use ic_types::PrincipalId;
+use ledger_canister::{
+    account_identifier::{AccountIdentifier, Subaccount as IcpSubaccount},
+    tokens::Tokens,
+    AccountBalanceArgs, BlockHeight, BlockRes, Memo, Operation as Operate, SendArgs,
+};
+use on_wire::{FromWire, IntoWire};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MEMO_TOP_UP_CANISTER: u64 = 1347768404_u64;
+const MAX_RETRY: u8 = 5;
+
+/// The canisters minting goes through, defaults to the mainnet ones.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IcpConfig {
+    pub ledger: Principal,
+    pub cycles_minting_canister: Principal,
+    /// The fee of the ICP ledger in e8s.
+    pub icp_fee: u64,
+}
+
+impl Default for IcpConfig {
+    fn default() -> Self {
+        IcpConfig {
+            ledger: Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap(),
+            cycles_minting_canister: Principal::from_text("rkp4c-7iaaa-aaaaa-aaaca-cai").unwrap(),
+            icp_fee: 10_000,
+        }
+    }
+}
+
+impl IcpConfig {
+    pub fn load(data: IcpConfig) {
+        *ic::get_mut::<IcpConfig>() = data;
+    }
+
+    #[inline]
+    pub fn get() -> IcpConfig {
+        *ic::get::<IcpConfig>()
+    }
+
+    /// Replace the configuration and record the change in the audit log.
+    pub fn set(config: IcpConfig) {
+        IcpConfig::load(config);
+        AuditLog::record(AdminAction::SetIcpConfig { config });
+    }
+
+    #[inline]
+    fn fee(&self) -> Tokens {
+        Tokens::from_e8s(self.icp_fee)
+    }
+}
+
+/// The ICP/XDR rate of the cycles minting canister.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IcpRate {
+    pub xdr_permyriad_per_icp: u64,
+    /// The time the cycles minting canister set the rate at.
+    pub timestamp_seconds: u64,
+}
+
+/// The last rate fetched from the cycles minting canister, it is used for the mints and the
+/// quotes until it gets older than max_age.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IcpRateCache {
+    pub rate: Option<IcpRate>,
+    /// The nanoseconds a rate can be used for after the cycles minting canister set it.
+    pub max_age: u64,
+}
+
+impl Default for IcpRateCache {
+    fn default() -> Self {
+        IcpRateCache {
+            rate: None,
+            // 10 minutes.
+            max_age: 600_000_000_000,
+        }
+    }
+}
+
+impl IcpRateCache {
+    pub fn load(data: IcpRateCache) {
+        *ic::get_mut::<IcpRateCache>() = data;
+    }
+
+    #[inline]
+    pub fn get() -> IcpRateCache {
+        *ic::get::<IcpRateCache>()
+    }
+
+    /// Replace the staleness bound and record the change in the audit log.
+    pub fn set_max_age(max_age: u64) {
+        ic::get_mut::<IcpRateCache>().max_age = max_age;
+        AuditLog::record(AdminAction::SetIcpRateMaxAge { max_age });
+    }
+
+    /// Return the cached rate if it is not stale at the given time.
+    fn fresh(now: u64) -> Option<IcpRate> {
+        let cache = ic::get::<IcpRateCache>();
+        cache.rate.filter(|rate| {
+            let set_at = rate.timestamp_seconds.saturating_mul(1_000_000_000);
+            now <= set_at.saturating_add(cache.max_age)
+        })
+    }
+}
+
+/// The argument of `notify_top_up` on the cycles minting canister.
+#[derive(CandidType, Deserialize)]
+struct NotifyTopUpArg {
+    block_index: BlockHeight,
+    canister_id: Principal,
+}
+
+/// The errors of `notify_top_up` on the cycles minting canister.
+#[derive(CandidType, Deserialize, Debug)]
+enum NotifyError {
+    Refunded {
+        reason: String,
+        block_index: Option<BlockHeight>,
+    },
+    Processing,
+    TransactionTooOld(BlockHeight),
+    InvalidTransaction(String),
+    Other {
+        error_code: u64,
+        error_message: String,
+    },
+}
+
+/// The expected outcome of minting from an ICP transfer.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IcpMintQuote {
+    /// The cycles credited after the ICP fee and the XTC fee.
+    pub cycles: u64,
+    /// The XTC fee.
+    pub fee: u64,
+    /// The rate the quote is based on.
+    pub rate: IcpRate,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IcpMintStage {
+    /// The block was checked and reserved for the mint.
+    Verified,
+    /// The ICP was sent to the cycles minting canister in send_block, the cycles and the fee
+    /// are the ones quoted before sending, the credited ones follow the notify.
+    Sent {
+        send_block: BlockHeight,
+        cycles: u64,
+        fee: u64,
+    },
+    /// The cycles minting canister topped up XTC, the cycles and the fee are taken from the
+    /// cycles it reported.
+    Notified {
+        send_block: BlockHeight,
+        cycles: u64,
+        fee: u64,
+    },
+}
+
+/// A mint that has not been credited yet, the record is dropped once the XTC is credited.
+#[derive(CandidType, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct IcpMint {
+    pub to: Principal,
+    /// The subaccount of XTC the ICP is sent to the cycles minting canister from.
+    pub from_subaccount: Option<IcpSubaccount>,
+    /// The e8s left to send to the cycles minting canister after the ledger fee.
+    pub amount: u64,
+    pub stage: IcpMintStage,
+    /// The last time the mint moved to another stage.
+    pub updated_at: u64,
+}
+
+/// The pending mints by the block of the ICP transfer to XTC, mints from a deposit are keyed
+/// by the block sending the deposit to the cycles minting canister.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct IcpMints(HashMap<BlockHeight, IcpMint>);
+
+impl IcpMints {
+    pub fn load(data: IcpMints) {
+        *ic::get_mut::<IcpMints>() = data;
+    }
+
+    #[inline]
+    pub fn get() -> IcpMints {
+        ic::get::<IcpMints>().clone()
+    }
+
+    fn set_stage(block_height: BlockHeight, stage: IcpMintStage) {
+        if let Some(mint) = ic::get_mut::<IcpMints>().0.get_mut(&block_height) {
+            mint.stage = stage;
+            mint.updated_at = ic::time();
+        }
+    }
+}
+
+/// The mints with an inter-canister call in flight, a mint must never be advanced by two
+/// calls at the same time or the ICP could be sent twice. Not persisted since upgrades require
+/// the canister to be stopped.
+#[derive(Default)]
+struct IcpMintsInFlight(HashSet<BlockHeight>);
+
+/// The subaccount of XTC the given principal deposits ICP to.
+pub fn deposit_subaccount(owner: Principal) -> IcpSubaccount {
+    IcpSubaccount::from(&PrincipalId::from(owner))
+}
+
+/// Call a protobuf method of the ICP ledger, or one of its archive canisters.
+async fn call_protobuf<A: ToProto, R: ToProto>(
+    canister: Principal,
+    method: &str,
+    args: A,
+) -> Result<R, TxError> {
+    let args = ProtoBuf(args).into_bytes().map_err(|_| TxError::Other)?;
+    let response = get_context()
+        .call_raw(canister, method, args, 0)
+        .await
+        .map_err(|_| TxError::Other)?;
+    ProtoBuf::<R>::from_bytes(response)
+        .map(|ProtoBuf(r)| r)
+        .map_err(|_| TxError::Other)
+}
+
+/// Return the sender, the receiver and the amount of the transfer in the given block, along
+/// with the time the block was created at.
+async fn get_block_info(
+    block_height: BlockHeight,
+) -> Result<(AccountIdentifier, AccountIdentifier, Tokens, u64), TxError> {
+    let BlockRes(block_response) =
+        call_protobuf(IcpConfig::get().ledger, "block_pb", block_height).await?;
+
+    let block = match block_response.ok_or(TxError::Other)? {
+        Ok(encode_block) => encode_block,
+        Err(storage_canister) => {
+            let BlockRes(block_response) =
+                call_protobuf(storage_canister.get().0, "get_block_pb", block_height).await?;
+            block_response
+                .ok_or(TxError::Other)?
+                .map_err(|_| TxError::Other)?
+        }
+    }
+    .decode()
+    .map_err(|_| TxError::Other)?;
+
+    let created_at = SystemTime::from(block.timestamp)
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| TxError::Other)?
+        .as_nanos() as u64;
+
+    match block.transaction.operation {
+        Operate::Transfer {
+            from,
+            to,
+            amount,
+            fee: _,
+        } => Ok((from, to, amount, created_at)),
+        _ => {
+            return Err(TxError::ErrorOperationStyle);
+        }
+    }
+}
+
+/// Check the ICP transfer in the given block and record the mint to the owner of the sending
+/// account.
+async fn verify_icp_mint(
+    sub_account: Option<IcpSubaccount>,
+    block_height: BlockHeight,
+    owner: Principal,
+) -> Result<(), TxError> {
+    let (from, to, amount, created_at) = get_block_info(block_height).await?;
+
+    let used_blocks = ic::get_mut::<UsedBlocks>();
+
+    // guard
+    if !used_blocks.insert(block_height, created_at) {
+        return Err(TxError::BlockUsed);
+    }
+
+    let from_account = AccountIdentifier::new(PrincipalId::from(owner), sub_account);
+    let xtc_account = AccountIdentifier::new(PrincipalId::from(ic::id()), None);
+
+    if from_account != from {
+        used_blocks.remove(&block_height);
+        return Err(TxError::Unauthorized);
+    }
+
+    if xtc_account != to {
+        used_blocks.remove(&block_height);
+        return Err(TxError::ErrorTo);
+    }
+
+    // ====================================================
+    // The fee of "send_dfx"
+    let amount = (amount - IcpConfig::get().fee()).map_err(|_| {
+        used_blocks.remove(&block_height);
+        TxError::AmountTooSmall
+    })?;
+    // ====================================================
+
+    ic::get_mut::<IcpMints>().0.insert(
+        block_height,
+        IcpMint {
+            to: owner,
+            from_subaccount: None,
+            amount: amount.get_e8s(),
+            stage: IcpMintStage::Verified,
+            updated_at: ic::time(),
+        },
+    );
+
+    Ok(())
+}
+
+/// Run the pending mint from its current stage until it is credited or a step fails, the
+/// mint stays at the last completed stage on failures.
+async fn advance_icp_mint(block_height: BlockHeight) -> TxReceipt {
+    if !ic::get_mut::<IcpMintsInFlight>().0.insert(block_height) {
+        return Err(TxError::BlockUsed);
+    }
+
+    let result = run_icp_mint(block_height).await;
+    ic::get_mut::<IcpMintsInFlight>().0.remove(&block_height);
+    result
+}
+
+/// Return the cycles the cycles minting canister tops up for the given ICP at the rate.
+fn to_cycles(rate: IcpRate, amount: Tokens) -> u64 {
+    (TokensToCycles {
+        xdr_permyriad_per_icp: rate.xdr_permyriad_per_icp,
+        cycles_per_xdr: DEFAULT_CYCLES_PER_XDR.into(),
+    })
+    .to_cycles(amount)
+    .into()
+}
+
+/// Return the cycles the cycles minting canister tops up for the given ICP at the rate,
+/// together with the XTC fee taken from them.
+fn convert(rate: IcpRate, amount: Tokens) -> Result<(u64, u64), TxError> {
+    let cycles = to_cycles(rate, amount);
+    let fee = compute_fee(FeeOperation::MintByIcp, cycles);
+    if cycles <= fee {
+        return Err(TxError::InsufficientXTCFee);
+    }
+
+    // actual user cycles
+    Ok((cycles - fee, fee))
+}
+
+/// Fetch the rate from the cycles minting canister and cache it, fails with StaleRate if the
+/// cycles minting canister set it more than max_age ago.
+async fn fetch_icp_rate() -> Result<IcpRate, TxError> {
+    let response = call::<_, (IcpXdrConversionRateCertifiedResponse,), _>(
+        IcpConfig::get().cycles_minting_canister,
+        "get_icp_xdr_conversion_rate",
+        (),
+    )
+    .await
+    .map_err(|_| TxError::FetchRateFailed)?
+    .0;
+
+    let cache = ic::get_mut::<IcpRateCache>();
+    cache.rate = Some(IcpRate {
+        xdr_permyriad_per_icp: response.data.xdr_permyriad_per_icp,
+        timestamp_seconds: response.data.timestamp_seconds,
+    });
+
+    IcpRateCache::fresh(ic::time()).ok_or(TxError::StaleRate)
+}
+
+/// Return the cached rate, or fetch it from the cycles minting canister once it is stale.
+async fn icp_rate() -> Result<IcpRate, TxError> {
+    match IcpRateCache::fresh(ic::time()) {
+        Some(rate) => Ok(rate),
+        None => fetch_icp_rate().await,
+    }
+}
+
+/// Return the cycles the cycles minting canister tops up for the given ICP, together with the
+/// XTC fee taken from them.
+async fn quote_cycles(amount: Tokens) -> Result<(u64, u64), TxError> {
+    convert(icp_rate().await?, amount)
+}
+
+/// Send the ICP to the cycles minting canister to top up XTC and return the ledger block.
+async fn send_to_cmc(
+    amount: Tokens,
+    from_subaccount: Option<IcpSubaccount>,
+) -> Result<BlockHeight, TxError> {
+    let config = IcpConfig::get();
+    let send_block = call::<_, (u64,), _>(
+        config.ledger,
+        "send_dfx",
+        (SendArgs {
+            memo: Memo(MEMO_TOP_UP_CANISTER),
+            amount,
+            fee: config.fee(),
+            from_subaccount,
+            to: AccountIdentifier::new(
+                PrincipalId::from(config.cycles_minting_canister),
+                Some(IcpSubaccount::from(&PrincipalId::from(ic::id()))),
+            ),
+            created_at_time: None,
+        },),
+    )
+    .await
+    .map_err(|_| TxError::LedgerTrap)?
+    .0;
+    Ok(send_block)
+}
+
+/// Send the ICP in the deposit account of the owner to the cycles minting canister and record
+/// the mint, return the block of the send.
+async fn send_deposit(owner: Principal) -> Result<BlockHeight, TxError> {
+    let config = IcpConfig::get();
+    let from_subaccount = deposit_subaccount(owner);
+
+    let balance = call::<_, (Tokens,), _>(
+        config.ledger,
+        "account_balance_dfx",
+        (AccountBalanceArgs {
+            account: AccountIdentifier::new(PrincipalId::from(ic::id()), Some(from_subaccount)),
+        },),
+    )
+    .await
+    .map_err(|_| TxError::LedgerTrap)?
+    .0;
+
+    // The fee of "send_dfx"
+    let amount = (balance - config.fee()).map_err(|_| TxError::AmountTooSmall)?;
+
+    let (cycles, fee) = quote_cycles(amount).await?;
+
+    // Concurrent calls read the same balance, but the ledger rejects all sends except the
+    // first one as the deposit no longer covers them.
+    let send_block = send_to_cmc(amount, Some(from_subaccount)).await?;
+
+    ic::get_mut::<UsedBlocks>().insert(send_block, ic::time());
+    ic::get_mut::<IcpMints>().0.insert(
+        send_block,
+        IcpMint {
+            to: owner,
+            from_subaccount: Some(from_subaccount),
+            amount: amount.get_e8s(),
+            stage: IcpMintStage::Sent {
+                send_block,
+                cycles,
+                fee,
+            },
+            updated_at: ic::time(),
+        },
+    );
+
+    Ok(send_block)
+}
+
+async fn run_icp_mint(block_height: BlockHeight) -> TxReceipt {
+    let cycles_minting_canister = IcpConfig::get().cycles_minting_canister;
+
+    loop {
+        let mint = match ic::get::<IcpMints>().0.get(&block_height) {
+            Some(mint) => mint.clone(),
+            None if ic::get::<UsedBlocks>().contains(&block_height) => {
+                return Err(TxError::BlockUsed)
+            }
+            None => return Err(TxError::Other),
+        };
+
+        match mint.stage {
+            IcpMintStage::Verified => {
+                let amount = Tokens::from_e8s(mint.amount);
+
+                let (cycles, fee) = match quote_cycles(amount).await {
+                    Err(TxError::InsufficientXTCFee) => {
+                        // Nothing was sent yet, the block can be used again.
+                        ic::get_mut::<IcpMints>().0.remove(&block_height);
+                        ic::get_mut::<UsedBlocks>().remove(&block_height);
+                        return Err(TxError::InsufficientXTCFee);
+                    }
+                    result => result?,
+                };
+
+                let send_block = send_to_cmc(amount, mint.from_subaccount).await?;
+
+                // track `user transferred block` that map to `canister burned block`
+                ic::get_mut::<UsedBlocks>().set_send_block(block_height, send_block);
+                IcpMints::set_stage(
+                    block_height,
+                    IcpMintStage::Sent {
+                        send_block,
+                        cycles,
+                        fee,
+                    },
+                );
+            }
+            IcpMintStage::Sent { send_block, .. } => {
+                // ====================================================
+                // Notify - Retry until successful
+                let mut result: Option<Result<Nat, NotifyError>> = None;
+                for _ in 0..MAX_RETRY {
+                    match call::<_, (Result<Nat, NotifyError>,), _>(
+                        cycles_minting_canister,
+                        "notify_top_up",
+                        (NotifyTopUpArg {
+                            block_index: send_block,
+                            canister_id: ic::id(),
+                        },),
+                    )
+                    .await
+                    {
+                        Ok((Err(NotifyError::Processing),)) | Err(_) => continue,
+                        Ok((response,)) => {
+                            result = Some(response);
+                            break;
+                        }
+                    }
+                }
+                // ====================================================
+
+                // The cycles minting canister converts the ICP at its rate of the time of the
+                // notify, the cycles it topped up XTC with are the ones credited.
+                let received = match result.ok_or(TxError::NotifyDfxFailed)? {
+                    Ok(received) => convert_nat_to_u64(received)
+                        .map_err(|_| TxError::UnexpectedCyclesResponse)?,
+                    Err(_) => return Err(TxError::UnexpectedCyclesResponse),
+                };
+
+                if received == 0 {
+                    // Nothing to credit, the block stays used.
+                    ic::get_mut::<IcpMints>().0.remove(&block_height);
+                    return Err(TxError::AmountTooSmall);
+                }
+
+                let fee = compute_fee(FeeOperation::MintByIcp, received).min(received);
+                IcpMints::set_stage(
+                    block_height,
+                    IcpMintStage::Notified {
+                        send_block,
+                        cycles: received - fee,
+                        fee,
+                    },
+                );
+            }
+            IcpMintStage::Notified { cycles, fee, .. } => {
+                // ====================================================
+                // Credit XTC
+                ic::get_mut::<IcpMints>().0.remove(&block_height);
+                ic::get_mut::<Ledger>().deposit(&mint.to.into(), cycles);
+                let id = ic::get_mut::<HistoryBuffer>().push(Transaction {
+                    timestamp: ic::time(),
+                    cycles,
+                    fee,
+                    kind: TransactionKind::Mint {
+                        to: mint.to,
+                        to_subaccount: None,
+                    },
+                    status: TransactionStatus::SUCCEEDED,
+                });
+                FeeCollector::collect(fee);
+                return Ok(Nat::from(id));
+                // ====================================================
+            }
+        }
+    }
+}
+
+#[update]
+pub async fn mint_by_icp(
+    sub_account: Option<IcpSubaccount>,
+    block_height: BlockHeight,
+) -> TxReceipt {
+    PauseFlags::guard(PauseTarget::MintByIcp);
+
+    let caller = ic::caller();
+
+    crate::progress().await;
+
+    verify_icp_mint(sub_account, block_height, caller).await?;
+    advance_icp_mint(block_height).await
+}
+
+#[update]
+pub async fn mint_by_icp_recover(
+    sub_account: Option<IcpSubaccount>,
+    block_height: BlockHeight,
+    user_principal: Principal,
+) -> TxReceipt {
+    PauseFlags::guard(PauseTarget::MintByIcp);
+
+    crate::progress().await;
+
+    verify_icp_mint(sub_account, block_height, user_principal).await?;
+    advance_icp_mint(block_height).await
+}
+
+/// Mint XTC from the ICP in the deposit account of the caller, see `get_deposit_account`.
+#[update]
+pub async fn mint_from_deposit() -> TxReceipt {
+    PauseFlags::guard(PauseTarget::MintByIcp);
+
+    let caller = ic::caller();
+
+    crate::progress().await;
+
+    let block_height = send_deposit(caller).await?;
+    advance_icp_mint(block_height).await
+}
+
+/// Return the ICP account the caller deposits to for `mint_from_deposit`.
+#[query]
+pub fn get_deposit_account() -> String {
+    AccountIdentifier::new(
+        PrincipalId::from(ic::id()),
+        Some(deposit_subaccount(ic::caller())),
+    )
+    .to_hex()
+}
+
+/// Continue a pending mint from its last completed stage, the XTC is always credited to the
+/// owner of the ICP so anyone can call this.
+#[update]
+pub async fn resume_icp_mint(block_height: BlockHeight) -> TxReceipt {
+    PauseFlags::guard(PauseTarget::MintByIcp);
+
+    crate::progress().await;
+
+    advance_icp_mint(block_height).await
+}
+
+/// Return what a transfer of the given e8s to XTC mints at the cached rate, fails with
+/// StaleRate when there is no fresh rate to quote with, see `refresh_icp_rate`. The mint credits
+/// the cycles at the rate of the cycles minting canister when it is notified, which may differ.
+#[query]
+pub fn quote_mint_by_icp(e8s: u64) -> Result<IcpMintQuote, TxError> {
+    let rate = IcpRateCache::fresh(ic::time()).ok_or(TxError::StaleRate)?;
+
+    // The fee of "send_dfx"
+    let amount =
+        (Tokens::from_e8s(e8s) - IcpConfig::get().fee()).map_err(|_| TxError::AmountTooSmall)?;
+
+    let (cycles, fee) = convert(rate, amount)?;
+    Ok(IcpMintQuote { cycles, fee, rate })
+}
+
+/// Fetch the rate from the cycles minting canister unless the cached one is still fresh.
+#[update]
+pub async fn refresh_icp_rate() -> Result<IcpRate, TxError> {
+    icp_rate().await
+}
+
+#[update]
+pub fn set_icp_rate_max_age(max_age: u64) {
+    Roles::guard(Role::Admin);
+    Proposals::guard_direct();
+
+    IcpRateCache::set_max_age(max_age);
+}
+
+#[query]
+pub fn get_icp_rate() -> IcpRateCache {
+    IcpRateCache::get()
+}
+
+#[update]
+pub fn set_icp_config(config: IcpConfig) {
+    Roles::guard(Role::Admin);
+    Proposals::guard_direct();
+
+    IcpConfig::set(config);
+}
+
+#[query]
+pub fn get_icp_config() -> IcpConfig {
+    IcpConfig::get()
+}
+
+/// Return the pending mints that are not being processed right now, together with the block
+/// of the ICP transfer they were started with.
+#[query]
+pub fn get_stuck_icp_mints() -> Vec<(BlockHeight, IcpMint)> {
+    let in_flight = ic::get::<IcpMintsInFlight>();
+    let mut mints: Vec<(BlockHeight, IcpMint)> = ic::get::<IcpMints>()
+        .0
+        .iter()
+        .filter(|(block_height, _)| !in_flight.0.contains(block_height))
+        .map(|(block_height, mint)| (*block_height, mint.clone()))
+        .collect();
+    mints.sort_by_key(|(block_height, _)| *block_height);
+    mints
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::TRANSACTION_WINDOW;
+    use ic_kit::{async_test, mock_principals, MockContext};
+    use icp_mock::IcpMock;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// 1 ICP plus the fee of `send_dfx`, mints 1T cycles at the mock rate.
+    const ONE_ICP: u64 = 100_000_000 + 10_000;
+
+    fn mock_icp(ctx: &mut MockContext) -> Rc<RefCell<IcpMock>> {
+        let mock = Rc::new(RefCell::new(IcpMock::default()));
+        ctx.clear_handlers();
+        ctx.use_handler(icp_mock::handler(mock.clone()));
+        mock
+    }
+
+    fn send_icp(mock: &RefCell<IcpMock>, from: Principal, to: Principal, e8s: u64) -> BlockHeight {
+        mock.borrow_mut().transfer(
+            AccountIdentifier::new(PrincipalId::from(from), None),
+            AccountIdentifier::new(PrincipalId::from(to), None),
+            Tokens::from_e8s(e8s),
+            Tokens::from_e8s(10_000),
+            ic::time(),
+        )
+    }
+
+    #[async_test]
+    async fn resume_notified_mint() {
+        let ctx = MockContext::new()
+            .with_caller(mock_principals::bob())
+            .inject();
+
+        ctx.get_mut::<UsedBlocks>().insert(7, ctx.time());
+        IcpMints::load(IcpMints(
+            vec![(
+                7,
+                IcpMint {
+                    to: mock_principals::alice(),
+                    from_subaccount: None,
+                    amount: 0,
+                    stage: IcpMintStage::Notified {
+                        send_block: 12,
+                        cycles: 1_000,
+                        fee: 2_000_000_000,
+                    },
+                    updated_at: 0,
+                },
+            )]
+            .into_iter()
+            .collect(),
+        ));
+        assert_eq!(get_stuck_icp_mints().len(), 1);
+
+        // Anyone can resume a mint, the XTC goes to the owner of the ICP.
+        resume_icp_mint(7).await.expect("Unexpected error.");
+        assert_eq!(
+            ctx.get::<Ledger>()
+                .balance(&mock_principals::alice().into()),
+            1_000
+        );
+        assert_eq!(get_stuck_icp_mints(), vec![]);
+        assert_eq!(resume_icp_mint(7).await, Err(TxError::BlockUsed));
+        assert_eq!(resume_icp_mint(8).await, Err(TxError::Other));
+    }
+
+    #[async_test]
+    async fn mint_by_icp_mock() {
+        let ctx = MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+        let mock = mock_icp(ctx);
+
+        let block = send_icp(&mock, mock_principals::alice(), ctx.id(), ONE_ICP);
+        mint_by_icp(None, block).await.expect("Unexpected error.");
+
+        let fee = compute_fee(FeeOperation::MintByIcp, 1_000_000_000_000);
+        assert_eq!(
+            ctx.get::<Ledger>()
+                .balance(&mock_principals::alice().into()),
+            1_000_000_000_000 - fee
+        );
+        assert_eq!(ctx.get::<UsedBlocks>().send_block(&block), Some(block + 1));
+        assert_eq!(mint_by_icp(None, block).await, Err(TxError::BlockUsed));
+        assert_eq!(get_stuck_icp_mints(), vec![]);
+    }
+
+    #[async_test]
+    async fn mint_by_icp_rejected() {
+        let ctx = MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+        let mock = mock_icp(ctx);
+        let xtc = ctx.id();
+
+        let block = send_icp(&mock, mock_principals::bob(), xtc, ONE_ICP);
+        assert_eq!(mint_by_icp(None, block).await, Err(TxError::Unauthorized));
+        assert!(!ctx.get::<UsedBlocks>().contains(&block));
+
+        let block = send_icp(
+            &mock,
+            mock_principals::alice(),
+            mock_principals::john(),
+            ONE_ICP,
+        );
+        assert_eq!(mint_by_icp(None, block).await, Err(TxError::ErrorTo));
+        assert!(!ctx.get::<UsedBlocks>().contains(&block));
+
+        let block = send_icp(&mock, mock_principals::alice(), xtc, 5_000);
+        assert_eq!(mint_by_icp(None, block).await, Err(TxError::AmountTooSmall));
+        assert!(!ctx.get::<UsedBlocks>().contains(&block));
+
+        // 1B cycles does not cover the fee.
+        let block = send_icp(&mock, mock_principals::alice(), xtc, 10_000 + 100_000);
+        assert_eq!(
+            mint_by_icp(None, block).await,
+            Err(TxError::InsufficientXTCFee)
+        );
+        assert!(!ctx.get::<UsedBlocks>().contains(&block));
+
+        let block = mock.borrow_mut().mint(
+            AccountIdentifier::new(PrincipalId::from(xtc), None),
+            Tokens::from_e8s(ONE_ICP),
+            0,
+        );
+        assert_eq!(
+            mint_by_icp(None, block).await,
+            Err(TxError::ErrorOperationStyle)
+        );
+
+        assert_eq!(mint_by_icp(None, block + 1).await, Err(TxError::Other));
+        assert_eq!(get_stuck_icp_mints(), vec![]);
+        assert_eq!(
+            ctx.get::<Ledger>()
+                .balance(&mock_principals::alice().into()),
+            0
+        );
+    }
+
+    #[async_test]
+    async fn mint_by_icp_late_claim() {
+        let ctx = MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+        let mock = mock_icp(ctx);
+
+        // A block created before the transaction window can still be claimed once.
+        let block = mock.borrow_mut().transfer(
+            AccountIdentifier::new(PrincipalId::from(mock_principals::alice()), None),
+            AccountIdentifier::new(PrincipalId::from(ctx.id()), None),
+            Tokens::from_e8s(ONE_ICP),
+            Tokens::from_e8s(10_000),
+            ctx.time() - TRANSACTION_WINDOW - 1,
+        );
+        mint_by_icp(None, block).await.expect("Unexpected error.");
+
+        // The block is archived when it is pruned and the next claims are still rejected.
+        assert_eq!(ctx.get_mut::<UsedBlocks>().prune(ctx.time(), 10), 1);
+        assert_eq!(mint_by_icp(None, block).await, Err(TxError::BlockUsed));
+        assert_eq!(ctx.get::<UsedBlocks>().send_block(&block), Some(block + 1));
+
+        let fee = compute_fee(FeeOperation::MintByIcp, 1_000_000_000_000);
+        assert_eq!(
+            ctx.get::<Ledger>()
+                .balance(&mock_principals::alice().into()),
+            1_000_000_000_000 - fee
+        );
+    }
+
+    #[async_test]
+    async fn mint_by_icp_notify_failure() {
+        let ctx = MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+        let mock = mock_icp(ctx);
+
+        let block = send_icp(&mock, mock_principals::alice(), ctx.id(), ONE_ICP);
+        mock.borrow_mut().notify_failures = MAX_RETRY as u32;
+        assert_eq!(
+            mint_by_icp(None, block).await,
+            Err(TxError::NotifyDfxFailed)
+        );
+
+        // The ICP is already sent to the cycles minting canister, only the notify is retried.
+        let stuck = get_stuck_icp_mints();
+        assert_eq!(stuck.len(), 1);
+        match stuck[0].1.stage {
+            IcpMintStage::Sent { send_block, .. } => assert_eq!(send_block, block + 1),
+            _ => panic!("Expected the mint to be sent."),
+        }
+        assert_eq!(
+            ctx.get::<Ledger>()
+                .balance(&mock_principals::alice().into()),
+            0
+        );
+
+        resume_icp_mint(block).await.expect("Unexpected error.");
+        let fee = compute_fee(FeeOperation::MintByIcp, 1_000_000_000_000);
+        assert_eq!(
+            ctx.get::<Ledger>()
+                .balance(&mock_principals::alice().into()),
+            1_000_000_000_000 - fee
+        );
+        assert_eq!(get_stuck_icp_mints(), vec![]);
+    }
+
+    #[async_test]
+    async fn mint_from_deposit_mock() {
+        let ctx = MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+        let mock = mock_icp(ctx);
+        let deposit = AccountIdentifier::new(
+            PrincipalId::from(ctx.id()),
+            Some(deposit_subaccount(mock_principals::alice())),
+        );
+        assert_eq!(get_deposit_account(), deposit.to_hex());
+
+        assert_eq!(mint_from_deposit().await, Err(TxError::AmountTooSmall));
+
+        mock.borrow_mut()
+            .mint(deposit, Tokens::from_e8s(ONE_ICP), 0);
+        mock.borrow_mut().notify_failures = MAX_RETRY as u32;
+        assert_eq!(mint_from_deposit().await, Err(TxError::NotifyDfxFailed));
+
+        // The deposit is already sent, the mint continues from the send block.
+        let stuck = get_stuck_icp_mints();
+        assert_eq!(stuck.len(), 1);
+        let (send_block, mint) = stuck[0].clone();
+        assert_eq!(
+            mint.from_subaccount,
+            Some(deposit_subaccount(mock_principals::alice()))
+        );
+        assert_eq!(mint_from_deposit().await, Err(TxError::AmountTooSmall));
+
+        resume_icp_mint(send_block)
+            .await
+            .expect("Unexpected error.");
+        let fee = compute_fee(FeeOperation::MintByIcp, 1_000_000_000_000);
+        assert_eq!(
+            ctx.get::<Ledger>()
+                .balance(&mock_principals::alice().into()),
+            1_000_000_000_000 - fee
+        );
+        assert_eq!(get_stuck_icp_mints(), vec![]);
+        assert_eq!(mint_by_icp(None, send_block).await, Err(TxError::BlockUsed));
+
+        // The deposits are kept apart.
+        ctx.update_caller(mock_principals::bob());
+        assert_eq!(mint_from_deposit().await, Err(TxError::AmountTooSmall));
+    }
+
+    #[async_test]
+    async fn quote_mint_by_icp_cached_rate() {
+        let ctx = MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+        let mock = mock_icp(ctx);
+
+        assert_eq!(quote_mint_by_icp(ONE_ICP), Err(TxError::StaleRate));
+        let rate = refresh_icp_rate().await.expect("Unexpected error.");
+
+        let fee = compute_fee(FeeOperation::MintByIcp, 1_000_000_000_000);
+        let quote = quote_mint_by_icp(ONE_ICP).expect("Unexpected error.");
+        assert_eq!(
+            quote,
+            IcpMintQuote {
+                cycles: 1_000_000_000_000 - fee,
+                fee,
+                rate
+            }
+        );
+        assert_eq!(quote_mint_by_icp(5_000), Err(TxError::AmountTooSmall));
+        assert_eq!(
+            quote_mint_by_icp(10_000 + 100_000),
+            Err(TxError::InsufficientXTCFee)
+        );
+
+        // The quote uses the cached rate, but the mint credits the cycles at the rate the
+        // cycles minting canister has when it is notified.
+        mock.borrow_mut().xdr_permyriad_per_icp = 20_000;
+        assert_eq!(quote_mint_by_icp(ONE_ICP), Ok(quote));
+        let block = send_icp(&mock, mock_principals::alice(), ctx.id(), ONE_ICP);
+        mint_by_icp(None, block).await.expect("Unexpected error.");
+        let fee = compute_fee(FeeOperation::MintByIcp, 2_000_000_000_000);
+        assert_eq!(
+            ctx.get::<Ledger>()
+                .balance(&mock_principals::alice().into()),
+            2_000_000_000_000 - fee
+        );
+    }
+
+    #[async_test]
+    async fn mint_by_icp_stale_rate() {
+        let ctx = MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+        let mock = mock_icp(ctx);
+
+        mock.borrow_mut().rate_timestamp_seconds = Some(0);
+        assert_eq!(refresh_icp_rate().await, Err(TxError::StaleRate));
+        assert_eq!(quote_mint_by_icp(ONE_ICP), Err(TxError::StaleRate));
+
+        // Nothing is sent with a stale rate, the mint can be resumed once the rate is updated.
+        let block = send_icp(&mock, mock_principals::alice(), ctx.id(), ONE_ICP);
+        assert_eq!(mint_by_icp(None, block).await, Err(TxError::StaleRate));
+        assert_eq!(get_stuck_icp_mints()[0].1.stage, IcpMintStage::Verified);
+
+        mock.borrow_mut().rate_timestamp_seconds = None;
+        resume_icp_mint(block).await.expect("Unexpected error.");
+        assert_eq!(get_stuck_icp_mints(), vec![]);
+
+        // Once the ICP is sent the notify does not wait for a fresh rate.
+        let block = send_icp(&mock, mock_principals::alice(), ctx.id(), ONE_ICP);
+        mock.borrow_mut().notify_failures = MAX_RETRY as u32;
+        assert_eq!(
+            mint_by_icp(None, block).await,
+            Err(TxError::NotifyDfxFailed)
+        );
+        mock.borrow_mut().rate_timestamp_seconds = Some(0);
+        resume_icp_mint(block).await.expect("Unexpected error.");
+        assert_eq!(get_stuck_icp_mints(), vec![]);
+    }
+
+    #[async_test]
+    async fn mint_by_icp_credits_reported_cycles() {
+        let ctx = MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+        let mock = mock_icp(ctx);
+
+        // The cycles minting canister tops up less than quoted.
+        let block = send_icp(&mock, mock_principals::alice(), ctx.id(), ONE_ICP);
+        mock.borrow_mut().notify_cycles = Some(500_000_000_000);
+        mint_by_icp(None, block).await.expect("Unexpected error.");
+        let fee = compute_fee(FeeOperation::MintByIcp, 500_000_000_000);
+        assert_eq!(
+            ctx.get::<Ledger>()
+                .balance(&mock_principals::alice().into()),
+            500_000_000_000 - fee
+        );
+
+        // A top up below the fee is all kept as the fee.
+        let block = send_icp(&mock, mock_principals::alice(), ctx.id(), ONE_ICP);
+        mock.borrow_mut().notify_cycles = Some(1);
+        mint_by_icp(None, block).await.expect("Unexpected error.");
+        assert_eq!(
+            ctx.get::<Ledger>()
+                .balance(&mock_principals::alice().into()),
+            500_000_000_000 - fee
+        );
+
+        // Nothing is credited nor recorded without a top up.
+        let history = ctx.get::<HistoryBuffer>().len();
+        let block = send_icp(&mock, mock_principals::alice(), ctx.id(), ONE_ICP);
+        mock.borrow_mut().notify_cycles = Some(0);
+        assert_eq!(mint_by_icp(None, block).await, Err(TxError::AmountTooSmall));
+        assert_eq!(ctx.get::<HistoryBuffer>().len(), history);
+        assert_eq!(get_stuck_icp_mints(), vec![]);
+        assert_eq!(mint_by_icp(None, block).await, Err(TxError::BlockUsed));
+    }
+}
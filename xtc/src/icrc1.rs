@@ -0,0 +1,317 @@
+//! Implementation of the ICRC-1 fungible token standard on top of the XTC ledger.
+//! https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-1
+
+use crate::common_types::{Account, Subaccount};
+use crate::fee::{compute_fee, FeeCollector, FeeOperation};
+use crate::history::{
+    HistoryBuffer, Transaction, TransactionId, TransactionKind, TransactionStatus,
+};
+use crate::ledger::Ledger;
+use crate::management::{PauseFlags, PauseTarget};
+use crate::meta::get_metadata;
+use crate::stats::StatsData;
+use crate::utils;
+use ic_kit::candid::{encode_args, CandidType, Deserialize, Int, Nat};
+use ic_kit::macros::*;
+use ic_kit::{get_context, ic, Context, Principal};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+
+/// Transactions created more than this many nanoseconds ago are rejected with TooOld.
+const TX_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// The allowed clock drift between the caller and the canister in nanoseconds.
+const PERMITTED_DRIFT: u64 = 2 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Deserialize)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<ByteBuf>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Debug, Deserialize, Eq, PartialEq)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+/// Errors returned when the created_at_time of a request is outside of the accepted window.
+pub enum TimeError {
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+}
+
+impl From<TimeError> for TransferError {
+    fn from(e: TimeError) -> Self {
+        match e {
+            TimeError::TooOld => TransferError::TooOld,
+            TimeError::CreatedInFuture { ledger_time } => {
+                TransferError::CreatedInFuture { ledger_time }
+            }
+        }
+    }
+}
+
+/// Return the time after which a request created at the given time is rejected with TooOld.
+#[inline]
+fn expires_at(created_at_time: u64) -> u64 {
+    created_at_time
+        .saturating_add(TX_WINDOW)
+        .saturating_add(PERMITTED_DRIFT)
+}
+
+/// Check that a request created at the given time can still be accepted.
+pub fn check_created_at_time(created_at_time: Option<u64>, now: u64) -> Result<(), TimeError> {
+    if let Some(created_at_time) = created_at_time {
+        if expires_at(created_at_time) < now {
+            return Err(TimeError::TooOld);
+        }
+        if created_at_time > now.saturating_add(PERMITTED_DRIFT) {
+            return Err(TimeError::CreatedInFuture { ledger_time: now });
+        }
+    }
+
+    Ok(())
+}
+
+/// A transaction in `RecentTransactions` as it is persisted in the stable storage.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct RecentTransaction {
+    pub hash: ByteBuf,
+    pub created_at_time: u64,
+    pub id: TransactionId,
+}
+
+/// The transactions that were requested with a created_at_time and are not too old yet, keyed
+/// on the hash of the caller and the arguments, so a retried request is rejected as Duplicate
+/// instead of being applied twice.
+#[derive(Default)]
+pub struct RecentTransactions {
+    /// The created_at_time and the id of the transactions.
+    transactions: HashMap<[u8; 32], (u64, TransactionId)>,
+    /// The transactions ordered by the time they expire at.
+    expirations: BTreeSet<(u64, [u8; 32])>,
+}
+
+impl RecentTransactions {
+    pub fn load(archive: Vec<RecentTransaction>) {
+        let mut recent = RecentTransactions::default();
+        for transaction in archive {
+            let mut hash = [0; 32];
+            hash.copy_from_slice(&transaction.hash);
+            recent.insert(hash, transaction.created_at_time, transaction.id);
+        }
+        *ic::get_mut::<RecentTransactions>() = recent;
+    }
+
+    pub fn archive() -> Vec<RecentTransaction> {
+        ic::get::<RecentTransactions>()
+            .transactions
+            .iter()
+            .map(|(hash, (created_at_time, id))| RecentTransaction {
+                hash: ByteBuf::from(hash.to_vec()),
+                created_at_time: *created_at_time,
+                id: *id,
+            })
+            .collect()
+    }
+
+    /// Return the hash a request of the caller with the given arguments is deduplicated on.
+    pub fn hash<T: CandidType>(caller: &Principal, args: &T) -> [u8; 32] {
+        let bytes = encode_args((caller, args)).expect("Failed to encode the arguments.");
+        Sha256::digest(&bytes).into()
+    }
+
+    /// Drop the transactions that are too old, and return the id of the transaction with the
+    /// given hash if there is one.
+    pub fn find(&mut self, hash: &[u8; 32], now: u64) -> Option<TransactionId> {
+        while let Some(&(expires_at, expired)) = self.expirations.iter().next() {
+            if expires_at >= now {
+                break;
+            }
+            self.expirations.remove(&(expires_at, expired));
+            self.transactions.remove(&expired);
+        }
+
+        self.transactions.get(hash).map(|(_, id)| *id)
+    }
+
+    pub fn insert(&mut self, hash: [u8; 32], created_at_time: u64, id: TransactionId) {
+        self.transactions.insert(hash, (created_at_time, id));
+        self.expirations.insert((expires_at(created_at_time), hash));
+    }
+}
+
+#[derive(CandidType, Debug, Deserialize, Eq, PartialEq)]
+pub enum Value {
+    Nat(Nat),
+    Int(Int),
+    Text(String),
+    Blob(ByteBuf),
+}
+
+#[derive(CandidType, Debug, Deserialize, Eq, PartialEq)]
+pub struct StandardRecord {
+    pub name: String,
+    pub url: String,
+}
+
+#[query]
+fn icrc1_name() -> &'static str {
+    get_metadata().name
+}
+
+#[query]
+fn icrc1_symbol() -> &'static str {
+    get_metadata().symbol
+}
+
+#[query]
+fn icrc1_decimals() -> u8 {
+    get_metadata().decimals
+}
+
+/// The fee of the transfers, it does not depend on the amount as the schedule only accepts a
+/// flat rule for the operations of the ICRC standards.
+#[query]
+fn icrc1_fee() -> Nat {
+    Nat::from(compute_fee(FeeOperation::Transfer, 0))
+}
+
+#[query]
+fn icrc1_total_supply() -> Nat {
+    StatsData::get().supply
+}
+
+/// XTC is minted by depositing cycles, so there is no minting account.
+#[query]
+fn icrc1_minting_account() -> Option<Account> {
+    None
+}
+
+#[query]
+fn icrc1_metadata() -> Vec<(String, Value)> {
+    let metadata = get_metadata();
+    vec![
+        ("icrc1:name".into(), Value::Text(metadata.name.into())),
+        ("icrc1:symbol".into(), Value::Text(metadata.symbol.into())),
+        (
+            "icrc1:decimals".into(),
+            Value::Nat(Nat::from(metadata.decimals)),
+        ),
+        ("icrc1:fee".into(), Value::Nat(metadata.fee)),
+        ("icrc1:logo".into(), Value::Text(metadata.logo.into())),
+    ]
+}
+
+#[query]
+fn icrc1_supported_standards() -> Vec<StandardRecord> {
+    vec![
+        StandardRecord {
+            name: "ICRC-1".into(),
+            url: "https://github.com/dfinity/ICRC-1".into(),
+        },
+        StandardRecord {
+            name: "ICRC-2".into(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-2".into(),
+        },
+    ]
+}
+
+#[query]
+fn icrc1_balance_of(account: Account) -> Nat {
+    let ledger = get_context().get::<Ledger>();
+    Nat::from(ledger.balance(&Account::new(account.owner, account.subaccount)))
+}
+
+#[update]
+pub async fn icrc1_transfer(args: TransferArg) -> Result<Nat, TransferError> {
+    PauseFlags::guard(PauseTarget::Transfers);
+
+    let ic = get_context();
+    let caller = ic.caller();
+    let from = Account::new(caller, args.from_subaccount);
+    let to = Account::new(args.to.owner, args.to.subaccount);
+
+    crate::progress().await;
+
+    let now = ic.time();
+    check_created_at_time(args.created_at_time, now)?;
+
+    // Only the requests with a created_at_time are deduplicated.
+    let dedup = args
+        .created_at_time
+        .map(|created_at_time| (RecentTransactions::hash(&caller, &args), created_at_time));
+    if let Some((hash, _)) = &dedup {
+        if let Some(id) = ic.get_mut::<RecentTransactions>().find(hash, now) {
+            return Err(TransferError::Duplicate {
+                duplicate_of: Nat::from(id),
+            });
+        }
+    }
+
+    let ledger = ic.get_mut::<Ledger>();
+    let amount = match utils::convert_nat_to_u64(args.amount) {
+        Ok(amount) => amount,
+        Err(_) => {
+            return Err(TransferError::InsufficientFunds {
+                balance: Nat::from(ledger.balance(&from)),
+            })
+        }
+    };
+
+    let fee = compute_fee(FeeOperation::Transfer, amount);
+    if let Some(expected) = args.fee {
+        if expected != Nat::from(fee) {
+            return Err(TransferError::BadFee {
+                expected_fee: Nat::from(fee),
+            });
+        }
+    }
+
+    if amount == 0 || to == from {
+        return Err(TransferError::GenericError {
+            error_code: Nat::from(1),
+            message: "Transfer amount must be non-zero and the receiver must not be the sender."
+                .into(),
+        });
+    }
+
+    ledger
+        .transfer(&from, &to, amount, fee)
+        .map_err(|_| TransferError::InsufficientFunds {
+            balance: Nat::from(ledger.balance(&from)),
+        })?;
+
+    let transaction = Transaction {
+        timestamp: now,
+        cycles: amount,
+        fee,
+        kind: TransactionKind::Transfer {
+            from: caller,
+            to: to.owner,
+            from_subaccount: from.subaccount,
+            to_subaccount: to.subaccount,
+        },
+        status: TransactionStatus::SUCCEEDED,
+    };
+
+    let id = ic.get_mut::<HistoryBuffer>().push(transaction);
+    FeeCollector::collect(fee);
+    if let Some((hash, created_at_time)) = dedup {
+        ic.get_mut::<RecentTransactions>()
+            .insert(hash, created_at_time, id);
+    }
+    Ok(Nat::from(id))
+}
@@ -0,0 +1,293 @@
+//! Implementation of the ICRC-2 approve and transfer from extension on top of the XTC ledger.
+//! https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-2
+
+use crate::common_types::{Account, Subaccount};
+use crate::fee::{compute_fee, FeeCollector, FeeOperation};
+use crate::history::{HistoryBuffer, Transaction, TransactionKind, TransactionStatus};
+use crate::icrc1::{check_created_at_time, RecentTransactions, TimeError};
+use crate::ledger::Ledger;
+use crate::management::{PauseFlags, PauseTarget};
+use crate::utils;
+use ic_kit::candid::{CandidType, Deserialize, Nat};
+use ic_kit::macros::*;
+use ic_kit::{get_context, Context};
+use serde_bytes::ByteBuf;
+
+#[derive(CandidType, Deserialize)]
+pub struct ApproveArgs {
+    pub from_subaccount: Option<Subaccount>,
+    pub spender: Account,
+    pub amount: Nat,
+    pub expected_allowance: Option<Nat>,
+    pub expires_at: Option<u64>,
+    pub fee: Option<Nat>,
+    pub memo: Option<ByteBuf>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Debug, Deserialize, Eq, PartialEq)]
+pub enum ApproveError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    AllowanceChanged { current_allowance: Nat },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+impl From<TimeError> for ApproveError {
+    fn from(e: TimeError) -> Self {
+        match e {
+            TimeError::TooOld => ApproveError::TooOld,
+            TimeError::CreatedInFuture { ledger_time } => {
+                ApproveError::CreatedInFuture { ledger_time }
+            }
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<ByteBuf>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Debug, Deserialize, Eq, PartialEq)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+impl From<TimeError> for TransferFromError {
+    fn from(e: TimeError) -> Self {
+        match e {
+            TimeError::TooOld => TransferFromError::TooOld,
+            TimeError::CreatedInFuture { ledger_time } => {
+                TransferFromError::CreatedInFuture { ledger_time }
+            }
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AllowanceArgs {
+    pub account: Account,
+    pub spender: Account,
+}
+
+#[derive(CandidType, Debug, Deserialize, Eq, PartialEq)]
+pub struct Allowance {
+    pub allowance: Nat,
+    pub expires_at: Option<u64>,
+}
+
+#[query]
+fn icrc2_allowance(args: AllowanceArgs) -> Allowance {
+    let ledger = get_context().get::<Ledger>();
+    let account = Account::new(args.account.owner, args.account.subaccount);
+    let spender = Account::new(args.spender.owner, args.spender.subaccount);
+
+    Allowance {
+        allowance: Nat::from(ledger.allowance(&account, &spender)),
+        expires_at: ledger.allowance_expiration(&account, &spender),
+    }
+}
+
+/// Unlike the DIP20 approve, the approved amount is stored as is and the fees of the future
+/// transfers are deducted from the allowance.
+#[update]
+pub async fn icrc2_approve(args: ApproveArgs) -> Result<Nat, ApproveError> {
+    PauseFlags::guard(PauseTarget::Approvals);
+
+    let ic = get_context();
+    let caller = ic.caller();
+    let from = Account::new(caller, args.from_subaccount);
+    let spender = Account::new(args.spender.owner, args.spender.subaccount);
+
+    crate::progress().await;
+
+    let now = ic.time();
+    check_created_at_time(args.created_at_time, now)?;
+
+    let dedup = args
+        .created_at_time
+        .map(|created_at_time| (RecentTransactions::hash(&caller, &args), created_at_time));
+    if let Some((hash, _)) = &dedup {
+        if let Some(id) = ic.get_mut::<RecentTransactions>().find(hash, now) {
+            return Err(ApproveError::Duplicate {
+                duplicate_of: Nat::from(id),
+            });
+        }
+    }
+
+    if from == spender {
+        return Err(ApproveError::GenericError {
+            error_code: Nat::from(1),
+            message: "The spender must not be the approving account.".into(),
+        });
+    }
+
+    if let Some(expires_at) = args.expires_at {
+        if expires_at <= now {
+            return Err(ApproveError::Expired { ledger_time: now });
+        }
+    }
+
+    let ledger = ic.get_mut::<Ledger>();
+    ledger.prune_expired_allowances(now);
+
+    // Allowances larger than what a balance can hold are capped.
+    let amount = utils::convert_nat_to_u64(args.amount).unwrap_or(u64::MAX);
+    let fee = compute_fee(FeeOperation::Approve, amount);
+    if let Some(expected) = args.fee {
+        if expected != Nat::from(fee) {
+            return Err(ApproveError::BadFee {
+                expected_fee: Nat::from(fee),
+            });
+        }
+    }
+
+    if let Some(expected) = args.expected_allowance {
+        let current_allowance = Nat::from(ledger.allowance(&from, &spender));
+        if expected != current_allowance {
+            return Err(ApproveError::AllowanceChanged { current_allowance });
+        }
+    }
+
+    ledger
+        .withdraw_erc20(&from, 0, fee)
+        .map_err(|_| ApproveError::InsufficientFunds {
+            balance: Nat::from(ledger.balance(&from)),
+        })?;
+    ledger.set_allowance(&from, &spender, amount, args.expires_at);
+
+    let transaction = Transaction {
+        timestamp: now,
+        cycles: amount,
+        fee,
+        kind: TransactionKind::Approve {
+            from: caller,
+            to: spender.owner,
+            from_subaccount: from.subaccount,
+            to_subaccount: spender.subaccount,
+        },
+        status: TransactionStatus::SUCCEEDED,
+    };
+
+    let id = ic.get_mut::<HistoryBuffer>().push(transaction);
+    FeeCollector::collect(fee);
+    if let Some((hash, created_at_time)) = dedup {
+        ic.get_mut::<RecentTransactions>()
+            .insert(hash, created_at_time, id);
+    }
+    Ok(Nat::from(id))
+}
+
+#[update]
+pub async fn icrc2_transfer_from(args: TransferFromArgs) -> Result<Nat, TransferFromError> {
+    PauseFlags::guard(PauseTarget::Transfers);
+
+    let ic = get_context();
+    let caller = ic.caller();
+    let spender = Account::new(caller, args.spender_subaccount);
+    let from = Account::new(args.from.owner, args.from.subaccount);
+    let to = Account::new(args.to.owner, args.to.subaccount);
+
+    crate::progress().await;
+
+    let now = ic.time();
+    check_created_at_time(args.created_at_time, now)?;
+
+    let dedup = args
+        .created_at_time
+        .map(|created_at_time| (RecentTransactions::hash(&caller, &args), created_at_time));
+    if let Some((hash, _)) = &dedup {
+        if let Some(id) = ic.get_mut::<RecentTransactions>().find(hash, now) {
+            return Err(TransferFromError::Duplicate {
+                duplicate_of: Nat::from(id),
+            });
+        }
+    }
+
+    let ledger = ic.get_mut::<Ledger>();
+    ledger.prune_expired_allowances(now);
+
+    let amount = match utils::convert_nat_to_u64(args.amount) {
+        Ok(amount) => amount,
+        Err(_) => {
+            return Err(TransferFromError::InsufficientFunds {
+                balance: Nat::from(ledger.balance(&from)),
+            })
+        }
+    };
+
+    let fee = compute_fee(FeeOperation::TransferFrom, amount);
+    if let Some(expected) = args.fee {
+        if expected != Nat::from(fee) {
+            return Err(TransferFromError::BadFee {
+                expected_fee: Nat::from(fee),
+            });
+        }
+    }
+
+    if amount == 0 || from == to {
+        return Err(TransferFromError::GenericError {
+            error_code: Nat::from(1),
+            message: "Transfer amount must be non-zero and the receiver must not be the sender."
+                .into(),
+        });
+    }
+
+    let allowance = ledger.allowance(&from, &spender);
+    match amount.checked_add(fee) {
+        Some(total) if total <= allowance => (),
+        _ => {
+            return Err(TransferFromError::InsufficientAllowance {
+                allowance: Nat::from(allowance),
+            })
+        }
+    }
+
+    ledger
+        .transfer_from(&spender, &from, &to, amount, fee)
+        .map_err(|_| TransferFromError::InsufficientFunds {
+            balance: Nat::from(ledger.balance(&from)),
+        })?;
+
+    let transaction = Transaction {
+        timestamp: now,
+        cycles: amount,
+        fee,
+        kind: TransactionKind::TransferFrom {
+            caller,
+            from: from.owner,
+            to: to.owner,
+            from_subaccount: from.subaccount,
+            to_subaccount: to.subaccount,
+        },
+        status: TransactionStatus::SUCCEEDED,
+    };
+
+    let id = ic.get_mut::<HistoryBuffer>().push(transaction);
+    FeeCollector::collect(fee);
+    if let Some((hash, created_at_time)) = dedup {
+        ic.get_mut::<RecentTransactions>()
+            .insert(hash, created_at_time, id);
+    }
+    Ok(Nat::from(id))
+}
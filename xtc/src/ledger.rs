@@ -1,57 +1,151 @@
+use crate::canisters::CanisterRegistry;
 use crate::common_types::{
-    Operation, TxError, TxErrorLegacy, TxReceipt, TxReceiptLegacy, TxRecord,
+    Account, Operation, Subaccount, TxError, TxErrorLegacy, TxReceipt, TxReceiptLegacy, TxRecord,
 };
-use crate::fee::compute_fee;
+use crate::fee::{compute_fee, FeeCollector, FeeOperation};
 use crate::history::{
     HistoryBuffer, Transaction, TransactionId, TransactionKind, TransactionStatus,
 };
-use crate::management::IsShutDown;
+use crate::management::{PauseFlags, PauseTarget, Role, Roles};
+use crate::memory::{self, Region};
 use crate::stats::StatsData;
 use crate::utils;
-use cycles_minting_canister::{
-    IcpXdrConversionRateCertifiedResponse, TokensToCycles, DEFAULT_CYCLES_PER_XDR,
-};
-use dfn_core::api::call_with_cleanup;
-use dfn_protobuf::protobuf;
 use ic_kit::candid::{CandidType, Int, Nat};
 use ic_kit::macros::*;
 use ic_kit::{get_context, ic, ic::call, Context, Principal};
-use ic_types::{CanisterId, PrincipalId};
-use ledger_canister::{
-    account_identifier::{AccountIdentifier, Subaccount},
-    tokens::Tokens,
-    BlockHeight, BlockRes, CyclesResponse, Memo, NotifyCanisterArgs, Operation as Operate,
-    SendArgs,
-};
+use ledger_canister::BlockHeight;
 use serde::*;
 use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
+use xtc_stable::{StableHashMap, StableQueue, Storable};
+
+/// A single allowance as it is persisted in the stable storage.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct AllowanceEntry {
+    pub allower: Account,
+    pub spender: Account,
+    pub amount: u64,
+    pub expires_at: Option<u64>,
+}
 
-#[derive(Default)]
 pub struct Ledger {
-    // stores the cycle balance hold by the principal
-    balances: HashMap<Principal, u64>,
+    // stores the cycle balance hold by the account, lives in the stable memory
+    balances: StableHashMap<Account, u64, Region>,
+
+    // stores the allowances, approving account -> spender account -> cycle balanace
+    allowances: HashMap<(Account, Account), u64>,
 
-    // stores the allowances, approving Principal -> spender principal -> cycle balanace
-    allowances: HashMap<(Principal, Principal), u64>,
+    // stores the expiration time of the allowances that were given with an expiry
+    expirations: HashMap<(Account, Account), u64>,
+}
+
+impl Default for Ledger {
+    /// Create an empty ledger, this drops the balances stored in the stable memory.
+    fn default() -> Self {
+        Ledger {
+            balances: StableHashMap::new(memory::region(memory::LEDGER)),
+            allowances: HashMap::new(),
+            expirations: HashMap::new(),
+        }
+    }
 }
 
 impl Ledger {
-    pub fn archive(&mut self) -> Vec<(Principal, u64)> {
-        std::mem::take(&mut self.balances)
+    /// Load the ledger with the balances stored in the stable memory, the allowances live on
+    /// the heap and should be loaded separately.
+    pub fn restore() -> Self {
+        Ledger {
+            balances: StableHashMap::init(memory::region(memory::LEDGER)),
+            allowances: HashMap::new(),
+            expirations: HashMap::new(),
+        }
+    }
+
+    /// Return false while the balances are keyed with the zero seed, see `crate::seed`.
+    #[inline]
+    pub fn is_seeded(&self) -> bool {
+        self.balances.is_seeded()
+    }
+
+    /// Key the hashes of the balances with a new seed, see `StableHashMap::reseed`.
+    #[inline]
+    pub fn reseed(&mut self, seed: [u8; 16]) -> bool {
+        self.balances.reseed(seed)
+    }
+
+    pub fn load(&mut self, archive: Vec<(Account, u64)>) {
+        for (account, balance) in archive {
+            if balance > 0 {
+                self.balances.insert(account, balance);
+            }
+        }
+    }
+
+    pub fn archive_allowances(&mut self) -> Vec<AllowanceEntry> {
+        let mut expirations = std::mem::take(&mut self.expirations);
+        std::mem::take(&mut self.allowances)
             .into_iter()
-            .filter(|(_, balance)| *balance > 0)
+            .map(|((allower, spender), amount)| AllowanceEntry {
+                allower,
+                spender,
+                amount,
+                expires_at: expirations.remove(&(allower, spender)),
+            })
             .collect()
     }
 
-    pub fn load(&mut self, archive: Vec<(Principal, u64)>) {
-        self.balances = archive.into_iter().collect();
-        self.balances.reserve(25_000 - self.balances.len());
+    pub fn load_allowances(&mut self, archive: Vec<AllowanceEntry>) {
+        for entry in archive {
+            self.set_allowance(
+                &entry.allower,
+                &entry.spender,
+                entry.amount,
+                entry.expires_at,
+            );
+        }
     }
 
     #[inline]
-    fn cleanup_allowances(&mut self, allower: &Principal, spender: &Principal) {
+    fn cleanup_allowances(&mut self, allower: &Account, spender: &Account) {
         self.allowances.remove(&(*allower, *spender));
+        self.expirations.remove(&(*allower, *spender));
+    }
+
+    /// Remove all of the allowances that have expired at the given time.
+    pub fn prune_expired_allowances(&mut self, now: u64) {
+        let expired: Vec<(Account, Account)> = self
+            .expirations
+            .iter()
+            .filter(|(_, expires_at)| **expires_at <= now)
+            .map(|(key, _)| *key)
+            .collect();
+
+        for (allower, spender) in expired {
+            self.cleanup_allowances(&allower, &spender);
+        }
+    }
+
+    /// Overwrite the allowance without charging any fees, unlike approve the given amount is
+    /// stored as is. Setting a zero amount clears the allowance from the internal map.
+    #[inline]
+    pub fn set_allowance(
+        &mut self,
+        allower: &Account,
+        spender: &Account,
+        amount: u64,
+        expires_at: Option<u64>,
+    ) {
+        if amount == 0 {
+            self.cleanup_allowances(allower, spender);
+            return;
+        }
+
+        self.allowances.insert((*allower, *spender), amount);
+
+        match expires_at {
+            Some(expires_at) => self.expirations.insert((*allower, *spender), expires_at),
+            None => self.expirations.remove(&(*allower, *spender)),
+        };
     }
 
     /// 1. Allower can allow more money to Spender than Allower's internal balance
@@ -61,8 +155,8 @@ impl Ledger {
     #[inline]
     pub fn approve(
         &mut self,
-        allower: &Principal,
-        spender: &Principal,
+        allower: &Account,
+        spender: &Account,
         amount: u64,
         fee: u64,
     ) -> Result<(), TxError> {
@@ -80,15 +174,25 @@ impl Ledger {
             self.cleanup_allowances(allower, spender);
         } else {
             // the allower will pay for the future transferFrom fees, so the total allowed amount equals to amount + fee
-            *(self.allowances.entry((*allower, *spender)).or_default()) = amount + fee;
+            self.set_allowance(allower, spender, amount + fee, None);
         }
 
         Ok(())
     }
 
+    /// Return the allowance, an expired allowance is reported as zero even if it is not
+    /// pruned yet.
     #[inline]
-    pub fn allowance(&self, allower: &Principal, spender: &Principal) -> u64 {
-        *self.allowances.get(&(*allower, *spender)).unwrap_or(&0)
+    pub fn allowance(&self, allower: &Account, spender: &Account) -> u64 {
+        match self.allowance_expiration(allower, spender) {
+            Some(expires_at) if expires_at <= get_context().time() => 0,
+            _ => *self.allowances.get(&(*allower, *spender)).unwrap_or(&0),
+        }
+    }
+
+    #[inline]
+    pub fn allowance_expiration(&self, allower: &Account, spender: &Account) -> Option<u64> {
+        self.expirations.get(&(*allower, *spender)).cloned()
     }
 
     /// 1. The fee is deducted from the caller's balance as opposed to the allower balance.
@@ -103,9 +207,9 @@ impl Ledger {
     #[inline]
     pub fn transfer_from(
         &mut self,
-        caller: &Principal,
-        allower: &Principal,
-        spender: &Principal,
+        caller: &Account,
+        allower: &Account,
+        spender: &Account,
         amount: u64,
         fee: u64,
     ) -> Result<(), TxError> {
@@ -121,7 +225,8 @@ impl Ledger {
             return Err(TxError::InsufficientBalance);
         }
 
-        self.approve(allower, caller, allowance - total_amount, 0);
+        let expires_at = self.allowance_expiration(allower, caller);
+        self.set_allowance(allower, caller, allowance - total_amount, expires_at);
         self.withdraw_erc20(&allower, 0, fee);
         self.transfer(allower, spender, amount, 0)?;
 
@@ -133,8 +238,8 @@ impl Ledger {
     #[inline]
     pub fn transfer(
         &mut self,
-        from: &Principal,
-        to: &Principal,
+        from: &Account,
+        to: &Account,
         amount: u64,
         fee: u64,
     ) -> Result<(), TxError> {
@@ -146,36 +251,36 @@ impl Ledger {
     }
 
     #[inline]
-    pub fn balance(&self, account: &Principal) -> u64 {
-        *(self.balances.get(account).unwrap_or(&0))
+    pub fn balance(&self, account: &Account) -> u64 {
+        self.balances.get(account).unwrap_or(0)
     }
 
     #[inline]
-    pub fn deposit(&mut self, account: &Principal, amount: u64) {
+    pub fn deposit(&mut self, account: &Account, amount: u64) {
         StatsData::deposit(amount);
-        *(self.balances.entry(*account).or_default()) += amount;
+        let balance = self.balance(account);
+        self.balances.insert(*account, balance + amount);
     }
 
     #[inline]
     pub fn withdraw_erc20(
         &mut self,
-        account: &Principal,
+        account: &Account,
         amount: u64,
         fee: u64,
     ) -> Result<(), TxError> {
         let total_amount = fee + amount;
 
-        let balance = match self.balances.get_mut(&account) {
-            Some(balance) if *balance >= total_amount => {
-                *balance -= total_amount;
-                *balance
-            }
+        let balance = match self.balances.get(account) {
+            Some(balance) if balance >= total_amount => balance - total_amount,
             _ if total_amount == 0 => return Ok(()),
             _ => return Err(TxError::InsufficientBalance),
         };
 
         if balance == 0 {
-            self.balances.remove(&account);
+            self.balances.remove(account);
+        } else {
+            self.balances.insert(*account, balance);
         }
 
         StatsData::withdraw(total_amount);
@@ -184,17 +289,16 @@ impl Ledger {
     }
 
     #[inline]
-    pub fn withdraw(&mut self, account: &Principal, amount: u64) -> Result<(), ()> {
-        let balance = match self.balances.get_mut(&account) {
-            Some(balance) if *balance >= amount => {
-                *balance -= amount;
-                *balance
-            }
+    pub fn withdraw(&mut self, account: &Account, amount: u64) -> Result<(), ()> {
+        let balance = match self.balances.get(account) {
+            Some(balance) if balance >= amount => balance - amount,
             _ => return Err(()),
         };
 
         if balance == 0 {
-            self.balances.remove(&account);
+            self.balances.remove(account);
+        } else {
+            self.balances.insert(*account, balance);
         }
 
         StatsData::withdraw(amount);
@@ -206,30 +310,39 @@ impl Ledger {
 //////////////////// BEGIN OF ERC-20 ///////////////////////
 
 #[query(name=balanceOf)]
-pub async fn balance_of(account: Principal) -> Nat {
+pub async fn balance_of(account: Principal, subaccount: Option<Subaccount>) -> Nat {
     let ledger = ic_kit::get_context().get::<Ledger>();
-    Nat::from(ledger.balance(&account))
+    Nat::from(ledger.balance(&Account::new(account, subaccount)))
 }
 
+/// The allowance `from` gave to `to`. The DIP20 methods only know the default subaccount of the
+/// spender, use `icrc2_allowance` for the allowances of the other subaccounts.
 #[query]
-pub async fn allowance(from: Principal, to: Principal) -> Nat {
-    return get_context().get::<Ledger>().allowance(&from, &to).into();
+pub async fn allowance(from: Principal, to: Principal, from_subaccount: Option<Subaccount>) -> Nat {
+    let from = Account::new(from, from_subaccount);
+    return get_context()
+        .get::<Ledger>()
+        .allowance(&from, &to.into())
+        .into();
 }
 
+/// Let `to` spend `amount` from the given subaccount of the caller. The allowance is always given
+/// to the default subaccount of the spender, use `icrc2_approve` to approve another subaccount.
 #[update]
-pub async fn approve(to: Principal, amount: Nat) -> TxReceipt {
-    IsShutDown::guard();
+pub async fn approve(to: Principal, amount: Nat, from_subaccount: Option<Subaccount>) -> TxReceipt {
+    PauseFlags::guard(PauseTarget::Approvals);
     use ic_cdk::export::candid;
     let caller = ic_kit::ic::caller();
+    let from = Account::new(caller, from_subaccount);
 
     crate::progress().await;
 
     let ledger = ic_kit::ic::get_mut::<Ledger>();
     let amount_u64: u64 =
         utils::convert_nat_to_u64(amount).expect("Amount cannot be represented as u64");
-    let fee = compute_fee(amount_u64);
+    let fee = compute_fee(FeeOperation::Approve, amount_u64);
 
-    ledger.approve(&caller, &to, amount_u64, fee)?;
+    ledger.approve(&from, &to.into(), amount_u64, fee)?;
 
     let transaction = Transaction {
         timestamp: ic_kit::ic::time(),
@@ -238,36 +351,47 @@ pub async fn approve(to: Principal, amount: Nat) -> TxReceipt {
         kind: TransactionKind::Approve {
             from: caller,
             to: to,
+            from_subaccount: from.subaccount,
+            to_subaccount: None,
         },
         status: TransactionStatus::SUCCEEDED,
     };
 
-    Ok(Nat::from(
-        ic_kit::ic::get_mut::<HistoryBuffer>().push(transaction.clone()),
-    ))
+    let id = ic_kit::ic::get_mut::<HistoryBuffer>().push(transaction);
+    FeeCollector::collect(fee);
+    Ok(Nat::from(id))
 }
 
 #[update(name=transferErc20)]
 pub async fn transfer_erc20(to: Principal, amount: Nat) -> TxReceiptLegacy {
-    transfer(to, amount).await.map_err(|err| match err {
-        TxError::InsufficientAllowance => TxErrorLegacy::InsufficientAllowance,
-        _ => TxErrorLegacy::InsufficientBalance,
-    })
+    transfer(to, amount, None, None)
+        .await
+        .map_err(|err| match err {
+            TxError::InsufficientAllowance => TxErrorLegacy::InsufficientAllowance,
+            _ => TxErrorLegacy::InsufficientBalance,
+        })
 }
 
 #[update]
-pub async fn transfer(to: Principal, amount: Nat) -> TxReceipt {
-    IsShutDown::guard();
+pub async fn transfer(
+    to: Principal,
+    amount: Nat,
+    from_subaccount: Option<Subaccount>,
+    to_subaccount: Option<Subaccount>,
+) -> TxReceipt {
+    PauseFlags::guard(PauseTarget::Transfers);
 
     let caller = ic_kit::ic::caller();
+    let from = Account::new(caller, from_subaccount);
+    let to = Account::new(to, to_subaccount);
 
     crate::progress().await;
 
     let ledger = ic_kit::ic::get_mut::<Ledger>();
     let amount_u64: u64 =
         utils::convert_nat_to_u64(amount).expect("transfer failed - unable to convert amount");
-    let fee = compute_fee(amount_u64);
-    ledger.transfer(&caller, &to, amount_u64, fee)?;
+    let fee = compute_fee(FeeOperation::Transfer, amount_u64);
+    ledger.transfer(&from, &to, amount_u64, fee)?;
 
     let transaction = Transaction {
         timestamp: ic_kit::ic::time(),
@@ -275,29 +399,40 @@ pub async fn transfer(to: Principal, amount: Nat) -> TxReceipt {
         fee,
         kind: TransactionKind::Transfer {
             from: caller,
-            to: to,
+            to: to.owner,
+            from_subaccount: from.subaccount,
+            to_subaccount: to.subaccount,
         },
         status: TransactionStatus::SUCCEEDED,
     };
 
-    Ok(Nat::from(
-        ic_kit::ic::get_mut::<HistoryBuffer>().push(transaction.clone()),
-    ))
+    let id = ic_kit::ic::get_mut::<HistoryBuffer>().push(transaction);
+    FeeCollector::collect(fee);
+    Ok(Nat::from(id))
 }
 
+/// Transfer from an allowance that `from` gave to the default subaccount of the caller.
 #[update(name=transferFrom)]
-pub async fn transfer_from(from: Principal, to: Principal, amount: Nat) -> TxReceipt {
-    IsShutDown::guard();
+pub async fn transfer_from(
+    from: Principal,
+    to: Principal,
+    amount: Nat,
+    from_subaccount: Option<Subaccount>,
+    to_subaccount: Option<Subaccount>,
+) -> TxReceipt {
+    PauseFlags::guard(PauseTarget::Transfers);
 
     let caller = ic_kit::ic::caller();
+    let from = Account::new(from, from_subaccount);
+    let to = Account::new(to, to_subaccount);
 
     crate::progress().await;
 
     let ledger = ic_kit::ic::get_mut::<Ledger>();
     let amount_u64: u64 =
         utils::convert_nat_to_u64(amount).expect("transfer failed - unable to convert amount");
-    let fee = compute_fee(amount_u64);
-    ledger.transfer_from(&caller, &from, &to, amount_u64, fee)?;
+    let fee = compute_fee(FeeOperation::TransferFrom, amount_u64);
+    ledger.transfer_from(&caller.into(), &from, &to, amount_u64, fee)?;
 
     let transaction = Transaction {
         timestamp: ic_kit::ic::time(),
@@ -305,388 +440,353 @@ pub async fn transfer_from(from: Principal, to: Principal, amount: Nat) -> TxRec
         fee,
         kind: TransactionKind::TransferFrom {
             caller: caller,
-            from: from,
-            to: to,
+            from: from.owner,
+            to: to.owner,
+            from_subaccount: from.subaccount,
+            to_subaccount: to.subaccount,
         },
         status: TransactionStatus::SUCCEEDED,
     };
 
-    Ok(Nat::from(
-        ic_kit::ic::get_mut::<HistoryBuffer>().push(transaction.clone()),
-    ))
+    let id = ic_kit::ic::get_mut::<HistoryBuffer>().push(transaction);
+    FeeCollector::collect(fee);
+    Ok(Nat::from(id))
 }
 
-pub type UsedBlocks = HashSet<BlockHeight>;
-pub type UsedMapBlocks = HashMap<BlockHeight, BlockHeight>;
+/// The nanoseconds a used block is kept in the log after it was created, it is archived once
+/// it is pruned.
+pub const TRANSACTION_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// The most records `UsedBlocks::insert` prunes, so each mint pays for a bounded amount of
+/// pruning.
+const PRUNE_STEP: u64 = 16;
+
+/// The most records `prune_used_blocks` prunes in a single call.
+const MAX_PRUNE: u64 = 10_000;
+
+/// An ICP block that was used to mint XTC.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct UsedBlock {
+    pub block_height: BlockHeight,
+    /// The block of the ICP burned for the cycles, once it was sent.
+    pub send_block: Option<BlockHeight>,
+    /// The time the block was created at according to the ICP ledger.
+    pub created_at: u64,
+    pub used_at: u64,
+}
 
-#[query(name = "getBlockUsed")]
-fn get_block_used() -> &'static HashSet<u64> {
-    ic::get::<UsedBlocks>()
+/// A used block as it is stored in the stable memory, the position is the one of its record
+/// in the log.
+#[derive(Clone, Copy)]
+struct UsedBlockEntry {
+    send_block: Option<BlockHeight>,
+    created_at: u64,
+    used_at: u64,
+    position: u64,
 }
 
-#[query(name = "isBlockUsed")]
-fn is_block_used(block_number: BlockHeight) -> bool {
-    ic::get::<UsedBlocks>().contains(&block_number)
+impl Storable for UsedBlockEntry {
+    // A flag for the send block, the send block, the creation and use times and the position.
+    const SIZE: usize = 33;
+
+    fn write_to(&self, buf: &mut [u8]) {
+        buf[0] = self.send_block.is_some() as u8;
+        self.send_block.unwrap_or(0).write_to(&mut buf[1..9]);
+        self.created_at.write_to(&mut buf[9..17]);
+        self.used_at.write_to(&mut buf[17..25]);
+        self.position.write_to(&mut buf[25..33]);
+    }
+
+    fn read_from(buf: &[u8]) -> Self {
+        UsedBlockEntry {
+            send_block: match buf[0] {
+                0 => None,
+                _ => Some(u64::read_from(&buf[1..9])),
+            },
+            created_at: u64::read_from(&buf[9..17]),
+            used_at: u64::read_from(&buf[17..25]),
+            position: u64::read_from(&buf[25..33]),
+        }
+    }
 }
 
-#[query]
-fn get_map_block_used(block_number: BlockHeight) -> Option<&'static BlockHeight> {
-    ic::get::<UsedMapBlocks>().get(&block_number)
+/// The record of a block in the log of the used blocks, in the order they were used.
+#[derive(Clone, Copy)]
+struct UsedBlockRecord {
+    block_height: BlockHeight,
+    created_at: u64,
 }
 
-const ICPFEE: Tokens = Tokens::from_e8s(10000);
-const MEMO_TOP_UP_CANISTER: u64 = 1347768404_u64;
-const LEDGER_CANISTER_ID: CanisterId = CanisterId::from_u64(2);
-const MAX_RETRY: u8 = 5;
+impl Storable for UsedBlockRecord {
+    const SIZE: usize = 16;
 
-async fn get_block_info(
-    block_height: BlockHeight,
-) -> Result<(AccountIdentifier, AccountIdentifier, Tokens), TxError> {
-    let BlockRes(block_response) =
-        call_with_cleanup(LEDGER_CANISTER_ID, "block_pb", protobuf, block_height)
-            .await
-            .map_err(|_| TxError::Other)?;
-
-    let block = match block_response.ok_or(TxError::Other)? {
-        Ok(encode_block) => encode_block,
-        Err(e) => {
-            let storage = Principal::from_text(e.to_string()).map_err(|_| TxError::Other)?;
-            let storage_canister =
-                CanisterId::new(PrincipalId::from(storage)).map_err(|_| TxError::Other)?;
-            let BlockRes(block_response) =
-                call_with_cleanup(storage_canister, "get_block_pb", protobuf, block_height)
-                    .await
-                    .map_err(|_| TxError::Other)?;
-            block_response
-                .ok_or(TxError::Other)?
-                .map_err(|_| TxError::Other)?
+    fn write_to(&self, buf: &mut [u8]) {
+        self.block_height.write_to(&mut buf[0..8]);
+        self.created_at.write_to(&mut buf[8..16]);
+    }
+
+    fn read_from(buf: &[u8]) -> Self {
+        UsedBlockRecord {
+            block_height: u64::read_from(&buf[0..8]),
+            created_at: u64::read_from(&buf[8..16]),
         }
     }
-    .decode()
-    .map_err(|_| TxError::Other)?;
-
-    match block.transaction.operation {
-        Operate::Transfer {
-            from,
-            to,
-            amount,
-            fee: _,
-        } => Ok((from, to, amount)),
-        _ => {
-            return Err(TxError::ErrorOperationStyle);
+}
+
+/// The ICP blocks that were already used to mint XTC, they live in the stable memory.
+///
+/// Every used block is also recorded in a log in the order the blocks were used, the records
+/// at the front of the log are pruned once their block was created before the transaction
+/// window. A pruned block is moved to the archive, which only keeps one bit per block height
+/// and the send block, so the late claims of a block are still rejected. The blocks used by V0
+/// start in the archive.
+pub struct UsedBlocks {
+    blocks: StableHashMap<BlockHeight, UsedBlockEntry, Region>,
+    log: StableQueue<UsedBlockRecord, Region>,
+    /// The archived blocks, by words of 64 heights.
+    archive: StableHashMap<u64, u64, Region>,
+    archived_send_blocks: StableHashMap<BlockHeight, BlockHeight, Region>,
+}
+
+impl Default for UsedBlocks {
+    /// Create an empty set, this drops the blocks stored in the stable memory.
+    fn default() -> Self {
+        UsedBlocks {
+            blocks: StableHashMap::new(memory::region(memory::USED_BLOCK_ENTRIES)),
+            log: StableQueue::new(memory::region(memory::USED_BLOCK_LOG), 1024),
+            archive: StableHashMap::new(memory::region(memory::USED_BLOCK_ARCHIVE)),
+            archived_send_blocks: StableHashMap::new(memory::region(memory::ARCHIVED_SEND_BLOCKS)),
         }
     }
 }
 
-#[update]
-pub async fn mint_by_icp(sub_account: Option<Subaccount>, block_height: BlockHeight) -> TxReceipt {
-    IsShutDown::guard();
+impl UsedBlocks {
+    /// Load the blocks stored in the stable memory.
+    pub fn restore() -> Self {
+        UsedBlocks {
+            blocks: StableHashMap::init(memory::region(memory::USED_BLOCK_ENTRIES)),
+            log: StableQueue::init(memory::region(memory::USED_BLOCK_LOG), 1024),
+            archive: StableHashMap::init(memory::region(memory::USED_BLOCK_ARCHIVE)),
+            archived_send_blocks: StableHashMap::init(memory::region(memory::ARCHIVED_SEND_BLOCKS)),
+        }
+    }
 
-    let caller = ic::caller();
+    /// Archive the blocks used by V0 along with the blocks their ICP was sent in.
+    pub fn load_v0(
+        &mut self,
+        used_blocks: HashSet<BlockHeight>,
+        used_map_blocks: HashMap<BlockHeight, BlockHeight>,
+    ) {
+        for block_height in used_blocks {
+            self.archive(block_height, None);
+        }
+        for (block_height, send_block) in used_map_blocks {
+            self.archived_send_blocks.insert(block_height, send_block);
+        }
+    }
 
-    crate::progress().await;
+    /// Return false while one of the maps is keyed with the zero seed, see `crate::seed`.
+    pub fn is_seeded(&self) -> bool {
+        self.blocks.is_seeded() && self.archive.is_seeded() && self.archived_send_blocks.is_seeded()
+    }
 
-    let (from, to, amount) = get_block_info(block_height).await?;
-
-    let used_blocks = ic::get_mut::<UsedBlocks>();
-
-    // guard
-    if !used_blocks.insert(block_height) {
-        return Err(TxError::BlockUsed);
-    }
-
-    let caller_account = AccountIdentifier::new(ic_types::PrincipalId::from(caller), sub_account);
-    let xtc_account = AccountIdentifier::new(ic_types::PrincipalId::from(ic::id()), None);
-
-    if caller_account != from {
-        used_blocks.remove(&block_height);
-        return Err(TxError::Unauthorized);
-    }
-
-    if xtc_account != to {
-        used_blocks.remove(&block_height);
-        return Err(TxError::ErrorTo);
-    }
-
-    // ====================================================
-    // 2 times fee because of "send_dfx" and "notify_dfx"
-    let amount = (amount - ICPFEE).map_err(|_| {
-        used_blocks.remove(&block_height);
-        TxError::AmountTooSmall
-    })?;
-    let amount = (amount - ICPFEE).map_err(|_| {
-        used_blocks.remove(&block_height);
-        TxError::AmountTooSmall
-    })?;
-    // ====================================================
-
-    let cycles_minting_canister = Principal::from_text("rkp4c-7iaaa-aaaaa-aaaca-cai").unwrap();
-
-    // ====================================================
-    // check xtc fee
-    let rate = call::<_, (IcpXdrConversionRateCertifiedResponse,), _>(
-        cycles_minting_canister,
-        "get_icp_xdr_conversion_rate",
-        (),
-    )
-    .await
-    .map_err(|_| {
-        used_blocks.remove(&block_height);
-        TxError::FetchRateFailed
-    })?
-    .0;
-
-    let cycles: u64 = (TokensToCycles {
-        xdr_permyriad_per_icp: rate.data.xdr_permyriad_per_icp,
-        cycles_per_xdr: DEFAULT_CYCLES_PER_XDR.into(),
-    })
-    .to_cycles(amount)
-    .into();
-
-    let fee = compute_fee(0);
-    if cycles <= fee {
-        used_blocks.remove(&block_height);
-        return Err(TxError::InsufficientXTCFee);
-    }
-
-    // actual user cycles
-    let cycles = cycles - fee;
-    // ====================================================
-
-    // ====================================================
-    // Burn
-    let new_block_height = call::<_, (u64,), _>(
-        Principal::from_slice(LEDGER_CANISTER_ID.as_ref()),
-        "send_dfx",
-        (SendArgs {
-            memo: Memo(MEMO_TOP_UP_CANISTER),
-            amount,
-            fee: ICPFEE,
-            from_subaccount: None,
-            to: AccountIdentifier::new(
-                ic_types::PrincipalId::from(cycles_minting_canister),
-                Some(Subaccount::from(&ic_types::PrincipalId::from(ic::id()))),
-            ),
-            created_at_time: None,
-        },),
-    )
-    .await
-    .map_err(|_| {
-        used_blocks.remove(&block_height);
-        TxError::LedgerTrap
-    })?
-    .0;
-    // ====================================================
-
-    // track `user transferred block` that map to `canister burned block`
-    ic::get_mut::<UsedMapBlocks>().insert(block_height, new_block_height);
-
-    // ====================================================
-    // Notify - Retry until successful
-    // https://github.com/dfinity/sdk/pull/1973
-    let mut result: Option<CyclesResponse> = None;
-    for _ in (0..MAX_RETRY) {
-        match call::<_, (CyclesResponse,), _>(
-            Principal::from_slice(LEDGER_CANISTER_ID.as_ref()),
-            "notify_dfx",
-            (NotifyCanisterArgs {
-                block_height: new_block_height,
-                max_fee: ICPFEE,
-                from_subaccount: None,
-                to_canister: ic_types::CanisterId::new(ic_types::PrincipalId::from(
-                    cycles_minting_canister,
-                ))
-                .unwrap(),
-                to_subaccount: Some(Subaccount::from(&ic_types::PrincipalId::from(ic::id()))),
-            },),
-        )
-        .await
-        {
-            Ok(cycles_response) => {
-                result = Some(cycles_response.0);
-                break;
-            }
-            Err(_) => continue,
+    /// Key the hashes of the maps that do not have a seed yet with the given seed, see
+    /// `StableHashMap::reseed`.
+    pub fn reseed(&mut self, seed: [u8; 16]) {
+        if !self.blocks.is_seeded() {
+            self.blocks.reseed(seed);
+        }
+        if !self.archive.is_seeded() {
+            self.archive.reseed(seed);
+        }
+        if !self.archived_send_blocks.is_seeded() {
+            self.archived_send_blocks.reseed(seed);
         }
     }
-    let result = result.ok_or(TxError::NotifyDfxFailed)?;
-    // ====================================================
-
-    // ====================================================
-    // Credit XTC
-    match result {
-        CyclesResponse::ToppedUp(()) => {
-            ic::get_mut::<Ledger>().deposit(&caller, cycles);
-            Ok(Nat::from(ic::get_mut::<HistoryBuffer>().push(
-                Transaction {
-                    timestamp: ic::time(),
-                    cycles,
-                    fee,
-                    kind: TransactionKind::Mint { to: caller },
-                    status: TransactionStatus::SUCCEEDED,
-                },
-            )))
+
+    /// Mark the block created at the given time as used, returns false if it was already used.
+    /// A few of the blocks that are out of the transaction window are pruned first.
+    pub fn insert(&mut self, block_height: BlockHeight, created_at: u64) -> bool {
+        let now = ic::time();
+        self.prune(now, PRUNE_STEP);
+
+        if self.contains(&block_height) {
+            return false;
         }
-        _ => Err(TxError::UnexpectedCyclesResponse),
+
+        let position = self.log.head() + self.log.len();
+        self.log.push(&UsedBlockRecord {
+            block_height,
+            created_at,
+        });
+        self.blocks.insert(
+            block_height,
+            UsedBlockEntry {
+                send_block: None,
+                created_at,
+                used_at: now,
+                position,
+            },
+        );
+        true
     }
-    // ====================================================
-}
 
-#[update]
-pub async fn mint_by_icp_recover(
-    sub_account: Option<Subaccount>,
-    block_height: BlockHeight,
-    user_principal: Principal,
-) -> TxReceipt {
-    IsShutDown::guard();
+    /// Release a block that was not spent, the archived blocks can not be released.
+    #[inline]
+    pub fn remove(&mut self, block_height: &BlockHeight) -> bool {
+        self.blocks.remove(block_height).is_some()
+    }
 
-    crate::progress().await;
+    pub fn contains(&self, block_height: &BlockHeight) -> bool {
+        self.blocks.contains_key(block_height) || self.is_archived(*block_height)
+    }
 
-    let (from, to, amount) = get_block_info(block_height).await?;
-
-    let used_blocks = ic::get_mut::<UsedBlocks>();
-
-    // guard
-    if !used_blocks.insert(block_height) {
-        return Err(TxError::BlockUsed);
-    }
-
-    let from_account =
-        AccountIdentifier::new(ic_types::PrincipalId::from(user_principal), sub_account);
-    let xtc_account = AccountIdentifier::new(ic_types::PrincipalId::from(ic::id()), None);
-
-    if from_account != from {
-        used_blocks.remove(&block_height);
-        return Err(TxError::Unauthorized);
-    }
-
-    if xtc_account != to {
-        used_blocks.remove(&block_height);
-        return Err(TxError::ErrorTo);
-    }
-
-    // ====================================================
-    // 2 times fee because of "send_dfx" and "notify_dfx"
-    let amount = (amount - ICPFEE).map_err(|_| {
-        used_blocks.remove(&block_height);
-        TxError::AmountTooSmall
-    })?;
-    let amount = (amount - ICPFEE).map_err(|_| {
-        used_blocks.remove(&block_height);
-        TxError::AmountTooSmall
-    })?;
-    // ====================================================
-
-    let cycles_minting_canister = Principal::from_text("rkp4c-7iaaa-aaaaa-aaaca-cai").unwrap();
-
-    // ====================================================
-    // check xtc fee
-    let rate = call::<_, (IcpXdrConversionRateCertifiedResponse,), _>(
-        cycles_minting_canister,
-        "get_icp_xdr_conversion_rate",
-        (),
-    )
-    .await
-    .map_err(|_| {
-        used_blocks.remove(&block_height);
-        TxError::FetchRateFailed
-    })?
-    .0;
-
-    let cycles: u64 = (TokensToCycles {
-        xdr_permyriad_per_icp: rate.data.xdr_permyriad_per_icp,
-        cycles_per_xdr: DEFAULT_CYCLES_PER_XDR.into(),
-    })
-    .to_cycles(amount)
-    .into();
-
-    let fee = compute_fee(0);
-    if cycles <= fee {
-        used_blocks.remove(&block_height);
-        return Err(TxError::InsufficientXTCFee);
-    }
-
-    // actual user cycles
-    let cycles = cycles - fee;
-    // ====================================================
-
-    // ====================================================
-    // Burn
-    let new_block_height = call::<_, (u64,), _>(
-        Principal::from_slice(LEDGER_CANISTER_ID.as_ref()),
-        "send_dfx",
-        (SendArgs {
-            memo: Memo(MEMO_TOP_UP_CANISTER),
-            amount,
-            fee: ICPFEE,
-            from_subaccount: None,
-            to: AccountIdentifier::new(
-                ic_types::PrincipalId::from(cycles_minting_canister),
-                Some(Subaccount::from(&ic_types::PrincipalId::from(ic::id()))),
-            ),
-            created_at_time: None,
-        },),
-    )
-    .await
-    .map_err(|_| {
-        used_blocks.remove(&block_height);
-        TxError::LedgerTrap
-    })?
-    .0;
-    // ====================================================
-
-    // track `user transferred block` that map to `canister burned block`
-    ic::get_mut::<UsedMapBlocks>().insert(block_height, new_block_height);
-
-    // ====================================================
-    // Notify - Retry until successful
-    // https://github.com/dfinity/sdk/pull/1973
-    let mut result: Option<CyclesResponse> = None;
-    for _ in (0..MAX_RETRY) {
-        match call::<_, (CyclesResponse,), _>(
-            Principal::from_slice(LEDGER_CANISTER_ID.as_ref()),
-            "notify_dfx",
-            (NotifyCanisterArgs {
-                block_height: new_block_height,
-                max_fee: ICPFEE,
-                from_subaccount: None,
-                to_canister: ic_types::CanisterId::new(ic_types::PrincipalId::from(
-                    cycles_minting_canister,
-                ))
-                .unwrap(),
-                to_subaccount: Some(Subaccount::from(&ic_types::PrincipalId::from(ic::id()))),
-            },),
-        )
-        .await
-        {
-            Ok(cycles_response) => {
-                result = Some(cycles_response.0);
-                break;
-            }
-            Err(_) => continue,
+    fn is_archived(&self, block_height: BlockHeight) -> bool {
+        let word = self.archive.get(&(block_height / 64)).unwrap_or(0);
+        word & (1 << (block_height % 64)) != 0
+    }
+
+    fn archive(&mut self, block_height: BlockHeight, send_block: Option<BlockHeight>) {
+        let index = block_height / 64;
+        let word = self.archive.get(&index).unwrap_or(0);
+        self.archive
+            .insert(index, word | (1 << (block_height % 64)));
+        if let Some(send_block) = send_block {
+            self.archived_send_blocks.insert(block_height, send_block);
         }
     }
-    let result = result.ok_or(TxError::NotifyDfxFailed)?;
-    // ====================================================
-
-    // ====================================================
-    // Credit XTC
-    match result {
-        CyclesResponse::ToppedUp(()) => {
-            ic::get_mut::<Ledger>().deposit(&user_principal, cycles);
-            Ok(Nat::from(ic::get_mut::<HistoryBuffer>().push(
-                Transaction {
-                    timestamp: ic::time(),
-                    cycles,
-                    fee,
-                    kind: TransactionKind::Mint { to: user_principal },
-                    status: TransactionStatus::SUCCEEDED,
-                },
-            )))
+
+    /// Return the heights of all the used blocks, the archived ones included.
+    pub fn heights(&self) -> Vec<BlockHeight> {
+        let mut heights: Vec<BlockHeight> = self
+            .blocks
+            .iter()
+            .map(|(block_height, _)| block_height)
+            .chain(self.archive.iter().flat_map(|(index, word)| {
+                (0..64)
+                    .filter(move |bit| word & (1 << bit) != 0)
+                    .map(move |bit| index * 64 + bit)
+            }))
+            .collect();
+        heights.sort_unstable();
+        heights
+    }
+
+    /// Record the block the ICP of the used block was sent to the cycles minting canister in.
+    pub fn set_send_block(&mut self, block_height: BlockHeight, send_block: BlockHeight) {
+        if let Some(mut entry) = self.blocks.get(&block_height) {
+            entry.send_block = Some(send_block);
+            self.blocks.insert(block_height, entry);
+        }
+    }
+
+    pub fn send_block(&self, block_height: &BlockHeight) -> Option<BlockHeight> {
+        match self.blocks.get(block_height) {
+            Some(entry) => entry.send_block,
+            None => self.archived_send_blocks.get(block_height),
+        }
+    }
+
+    /// Return the blocks recorded in the log from the given position on, at most limit records
+    /// are read and the blocks that were released since are skipped. The next position is set
+    /// if the log has more records.
+    pub fn page(&self, start: u64, limit: u16) -> UsedBlocksPage {
+        let head = self.log.head();
+        let end = head + self.log.len();
+        let start = start.max(head);
+        let stop = start.saturating_add(limit as u64).min(end);
+
+        let blocks = self
+            .log
+            .range(start - head, stop - head)
+            .into_iter()
+            .zip(start..)
+            .filter_map(|(record, position)| {
+                let entry = self.blocks.get(&record.block_height)?;
+                if entry.position != position {
+                    return None;
+                }
+                Some(UsedBlock {
+                    block_height: record.block_height,
+                    send_block: entry.send_block,
+                    created_at: entry.created_at,
+                    used_at: entry.used_at,
+                })
+            })
+            .collect();
+
+        UsedBlocksPage {
+            blocks,
+            next: if stop < end { Some(stop) } else { None },
         }
-        _ => Err(TxError::UnexpectedCyclesResponse),
     }
-    // ====================================================
+
+    /// Drop up to limit records from the front of the log whose block was created before the
+    /// transaction window, returns the number of blocks that were archived.
+    pub fn prune(&mut self, now: u64, limit: u64) -> u64 {
+        let mut pruned = 0;
+        for _ in 0..limit {
+            let record = match self.log.get(0) {
+                Some(record) if record.created_at.saturating_add(TRANSACTION_WINDOW) < now => {
+                    record
+                }
+                _ => break,
+            };
+
+            // The block may have been released and used again since, with a new record.
+            let position = self.log.head();
+            if let Some(entry) = self.blocks.get(&record.block_height) {
+                if entry.position == position {
+                    self.blocks.remove(&record.block_height);
+                    self.archive(record.block_height, entry.send_block);
+                    pruned += 1;
+                }
+            }
+            self.log.pop_front(1);
+        }
+        pruned
+    }
+}
+
+/// A page of the used blocks, see `get_used_blocks`.
+#[derive(CandidType, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct UsedBlocksPage {
+    pub blocks: Vec<UsedBlock>,
+    pub next: Option<u64>,
+}
+
+/// Return the heights of all the used blocks, see `get_used_blocks` for the details of the
+/// recent ones.
+#[query(name = "getBlockUsed")]
+pub fn get_block_used() -> Vec<BlockHeight> {
+    ic::get::<UsedBlocks>().heights()
+}
+
+#[query(name = "isBlockUsed")]
+fn is_block_used(block_number: BlockHeight) -> bool {
+    ic::get::<UsedBlocks>().contains(&block_number)
+}
+
+#[query]
+fn get_map_block_used(block_number: BlockHeight) -> Option<BlockHeight> {
+    ic::get::<UsedBlocks>().send_block(&block_number)
+}
+
+/// Return the used blocks in the order they were used, starting at the given position of the
+/// log. Pass the next position of a page to get the following one, a page may hold fewer than
+/// limit blocks when some of them were released. The archived blocks are not listed.
+#[query]
+pub fn get_used_blocks(start: u64, limit: u16) -> UsedBlocksPage {
+    ic::get::<UsedBlocks>().page(start, limit)
+}
+
+/// Archive the blocks created before the transaction window, returns the number of archived
+/// blocks. The blocks are also pruned a few at a time on every mint.
+#[update]
+pub fn prune_used_blocks() -> u64 {
+    Roles::guard(Role::Operator);
+
+    ic::get_mut::<UsedBlocks>().prune(ic::time(), MAX_PRUNE)
 }
 
 //////////////////// END OF ERC-20 ///////////////////////
@@ -696,24 +796,31 @@ pub enum MintError {
     NotSufficientLiquidity,
 }
 
+/// Mint the given amount from the attached cycles, only the amount and the fee are accepted
+/// and the rest is refunded. A zero amount mints all the attached cycles after the fee.
 #[update]
-pub async fn mint(to: Principal, _amount: Nat) -> TxReceipt {
-    IsShutDown::guard();
+pub async fn mint(to: Principal, amount: Nat, to_subaccount: Option<Subaccount>) -> TxReceipt {
+    PauseFlags::guard(PauseTarget::Mint);
 
     let ic = get_context();
-    let caller = ic.caller();
+    let to = Account::new(to, to_subaccount);
+    let amount = utils::convert_nat_to_u64(amount).map_err(|_| TxError::InsufficientCycles)?;
 
     crate::progress().await;
 
     let available = ic.msg_cycles_available();
-    let fee = compute_fee(available);
+    let (cycles, fee) = if amount == 0 {
+        let fee = compute_fee(FeeOperation::Mint, available);
+        (available.saturating_sub(fee), fee)
+    } else {
+        (amount, compute_fee(FeeOperation::Mint, amount))
+    };
 
-    if available <= fee {
-        panic!("Cannot mint less than {}", fee);
+    if cycles == 0 || available < cycles.saturating_add(fee) {
+        return Err(TxError::InsufficientCycles);
     }
 
-    let accepted = ic.msg_cycles_accept(available);
-    let cycles = accepted - fee;
+    ic.msg_cycles_accept(cycles + fee);
 
     let ledger = ic.get_mut::<Ledger>();
     ledger.deposit(&to, cycles);
@@ -722,19 +829,23 @@ pub async fn mint(to: Principal, _amount: Nat) -> TxReceipt {
         timestamp: ic.time(),
         cycles,
         fee,
-        kind: TransactionKind::Mint { to },
+        kind: TransactionKind::Mint {
+            to: to.owner,
+            to_subaccount: to.subaccount,
+        },
         status: TransactionStatus::SUCCEEDED,
     };
 
-    Ok(Nat::from(
-        ic_kit::ic::get_mut::<HistoryBuffer>().push(transaction.clone()),
-    ))
+    let id = ic_kit::ic::get_mut::<HistoryBuffer>().push(transaction);
+    FeeCollector::collect(fee);
+    Ok(Nat::from(id))
 }
 
 #[derive(Deserialize, CandidType)]
 pub struct BurnArguments {
     pub canister_id: Principal,
     pub amount: u64,
+    pub from_subaccount: Option<Subaccount>,
 }
 
 #[derive(CandidType, Debug)]
@@ -746,15 +857,16 @@ pub enum BurnError {
 
 #[update]
 pub async fn burn(args: BurnArguments) -> Result<TransactionId, BurnError> {
-    IsShutDown::guard();
+    PauseFlags::guard(PauseTarget::Burn);
 
     let ic = get_context();
     let caller = ic.caller();
+    let from = Account::new(caller, args.from_subaccount);
 
-    let deduced_fee = compute_fee(args.amount);
+    let deduced_fee = compute_fee(FeeOperation::Burn, args.amount);
     let ledger = ic.get_mut::<Ledger>();
     ledger
-        .withdraw(&caller, args.amount + deduced_fee)
+        .withdraw(&from, args.amount + deduced_fee)
         .map_err(|_| BurnError::InsufficientBalance)?;
 
     #[derive(CandidType)]
@@ -778,13 +890,16 @@ pub async fn burn(args: BurnArguments) -> Result<TransactionId, BurnError> {
         Ok(()) => {
             let refunded = ic.msg_cycles_refunded();
             let cycles = args.amount - refunded;
-            let actual_fee = compute_fee(cycles);
+            let actual_fee = compute_fee(FeeOperation::Burn, cycles);
             let refunded = refunded + (deduced_fee - actual_fee);
 
             if refunded > 0 {
-                ledger.deposit(&caller, refunded);
+                ledger.deposit(&from, refunded);
             }
 
+            ic.get_mut::<CanisterRegistry>()
+                .add_cycles_sent(&args.canister_id, cycles);
+
             let id = ic.get_mut::<HistoryBuffer>().push(Transaction {
                 timestamp: ic.time(),
                 cycles,
@@ -792,14 +907,16 @@ pub async fn burn(args: BurnArguments) -> Result<TransactionId, BurnError> {
                 kind: TransactionKind::Burn {
                     from: caller.clone(),
                     to: args.canister_id,
+                    from_subaccount: from.subaccount,
                 },
                 status: TransactionStatus::SUCCEEDED,
             });
+            FeeCollector::collect(actual_fee);
 
             Ok(id)
         }
         Err(_) => {
-            ledger.deposit(&caller, args.amount);
+            ledger.deposit(&from, args.amount);
 
             ic.get_mut::<HistoryBuffer>().push(Transaction {
                 timestamp: ic.time(),
@@ -808,6 +925,7 @@ pub async fn burn(args: BurnArguments) -> Result<TransactionId, BurnError> {
                 kind: TransactionKind::Burn {
                     from: caller.clone(),
                     to: args.canister_id,
+                    from_subaccount: from.subaccount,
                 },
                 status: TransactionStatus::FAILED,
             });
@@ -820,23 +938,31 @@ pub async fn burn(args: BurnArguments) -> Result<TransactionId, BurnError> {
 mod tests {
     use super::Ledger;
     use super::TxError;
+    use crate::common_types::{Account, Subaccount};
     use ic_kit::{MockContext, Principal};
 
-    fn alice() -> Principal {
-        Principal::from_text("fterm-bydaq-aaaaa-aaaaa-c").unwrap()
+    fn alice() -> Account {
+        Principal::from_text("fterm-bydaq-aaaaa-aaaaa-c")
+            .unwrap()
+            .into()
     }
 
-    fn bob() -> Principal {
-        Principal::from_text("ai7t5-aibaq-aaaaa-aaaaa-c").unwrap()
+    fn bob() -> Account {
+        Principal::from_text("ai7t5-aibaq-aaaaa-aaaaa-c")
+            .unwrap()
+            .into()
     }
 
-    fn charlie() -> Principal {
-        Principal::from_text("hozae-racaq-aaaaa-aaaaa-c").unwrap()
+    fn charlie() -> Account {
+        Principal::from_text("hozae-racaq-aaaaa-aaaaa-c")
+            .unwrap()
+            .into()
     }
 
     #[test]
     #[should_panic]
     fn approval_to_self() {
+        MockContext::new().inject();
         let mut ledger = Ledger::default();
 
         // alice tries to approve herself
@@ -846,6 +972,7 @@ mod tests {
     #[test]
     #[should_panic]
     fn transfer_from_zero_amount() {
+        MockContext::new().inject();
         let mut ledger = Ledger::default();
 
         ledger.approve(&alice(), &bob(), 1000, 0);
@@ -1008,13 +1135,82 @@ mod tests {
         assert_eq!(ledger.balance(&bob()), 0);
         assert_eq!(ledger.balance(&charlie()), 0);
     }
+
+    #[test]
+    fn allowance_expiry() {
+        MockContext::new().inject();
+
+        let mut ledger = Ledger::default();
+        ledger.deposit(&alice(), 1000);
+
+        ledger.set_allowance(&alice(), &bob(), 500, Some(u64::MAX));
+        ledger.set_allowance(&alice(), &charlie(), 500, Some(0));
+        assert_eq!(ledger.allowance(&alice(), &bob()), 500);
+        // expired allowances are reported as zero and cannot be spent.
+        assert_eq!(ledger.allowance(&alice(), &charlie()), 0);
+        assert_eq!(
+            ledger
+                .transfer_from(&charlie(), &alice(), &charlie(), 100, 0)
+                .unwrap_err(),
+            TxError::InsufficientAllowance
+        );
+
+        // spending keeps the expiration of the allowance.
+        assert_eq!(
+            ledger.transfer_from(&bob(), &alice(), &bob(), 100, 0),
+            Ok(())
+        );
+        assert_eq!(ledger.allowance(&alice(), &bob()), 400);
+        assert_eq!(
+            ledger.allowance_expiration(&alice(), &bob()),
+            Some(u64::MAX)
+        );
+
+        // approve replaces the expiration.
+        assert_eq!(ledger.approve(&alice(), &bob(), 100, 0), Ok(()));
+        assert_eq!(ledger.allowance_expiration(&alice(), &bob()), None);
+
+        ledger.prune_expired_allowances(1);
+        assert!(ledger.allowances.get(&(alice(), charlie())).is_none());
+        assert!(ledger.expirations.is_empty());
+        assert_eq!(ledger.allowance(&alice(), &bob()), 100);
+    }
+
+    #[test]
+    fn subaccounts() {
+        MockContext::new().inject();
+
+        let alice_1 = Account::new(alice().owner, Some(Subaccount([1; 32])));
+        let alice_zero = Account::new(alice().owner, Some(Subaccount([0; 32])));
+
+        // the all-zero subaccount is the default subaccount.
+        assert_eq!(alice_zero, alice());
+
+        let mut ledger = Ledger::default();
+        ledger.deposit(&alice(), 1000);
+        assert_eq!(ledger.balance(&alice_1), 0);
+
+        assert_eq!(ledger.transfer(&alice(), &alice_1, 400, 10), Ok(()));
+        assert_eq!(ledger.balance(&alice()), 590);
+        assert_eq!(ledger.balance(&alice_1), 400);
+
+        // allowances are bound to the subaccount they were given from.
+        assert_eq!(ledger.approve(&alice_1, &bob(), 100, 0), Ok(()));
+        assert_eq!(ledger.allowance(&alice(), &bob()), 0);
+        assert_eq!(
+            ledger.transfer_from(&bob(), &alice_1, &charlie(), 100, 0),
+            Ok(())
+        );
+        assert_eq!(ledger.balance(&alice_1), 300);
+        assert_eq!(ledger.balance(&charlie()), 100);
+    }
 }
 
 #[update]
-pub async fn balance(account: Option<Principal>) -> u64 {
+pub async fn balance(account: Option<Principal>, subaccount: Option<Subaccount>) -> u64 {
     let ic = get_context();
     let caller = ic.caller();
     crate::progress().await;
     let ledger = ic.get::<Ledger>();
-    ledger.balance(&account.unwrap_or(caller))
+    ledger.balance(&Account::new(account.unwrap_or(caller), subaccount))
 }
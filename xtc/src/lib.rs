@@ -1,12 +1,19 @@
 #![allow(warnings)]
 
+mod canisters;
 mod common_types;
 mod cycles_wallet;
 mod fee;
 mod history;
+mod icp_mint;
+mod icrc1;
+mod icrc2;
 mod ledger;
 mod management;
+mod memory;
 mod meta;
+mod proposals;
+mod seed;
 mod stats;
 mod upgrade;
 mod utils;
@@ -18,12 +25,15 @@ mod tests;
 /// as the result of calling this method or not.
 /// This method should only be called from updates.
 ///
-/// Currently only the history has async tasks, but in future there might
-/// be more things following this design pattern for handling tasks.
+/// The seeds of the stable maps are fetched first, then the history runs its tasks.
 #[inline]
 pub async fn progress() -> bool {
     use ic_kit::{get_context, Context};
 
+    if seed::progress().await {
+        return true;
+    }
+
     let ic = get_context();
     let history = ic.get_mut::<history::HistoryBuffer>();
     history.progress().await
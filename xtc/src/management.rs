@@ -1,5 +1,14 @@
+use crate::common_types::Account;
+use crate::fee::FeeSchedule;
+use crate::icp_mint::IcpConfig;
+use crate::memory::{self, Region};
+use crate::proposals::{ProposalConfig, ProposalId, Proposals};
+use ic_kit::candid::{decode_one, encode_one, CandidType};
 use ic_kit::macros::*;
 use ic_kit::{get_context, Context, Principal};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use xtc_stable::StableLog;
 
 // --- init
 
@@ -26,6 +35,12 @@ impl Controller {
         }
     }
 
+    #[inline]
+    pub fn get() -> Option<Principal> {
+        let ic = get_context();
+        ic.get::<Controller>().0
+    }
+
     #[inline]
     pub fn get_principal() -> Principal {
         let ic = get_context();
@@ -33,55 +48,420 @@ impl Controller {
     }
 }
 
+/// The principal the controller offered to hand the canister to, it only becomes the controller
+/// once it accepts, so the canister cannot be handed to a principal nobody controls.
+#[derive(Default)]
+pub struct PendingController(Option<Principal>);
+
+impl PendingController {
+    pub fn load(controller: Option<Principal>) {
+        let ic = get_context();
+        ic.get_mut::<PendingController>().0 = controller;
+    }
+
+    #[inline]
+    pub fn get() -> Option<Principal> {
+        let ic = get_context();
+        ic.get::<PendingController>().0
+    }
+
+    /// Offer the canister to a new controller and record the offer in the audit log.
+    pub fn propose(controller: Option<Principal>) {
+        PendingController::load(controller);
+        AuditLog::record(AdminAction::ProposeController { controller });
+    }
+}
+
+/// Offer the canister to a new controller, None withdraws the pending offer. Once the
+/// proposals are enabled the offer has to go through an admin proposal.
+#[update]
+pub fn propose_controller(controller: Option<Principal>) {
+    let ic = get_context();
+
+    if ic.caller() != Controller::get_principal() {
+        panic!("Only the controller can call this method.");
+    }
+    Proposals::guard_direct();
+
+    PendingController::propose(controller);
+}
+
+#[update]
+pub fn accept_controller() {
+    let ic = get_context();
+    let caller = ic.caller();
+
+    if PendingController::get() != Some(caller) {
+        panic!("Only the proposed controller can call this method.");
+    }
+
+    PendingController::load(None);
+    Controller::load(caller);
+    AuditLog::record(AdminAction::AcceptController);
+}
+
+#[query]
+pub fn get_pending_controller() -> Option<Principal> {
+    PendingController::get()
+}
+
+// --- roles
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Role {
+    /// Grants and revokes roles, admins pass the checks of every other role.
+    Admin,
+    Pauser,
+    FeeManager,
+    Operator,
+}
+
+/// The principals holding each role, the controller is implicitly an admin.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct Roles(HashMap<Role, HashSet<Principal>>);
+
+impl Roles {
+    pub fn load(data: Roles) {
+        let ic = get_context();
+        *ic.get_mut::<Roles>() = data;
+    }
+
+    #[inline]
+    pub fn get() -> Roles {
+        let ic = get_context();
+        ic.get::<Roles>().clone()
+    }
+
+    /// Return true if the principal is allowed to act with the given role.
+    pub fn has_role(principal: &Principal, role: Role) -> bool {
+        let ic = get_context();
+        let roles = ic.get::<Roles>();
+        let holds = |role| {
+            roles
+                .0
+                .get(&role)
+                .map(|principals| principals.contains(principal))
+                .unwrap_or(false)
+        };
+
+        ic.get::<Controller>().0 == Some(*principal) || holds(Role::Admin) || holds(role)
+    }
+
+    /// Return the admins, the controller is not included.
+    pub fn admins() -> Vec<Principal> {
+        let ic = get_context();
+        ic.get::<Roles>()
+            .0
+            .get(&Role::Admin)
+            .map(|principals| principals.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn grant(role: Role, principal: Principal) {
+        let ic = get_context();
+        ic.get_mut::<Roles>()
+            .0
+            .entry(role)
+            .or_default()
+            .insert(principal);
+        AuditLog::record(AdminAction::GrantRole { role, principal });
+    }
+
+    pub fn revoke(role: Role, principal: Principal) {
+        let ic = get_context();
+        if let Some(principals) = ic.get_mut::<Roles>().0.get_mut(&role) {
+            principals.remove(&principal);
+        }
+        AuditLog::record(AdminAction::RevokeRole { role, principal });
+    }
+
+    /// Panic if the caller is not allowed to act with the given role.
+    #[inline]
+    pub fn guard(role: Role) {
+        let ic = get_context();
+        if !Roles::has_role(&ic.caller(), role) {
+            panic!("The caller does not have the {:?} role.", role);
+        }
+    }
+}
+
+#[update]
+pub fn grant_role(role: Role, principal: Principal) {
+    Roles::guard(Role::Admin);
+    Proposals::guard_direct();
+
+    Roles::grant(role, principal);
+}
+
+#[update]
+pub fn revoke_role(role: Role, principal: Principal) {
+    Roles::guard(Role::Admin);
+    Proposals::guard_direct();
+
+    Roles::revoke(role, principal);
+}
+
+#[query]
+pub fn list_roles() -> Vec<(Role, Vec<Principal>)> {
+    let ic = get_context();
+    ic.get::<Roles>()
+        .0
+        .iter()
+        .filter(|(_, principals)| !principals.is_empty())
+        .map(|(role, principals)| (*role, principals.iter().cloned().collect()))
+        .collect()
+}
+
+// --- audit
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum AdminAction {
+    GrantRole {
+        role: Role,
+        principal: Principal,
+    },
+    RevokeRole {
+        role: Role,
+        principal: Principal,
+    },
+    Halt,
+    Pause {
+        targets: Vec<PauseTarget>,
+        reason: Option<String>,
+    },
+    Unpause {
+        targets: Vec<PauseTarget>,
+    },
+    SetFeeCollector {
+        account: Option<Account>,
+    },
+    SetFeeSchedule {
+        schedule: FeeSchedule,
+    },
+    ProposeController {
+        controller: Option<Principal>,
+    },
+    AcceptController,
+    SetProposalConfig {
+        config: ProposalConfig,
+    },
+    ExecuteProposal {
+        id: ProposalId,
+    },
+    CancelProposal {
+        id: ProposalId,
+    },
+    SetIcpConfig {
+        config: IcpConfig,
+    },
+    SetIcpRateMaxAge {
+        max_age: u64,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    pub timestamp: u64,
+    pub caller: Principal,
+    pub action: AdminAction,
+}
+
+/// The trail of all the privileged actions performed on the canister, the entries are stored
+/// candid encoded in the stable memory.
+pub struct AuditLog(StableLog<Region>);
+
+impl Default for AuditLog {
+    /// Create an empty trail, this drops the entries stored in the stable memory.
+    fn default() -> Self {
+        AuditLog(StableLog::new(
+            memory::region(memory::AUDIT_LOG_INDEX),
+            memory::region(memory::AUDIT_LOG_DATA),
+        ))
+    }
+}
+
+impl AuditLog {
+    /// Load the trail stored in the stable memory.
+    pub fn restore() -> Self {
+        AuditLog(StableLog::init(
+            memory::region(memory::AUDIT_LOG_INDEX),
+            memory::region(memory::AUDIT_LOG_DATA),
+        ))
+    }
+
+    pub fn record(action: AdminAction) {
+        let ic = get_context();
+        let entry = AuditEntry {
+            timestamp: ic.time(),
+            caller: ic.caller(),
+            action,
+        };
+        let data = encode_one(&entry).expect("Failed to encode the audit entry.");
+        ic.get_mut::<AuditLog>().0.push(&data);
+    }
+
+    pub fn get(&self, index: u64) -> Option<AuditEntry> {
+        self.0
+            .get(index)
+            .map(|data| decode_one(&data).expect("Failed to decode the audit entry."))
+    }
+}
+
+/// Return at most limit entries of the audit trail starting at the given index.
+#[query]
+pub fn get_audit_log(start: u64, limit: u16) -> Vec<AuditEntry> {
+    let ic = get_context();
+    let log = ic.get::<AuditLog>();
+    let end = start.saturating_add(limit as u64).min(log.0.len());
+    (start..end).filter_map(|index| log.get(index)).collect()
+}
+
 #[init]
 fn init() {
     let ic = get_context();
     Controller::load_if_not_present(ic.caller());
 }
 
-// --- halt
+// --- pause
 
-pub struct IsShutDown(bool);
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum PauseTarget {
+    Transfers,
+    Approvals,
+    Mint,
+    MintByIcp,
+    Burn,
+    WalletCall,
+    WalletSend,
+    CreateCanister,
+    ManageCanister,
+}
 
-impl Default for IsShutDown {
-    fn default() -> Self {
-        IsShutDown(false)
-    }
+impl PauseTarget {
+    pub const ALL: [PauseTarget; 9] = [
+        PauseTarget::Transfers,
+        PauseTarget::Approvals,
+        PauseTarget::Mint,
+        PauseTarget::MintByIcp,
+        PauseTarget::Burn,
+        PauseTarget::WalletCall,
+        PauseTarget::WalletSend,
+        PauseTarget::CreateCanister,
+        PauseTarget::ManageCanister,
+    ];
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct PauseStatus {
+    pub target: PauseTarget,
+    pub paused: bool,
+    pub reason: Option<String>,
 }
 
-impl IsShutDown {
+/// The paused operations together with the reason given when they were paused.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct PauseFlags(HashMap<PauseTarget, Option<String>>);
+
+impl PauseFlags {
+    pub fn load(data: PauseFlags) {
+        let ic = get_context();
+        *ic.get_mut::<PauseFlags>() = data;
+    }
+
+    #[inline]
+    pub fn get() -> PauseFlags {
+        let ic = get_context();
+        ic.get::<PauseFlags>().clone()
+    }
+
     #[inline]
-    pub fn get() -> bool {
+    pub fn is_paused(target: PauseTarget) -> bool {
+        let ic = get_context();
+        ic.get::<PauseFlags>().0.contains_key(&target)
+    }
+
+    pub fn pause(targets: Vec<PauseTarget>, reason: Option<String>) {
+        let ic = get_context();
+        let flags = ic.get_mut::<PauseFlags>();
+        for target in &targets {
+            flags.0.insert(*target, reason.clone());
+        }
+        AuditLog::record(AdminAction::Pause { targets, reason });
+    }
+
+    pub fn unpause(targets: Vec<PauseTarget>) {
         let ic = get_context();
-        ic.get::<IsShutDown>().0
+        let flags = ic.get_mut::<PauseFlags>();
+        for target in &targets {
+            flags.0.remove(target);
+        }
+        AuditLog::record(AdminAction::Unpause { targets });
     }
 
+    pub fn halt() {
+        let ic = get_context();
+        let flags = ic.get_mut::<PauseFlags>();
+        for target in PauseTarget::ALL.iter() {
+            flags.0.insert(*target, None);
+        }
+        AuditLog::record(AdminAction::Halt);
+    }
+
+    /// Panic if the given operations are paused.
     #[inline]
-    pub fn guard() {
-        if IsShutDown::get() {
-            panic!("The canister has been halted until the next code upgrade.")
+    pub fn guard(target: PauseTarget) {
+        let ic = get_context();
+        match ic.get::<PauseFlags>().0.get(&target) {
+            Some(Some(reason)) => panic!("{:?} is paused: {}", target, reason),
+            Some(None) => panic!("{:?} is paused.", target),
+            None => (),
         }
     }
 }
 
+/// Pause the given operations.
 #[update]
-fn halt() {
+pub fn pause(targets: Vec<PauseTarget>, reason: Option<String>) {
+    Roles::guard(Role::Pauser);
+    Proposals::guard_direct();
+
+    PauseFlags::pause(targets, reason);
+}
+
+#[update]
+pub fn unpause(targets: Vec<PauseTarget>) {
+    Roles::guard(Role::Pauser);
+    Proposals::guard_direct();
+
+    PauseFlags::unpause(targets);
+}
+
+#[query]
+pub fn pause_status() -> Vec<PauseStatus> {
     let ic = get_context();
+    let flags = ic.get::<PauseFlags>();
+    PauseTarget::ALL
+        .iter()
+        .map(|target| PauseStatus {
+            target: *target,
+            paused: flags.0.contains_key(target),
+            reason: flags.0.get(target).cloned().flatten(),
+        })
+        .collect()
+}
 
-    if ic.caller() != Controller::get_principal() {
-        panic!("Only the controller can call this method.");
-    }
+/// Pause every operation, they can be resumed with unpause.
+#[update]
+fn halt() {
+    Roles::guard(Role::Pauser);
+    Proposals::guard_direct();
 
-    ic.get_mut::<IsShutDown>().0 = true;
+    PauseFlags::halt();
 }
 
 #[update]
 async fn finish_pending_tasks(limit: u32) {
-    let ic = get_context();
-
-    if ic.caller() != Controller::get_principal() {
-        panic!("Only the controller can call this method.");
-    }
+    Roles::guard(Role::Operator);
 
     for _ in 0..limit {
         if !crate::progress().await {
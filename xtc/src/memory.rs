@@ -0,0 +1,70 @@
+//! The layout of the stable memory, each structure that lives in the stable memory gets its
+//! own region so it can grow independently of the others.
+
+use ic_kit::{get_context, Context};
+use xtc_stable::manager;
+use xtc_stable::{Memory, MemoryId, VirtualMemory};
+
+pub const LEDGER: MemoryId = MemoryId::new(0);
+/// The used ICP blocks pruned from the log and the blocks their ICP was sent in.
+pub const USED_BLOCK_ARCHIVE: MemoryId = MemoryId::new(1);
+pub const ARCHIVED_SEND_BLOCKS: MemoryId = MemoryId::new(2);
+pub const HISTORY: MemoryId = MemoryId::new(3);
+/// The heap state which is serialized on every upgrade.
+pub const STATE: MemoryId = MemoryId::new(4);
+/// The used ICP blocks since V1 and the log they are pruned from.
+pub const USED_BLOCK_ENTRIES: MemoryId = MemoryId::new(5);
+pub const USED_BLOCK_LOG: MemoryId = MemoryId::new(6);
+/// The audit trail, the offsets of the entries and the entries themselves.
+pub const AUDIT_LOG_INDEX: MemoryId = MemoryId::new(7);
+pub const AUDIT_LOG_DATA: MemoryId = MemoryId::new(8);
+/// The admin proposals, written again on every change.
+pub const PROPOSALS: MemoryId = MemoryId::new(9);
+/// The canisters created through XTC and the lists of the canisters of each creator.
+pub const CANISTERS: MemoryId = MemoryId::new(10);
+pub const CANISTERS_BY_CREATOR: MemoryId = MemoryId::new(11);
+pub const CANISTER_COUNTS: MemoryId = MemoryId::new(12);
+
+/// The stable memory of the canister, accessed through the ic-kit context so it is mocked in
+/// the tests.
+#[derive(Clone, Copy, Default)]
+pub struct CanisterMemory;
+
+impl Memory for CanisterMemory {
+    fn size(&self) -> u64 {
+        get_context().stable_size() as u64
+    }
+
+    fn grow(&self, pages: u64) -> bool {
+        get_context().stable_grow(pages as u32).is_ok()
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        get_context().stable_read(offset as u32, dst)
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        get_context().stable_write(offset as u32, src)
+    }
+}
+
+pub type Region = VirtualMemory<CanisterMemory>;
+
+/// Return the region of the stable memory with the given id.
+#[inline]
+pub fn region(id: MemoryId) -> Region {
+    VirtualMemory::new(CanisterMemory, id)
+}
+
+/// Return false if the stable memory is empty or still holds the archive written by the
+/// versions of XTC that serialized the entire state on upgrades.
+#[inline]
+pub fn is_formatted() -> bool {
+    manager::is_formatted(&CanisterMemory)
+}
+
+/// Drop everything in the stable memory and start over with empty regions.
+#[inline]
+pub fn format() {
+    manager::format(&CanisterMemory)
+}
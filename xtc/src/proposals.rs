@@ -0,0 +1,367 @@
+//! The admin proposals, the sensitive actions are queued until enough admins approved them and
+//! the timelock has passed. While the approval threshold is zero the role holders can perform
+//! the actions directly.
+//!
+//! Each role proposes and executes the actions of its own role, so the pausers and the fee
+//! managers can still act once the threshold is set, but only the approvals of the admins are
+//! counted.
+//!
+//! At most `MAX_PROPOSALS` proposals are pending at once, a proposal leaves the list once it is
+//! executed or cancelled, or when it expired `PROPOSAL_EXPIRY` after its timelock.
+
+use crate::common_types::Account;
+use crate::fee::{FeeCollector, FeeSchedule};
+use crate::icp_mint::{IcpConfig, IcpRateCache};
+use crate::management::{
+    AdminAction, AuditLog, Controller, PauseFlags, PauseTarget, PendingController, Role, Roles,
+};
+use crate::memory;
+use ic_kit::candid::{decode_one, encode_one, CandidType};
+use ic_kit::macros::*;
+use ic_kit::{get_context, Context, Principal};
+use serde::Deserialize;
+use xtc_stable::StableCell;
+
+pub type ProposalId = u64;
+
+/// The maximum number of pending proposals.
+pub const MAX_PROPOSALS: usize = 32;
+
+/// The nanoseconds a proposal can still be executed once its timelock has passed.
+pub const PROPOSAL_EXPIRY: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum ProposalAction {
+    SetFeeSchedule {
+        schedule: FeeSchedule,
+    },
+    SetFeeCollector {
+        account: Option<Account>,
+    },
+    GrantRole {
+        role: Role,
+        principal: Principal,
+    },
+    RevokeRole {
+        role: Role,
+        principal: Principal,
+    },
+    Halt,
+    Pause {
+        targets: Vec<PauseTarget>,
+        reason: Option<String>,
+    },
+    Unpause {
+        targets: Vec<PauseTarget>,
+    },
+    SetProposalConfig {
+        config: ProposalConfig,
+    },
+    SetIcpConfig {
+        config: IcpConfig,
+    },
+    SetIcpRateMaxAge {
+        max_age: u64,
+    },
+    ProposeController {
+        controller: Option<Principal>,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ProposalConfig {
+    /// The number of admins that have to approve a proposal, zero disables the proposals.
+    pub threshold: u32,
+    /// The nanoseconds between the creation of a proposal and the time it can be executed.
+    pub timelock: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Proposal {
+    pub id: ProposalId,
+    pub proposer: Principal,
+    pub action: ProposalAction,
+    pub created_at: u64,
+    pub approvals: Vec<Principal>,
+    pub expires_at: u64,
+}
+
+/// The proposals and their settings, they are kept on the heap and written to their region of
+/// the stable memory on every change.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct Proposals {
+    config: ProposalConfig,
+    proposals: Vec<Proposal>,
+    next_id: ProposalId,
+}
+
+impl Proposals {
+    /// Load the proposals stored in the stable memory.
+    pub fn restore() -> Self {
+        StableCell::new(memory::region(memory::PROPOSALS))
+            .get()
+            .map(|data| decode_one(&data).expect("Failed to decode the proposals."))
+            .unwrap_or_default()
+    }
+
+    /// Write the proposals to the stable memory, this has to follow every change.
+    fn save(&self) {
+        let data = encode_one(self).expect("Failed to encode the proposals.");
+        StableCell::new(memory::region(memory::PROPOSALS)).set(&data);
+    }
+
+    /// Panic if the sensitive actions have to go through a proposal.
+    #[inline]
+    pub fn guard_direct() {
+        let ic = get_context();
+        if ic.get::<Proposals>().config.threshold > 0 {
+            panic!("This action requires an admin proposal.");
+        }
+    }
+
+    /// Return the number of principals that can approve a proposal, the admins and the
+    /// controller.
+    fn approvers() -> usize {
+        let admins = Roles::admins();
+        match Controller::get() {
+            Some(controller) if !admins.contains(&controller) => admins.len() + 1,
+            _ => admins.len(),
+        }
+    }
+
+    /// Check that the threshold can be met by the current approvers.
+    fn validate_config(config: &ProposalConfig) -> Result<(), String> {
+        let approvers = Proposals::approvers();
+        if config.threshold as usize > approvers {
+            return Err(format!(
+                "The threshold can not exceed the {} admins able to approve.",
+                approvers
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check that revoking the role does not leave fewer admins than the threshold.
+    pub fn validate_revoke(role: Role, principal: &Principal) -> Result<(), String> {
+        let ic = get_context();
+        let threshold = ic.get::<Proposals>().config.threshold as usize;
+        let is_approver = role == Role::Admin
+            && Roles::admins().contains(principal)
+            && Controller::get() != Some(*principal);
+        if is_approver && Proposals::approvers() - 1 < threshold {
+            return Err("Revoking the admin would leave fewer admins than the threshold.".into());
+        }
+        Ok(())
+    }
+
+    fn set_config(config: ProposalConfig) {
+        let ic = get_context();
+        let proposals = ic.get_mut::<Proposals>();
+        proposals.config = config;
+        proposals.save();
+        AuditLog::record(AdminAction::SetProposalConfig { config });
+    }
+
+    fn position(&self, id: ProposalId, now: u64) -> Result<usize, String> {
+        match self.proposals.iter().position(|proposal| proposal.id == id) {
+            Some(index) if self.proposals[index].expires_at <= now => {
+                Err("The proposal expired.".into())
+            }
+            Some(index) => Ok(index),
+            None => Err("The proposal does not exist.".into()),
+        }
+    }
+
+    /// Drop the proposals that expired.
+    fn prune(&mut self, now: u64) {
+        self.proposals.retain(|proposal| proposal.expires_at > now);
+    }
+}
+
+impl ProposalAction {
+    /// Return the role that proposes and executes the action.
+    fn role(&self) -> Role {
+        match self {
+            ProposalAction::SetFeeSchedule { .. } | ProposalAction::SetFeeCollector { .. } => {
+                Role::FeeManager
+            }
+            ProposalAction::Halt
+            | ProposalAction::Pause { .. }
+            | ProposalAction::Unpause { .. } => Role::Pauser,
+            _ => Role::Admin,
+        }
+    }
+
+    /// Check the action when it is proposed and again when it is executed, as the state may
+    /// have changed in between.
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            ProposalAction::SetFeeSchedule { schedule } => schedule.validate(),
+            ProposalAction::RevokeRole { role, principal } => {
+                Proposals::validate_revoke(*role, principal)
+            }
+            ProposalAction::SetProposalConfig { config } => Proposals::validate_config(config),
+            _ => Ok(()),
+        }
+    }
+
+    fn execute(self) {
+        match self {
+            ProposalAction::SetFeeSchedule { schedule } => FeeSchedule::set(schedule),
+            ProposalAction::SetFeeCollector { account } => FeeCollector::set(account),
+            ProposalAction::GrantRole { role, principal } => Roles::grant(role, principal),
+            ProposalAction::RevokeRole { role, principal } => Roles::revoke(role, principal),
+            ProposalAction::Halt => PauseFlags::halt(),
+            ProposalAction::Pause { targets, reason } => PauseFlags::pause(targets, reason),
+            ProposalAction::Unpause { targets } => PauseFlags::unpause(targets),
+            ProposalAction::SetProposalConfig { config } => Proposals::set_config(config),
+            ProposalAction::SetIcpConfig { config } => IcpConfig::set(config),
+            ProposalAction::SetIcpRateMaxAge { max_age } => IcpRateCache::set_max_age(max_age),
+            ProposalAction::ProposeController { controller } => {
+                PendingController::propose(controller)
+            }
+        }
+    }
+}
+
+/// Change the approval threshold and the timelock, once the threshold is non-zero this can
+/// only be done with a proposal. The threshold can not exceed the number of admins.
+#[update]
+pub fn set_proposal_config(config: ProposalConfig) -> Result<(), String> {
+    Roles::guard(Role::Admin);
+    Proposals::guard_direct();
+
+    Proposals::validate_config(&config)?;
+    Proposals::set_config(config);
+    Ok(())
+}
+
+#[query]
+pub fn get_proposal_config() -> ProposalConfig {
+    let ic = get_context();
+    ic.get::<Proposals>().config
+}
+
+/// Queue an action of one of the roles of the caller, the proposer approves it right away.
+#[update]
+pub fn create_proposal(action: ProposalAction) -> Result<ProposalId, String> {
+    Roles::guard(action.role());
+    action.validate()?;
+
+    let ic = get_context();
+    let now = ic.time();
+    let proposals = ic.get_mut::<Proposals>();
+    proposals.prune(now);
+    if proposals.proposals.len() >= MAX_PROPOSALS {
+        return Err("Too many pending proposals.".into());
+    }
+
+    let id = proposals.next_id;
+    proposals.next_id += 1;
+    proposals.proposals.push(Proposal {
+        id,
+        proposer: ic.caller(),
+        action,
+        created_at: now,
+        approvals: vec![ic.caller()],
+        expires_at: now
+            .saturating_add(proposals.config.timelock)
+            .saturating_add(PROPOSAL_EXPIRY),
+    });
+    proposals.save();
+
+    Ok(id)
+}
+
+#[update]
+pub fn approve_proposal(id: ProposalId) -> Result<(), String> {
+    Roles::guard(Role::Admin);
+
+    let ic = get_context();
+    let caller = ic.caller();
+    let proposals = ic.get_mut::<Proposals>();
+    let index = proposals.position(id, ic.time())?;
+    let proposal = &mut proposals.proposals[index];
+    if !proposal.approvals.contains(&caller) {
+        proposal.approvals.push(caller);
+    }
+    proposals.save();
+
+    Ok(())
+}
+
+/// Execute a proposal approved by enough admins once its timelock has passed, the approvals
+/// of the principals that are not admins are not counted.
+#[update]
+pub fn execute_proposal(id: ProposalId) -> Result<(), String> {
+    let ic = get_context();
+    let now = ic.time();
+    let proposals = ic.get_mut::<Proposals>();
+    let threshold = proposals.config.threshold.max(1) as usize;
+    let timelock = proposals.config.timelock;
+    let index = proposals.position(id, now)?;
+    let proposal = &proposals.proposals[index];
+    Roles::guard(proposal.action.role());
+
+    let approvals = proposal
+        .approvals
+        .iter()
+        .filter(|principal| Roles::has_role(principal, Role::Admin))
+        .count();
+    if approvals < threshold {
+        return Err(format!(
+            "The proposal has {} of the {} required approvals.",
+            approvals, threshold
+        ));
+    }
+
+    if now < proposal.created_at.saturating_add(timelock) {
+        return Err("The timelock of the proposal has not passed yet.".into());
+    }
+
+    proposal.action.validate()?;
+    let action = proposals.proposals.remove(index).action;
+    proposals.save();
+    AuditLog::record(AdminAction::ExecuteProposal { id });
+    action.execute();
+
+    Ok(())
+}
+
+/// Drop a pending proposal, only its proposer or an admin can cancel it.
+#[update]
+pub fn cancel_proposal(id: ProposalId) -> Result<(), String> {
+    let ic = get_context();
+    let caller = ic.caller();
+    let proposals = ic.get_mut::<Proposals>();
+    let index = match proposals
+        .proposals
+        .iter()
+        .position(|proposal| proposal.id == id)
+    {
+        Some(index) => index,
+        None => return Err("The proposal does not exist.".into()),
+    };
+    if proposals.proposals[index].proposer != caller {
+        Roles::guard(Role::Admin);
+    }
+
+    proposals.proposals.remove(index);
+    proposals.save();
+    AuditLog::record(AdminAction::CancelProposal { id });
+
+    Ok(())
+}
+
+/// Return at most limit pending proposals starting at the given id.
+#[query]
+pub fn get_proposals(start: ProposalId, limit: u16) -> Vec<Proposal> {
+    let ic = get_context();
+    ic.get::<Proposals>()
+        .proposals
+        .iter()
+        .filter(|proposal| proposal.id >= start)
+        .take(limit as usize)
+        .cloned()
+        .collect()
+}
@@ -0,0 +1,107 @@
+//! The seeds of the hashes of the maps that live in the stable memory. A map is keyed with the
+//! zero seed when it is created, because the randomness of the IC can only be fetched with an
+//! async call, so the first update that finds a map without a seed fetches a random one.
+
+use crate::canisters::CanisterRegistry;
+use crate::ledger::{Ledger, UsedBlocks};
+use ic_kit::{get_context, Context, Principal};
+use std::convert::TryInto;
+
+/// The state of the task that fetches the seed.
+#[derive(Default)]
+pub struct SeedTask {
+    in_flight: bool,
+}
+
+/// Return true if one of the maps is still keyed with the zero seed.
+#[cfg(not(test))]
+fn needs_seed() -> bool {
+    let ic = get_context();
+    !ic.get::<Ledger>().is_seeded()
+        || !ic.get::<UsedBlocks>().is_seeded()
+        || !ic.get::<CanisterRegistry>().is_seeded()
+}
+
+// The maps of the tests keep the zero seed, so the tests do not have to mock raw_rand.
+#[cfg(test)]
+fn needs_seed() -> bool {
+    false
+}
+
+/// Fetch a seed if one of the maps does not have one yet, returns whether an async call was
+/// performed.
+#[inline]
+pub async fn progress() -> bool {
+    needs_seed() && seed_maps().await
+}
+
+/// Fetch a random seed from the management canister and key the maps that do not have a seed
+/// yet with it. A map that is still being rehashed is seeded by a later call.
+async fn seed_maps() -> bool {
+    let ic = get_context();
+    if ic.get::<SeedTask>().in_flight {
+        return false;
+    }
+
+    ic.get_mut::<SeedTask>().in_flight = true;
+    let result: Result<(Vec<u8>,), _> = ic
+        .call(Principal::management_canister(), "raw_rand", ())
+        .await;
+    ic.get_mut::<SeedTask>().in_flight = false;
+
+    let seed: [u8; 16] = match result {
+        Ok((bytes,)) if bytes.len() >= 16 => bytes[..16].try_into().unwrap(),
+        _ => return true,
+    };
+
+    let ledger = ic.get_mut::<Ledger>();
+    if !ledger.is_seeded() {
+        ledger.reseed(seed);
+    }
+
+    ic.get_mut::<UsedBlocks>().reseed(seed);
+    ic.get_mut::<CanisterRegistry>().reseed(seed);
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common_types::Account;
+    use ic_kit::candid::encode_args;
+    use ic_kit::{async_test, mock_principals, MockContext, RawHandler};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[async_test]
+    async fn seed_maps_with_raw_rand() {
+        let calls = Rc::new(RefCell::new(0));
+        let handler_calls = calls.clone();
+        let ctx = MockContext::new().inject();
+        ctx.use_handler(RawHandler::raw(Box::new(move |_, _, _, method| {
+            assert_eq!(method, "raw_rand");
+            *handler_calls.borrow_mut() += 1;
+            Ok(encode_args((vec![7u8; 32],)).unwrap())
+        })));
+
+        ctx.store(Ledger::default());
+        ctx.store(UsedBlocks::default());
+        ctx.store(CanisterRegistry::default());
+        let alice: Account = mock_principals::alice().into();
+        ctx.get_mut::<Ledger>().deposit(&alice, 1_000);
+        assert!(!ctx.get::<Ledger>().is_seeded());
+
+        assert!(seed_maps().await);
+        assert_eq!(*calls.borrow(), 1);
+        assert!(ctx.get::<Ledger>().is_seeded());
+        assert!(ctx.get::<UsedBlocks>().is_seeded());
+        assert!(ctx.get::<CanisterRegistry>().is_seeded());
+        assert_eq!(ctx.get::<Ledger>().balance(&alice), 1_000);
+
+        // A call in flight is not started again.
+        ctx.get_mut::<SeedTask>().in_flight = true;
+        assert!(!seed_maps().await);
+        assert_eq!(*calls.borrow(), 1);
+    }
+}
@@ -128,16 +128,10 @@ fn total_supply() -> Nat {
     StatsData::get().supply
 }
 
+/// The number of events in the history, including the fee credits which are not counted in
+/// the usage statistics. The fee credits are reported with the feeCollected operation.
 #[query(name = "historySize")]
 fn history_size() -> Nat {
-    let stats_data = StatsData::get();
-    Nat::from(
-        stats_data.transfers_count
-            + stats_data.transfers_from_count
-            + stats_data.approvals_count
-            + stats_data.mints_count
-            + stats_data.burns_count
-            + stats_data.proxy_calls_count
-            + stats_data.canisters_created_count,
-    )
+    let ic = get_context();
+    Nat::from(ic.get::<HistoryBuffer>().len())
 }
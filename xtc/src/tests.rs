@@ -1,25 +1,28 @@
-use crate::fee::compute_fee;
+use crate::fee::{compute_fee, FeeOperation};
 use crate::ledger::Ledger;
-use ic_kit::candid::{CandidType, Nat};
+use ic_kit::candid::{encode_args, CandidType, Nat};
 use ic_kit::interfaces::management::WithCanisterId;
 use ic_kit::{async_test, Context, MockContext, Principal, RejectionCode};
 use ic_kit::{mock_principals, Method, RawHandler};
+use std::cell::RefCell;
 use std::fmt::Debug;
 use std::future::Future;
 use std::pin::Pin;
+use std::rc::Rc;
 
 fn reset_ledger(ctx: &mut MockContext) {
     let ledger = ctx.get_mut::<Ledger>();
-    ledger.deposit(&Principal::anonymous(), 3 * 10_000_000_000_000);
+    ledger.deposit(&Principal::anonymous().into(), 3 * 10_000_000_000_000);
     ledger.load(vec![
-        (mock_principals::alice(), 10_000_000_000_000),
-        (mock_principals::bob(), 10_000_000_000_000),
-        (mock_principals::john(), 10_000_000_000_000),
+        (mock_principals::alice().into(), 10_000_000_000_000),
+        (mock_principals::bob().into(), 10_000_000_000_000),
+        (mock_principals::john().into(), 10_000_000_000_000),
     ]);
 }
 
 /// General method to test the behaviour of fee implementation of methods.
 async fn test_with_call_fee<T: CandidType + Clone, O, E: Debug>(
+    operation: FeeOperation,
     response: T,
     cb: Box<dyn Fn(u64) -> Pin<Box<dyn Future<Output = Result<O, E>>>>>,
 ) {
@@ -39,8 +42,9 @@ async fn test_with_call_fee<T: CandidType + Clone, O, E: Debug>(
     ctx.call_state_reset();
 
     assert_eq!(
-        ctx.get::<Ledger>().balance(&mock_principals::alice()),
-        10_000_000_000_000 - 0 - compute_fee(0)
+        ctx.get::<Ledger>()
+            .balance(&mock_principals::alice().into()),
+        10_000_000_000_000 - 0 - compute_fee(operation, 0)
     );
 
     // Consumes all
@@ -49,8 +53,9 @@ async fn test_with_call_fee<T: CandidType + Clone, O, E: Debug>(
     ctx.call_state_reset();
 
     assert_eq!(
-        ctx.get::<Ledger>().balance(&mock_principals::alice()),
-        10_000_000_000_000 - 1_000 - compute_fee(1_000)
+        ctx.get::<Ledger>()
+            .balance(&mock_principals::alice().into()),
+        10_000_000_000_000 - 1_000 - compute_fee(operation, 1_000)
     );
 
     // With refund.
@@ -59,8 +64,9 @@ async fn test_with_call_fee<T: CandidType + Clone, O, E: Debug>(
     ctx.call_state_reset();
 
     assert_eq!(
-        ctx.get::<Ledger>().balance(&mock_principals::alice()),
-        10_000_000_000_000 - 2_000 - compute_fee(2_000)
+        ctx.get::<Ledger>()
+            .balance(&mock_principals::alice().into()),
+        10_000_000_000_000 - 2_000 - compute_fee(operation, 2_000)
     );
 
     // With error.
@@ -76,8 +82,9 @@ async fn test_with_call_fee<T: CandidType + Clone, O, E: Debug>(
     ctx.call_state_reset();
 
     assert_eq!(
-        ctx.get::<Ledger>().balance(&mock_principals::alice()),
-        10_000_000_000_000 - compute_fee(10_000)
+        ctx.get::<Ledger>()
+            .balance(&mock_principals::alice().into()),
+        10_000_000_000_000 - compute_fee(operation, 10_000)
     );
 }
 
@@ -85,6 +92,7 @@ async fn test_with_call_fee<T: CandidType + Clone, O, E: Debug>(
 async fn wallet_call_fee() {
     use crate::cycles_wallet::*;
     test_with_call_fee(
+        FeeOperation::ProxyCall,
         (),
         Box::new(|cycles| {
             Box::pin(async move {
@@ -93,6 +101,7 @@ async fn wallet_call_fee() {
                     method_name: "xxx".to_string(),
                     args: vec![],
                     cycles,
+                    from_subaccount: None,
                 })
                 .await
             })
@@ -101,10 +110,39 @@ async fn wallet_call_fee() {
     .await;
 }
 
+#[async_test]
+async fn wallet_call_long_method_name() {
+    use crate::cycles_wallet::*;
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+
+    reset_ledger(ctx);
+    ctx.use_handler(Method::new().response(()));
+
+    call(CallCanisterArgs {
+        canister: mock_principals::john(),
+        method_name: "x".repeat(129),
+        args: vec![],
+        cycles: 1_000,
+        from_subaccount: None,
+    })
+    .await
+    .err()
+    .expect("Expected Err response.");
+
+    assert_eq!(
+        ctx.get::<Ledger>()
+            .balance(&mock_principals::alice().into()),
+        10_000_000_000_000
+    );
+}
+
 #[async_test]
 async fn create_canister_fee() {
     use crate::cycles_wallet::*;
     test_with_call_fee(
+        FeeOperation::CreateCanister,
         WithCanisterId {
             canister_id: mock_principals::xtc(),
         },
@@ -113,6 +151,72 @@ async fn create_canister_fee() {
                 create_canister(CreateCanisterArgs {
                     cycles,
                     controller: None,
+                    settings: None,
+                    from_subaccount: None,
+                })
+                .await
+            })
+        }),
+    )
+    .await;
+}
+
+#[async_test]
+async fn wallet_call128_fee() {
+    use crate::cycles_wallet::*;
+    test_with_call_fee(
+        FeeOperation::ProxyCall,
+        (),
+        Box::new(|cycles| {
+            Box::pin(async move {
+                wallet_call128(CallCanisterArgs128 {
+                    canister: mock_principals::john(),
+                    method_name: "xxx".to_string(),
+                    args: vec![],
+                    cycles: cycles as u128,
+                    from_subaccount: None,
+                })
+                .await
+            })
+        }),
+    )
+    .await;
+}
+
+#[async_test]
+async fn create_canister128_fee() {
+    use crate::cycles_wallet::*;
+    test_with_call_fee(
+        FeeOperation::CreateCanister,
+        WithCanisterId {
+            canister_id: mock_principals::xtc(),
+        },
+        Box::new(|cycles| {
+            Box::pin(async move {
+                wallet_create_canister128(CreateCanisterArgs128 {
+                    cycles: cycles as u128,
+                    settings: WalletCanisterSettings::default(),
+                    from_subaccount: None,
+                })
+                .await
+            })
+        }),
+    )
+    .await;
+}
+
+#[async_test]
+async fn send128_fee() {
+    use crate::cycles_wallet::*;
+    test_with_call_fee(
+        FeeOperation::Burn,
+        (),
+        Box::new(|cycles| {
+            Box::pin(async move {
+                wallet_send128(SendCyclesArgs128 {
+                    canister: mock_principals::xtc(),
+                    amount: cycles as u128,
+                    from_subaccount: None,
                 })
                 .await
             })
@@ -121,16 +225,41 @@ async fn create_canister_fee() {
     .await;
 }
 
+#[async_test]
+async fn wallet_api128() {
+    use crate::cycles_wallet::*;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+    reset_ledger(ctx);
+
+    assert_eq!(wallet_api_version(), "0.2.0");
+    assert_eq!(wallet_balance128().amount, 10_000_000_000_000);
+
+    wallet_send128(SendCyclesArgs128 {
+        canister: mock_principals::xtc(),
+        amount: u64::MAX as u128 + 1,
+        from_subaccount: None,
+    })
+    .await
+    .err()
+    .expect("Expected Err response.");
+    assert_eq!(wallet_balance128().amount, 10_000_000_000_000);
+}
+
 #[async_test]
 async fn send_fee() {
     use crate::cycles_wallet::*;
     test_with_call_fee(
+        FeeOperation::Burn,
         (),
         Box::new(|cycles| {
             Box::pin(async move {
                 wallet_send(SendCyclesArgs {
                     canister: mock_principals::xtc(),
                     amount: cycles,
+                    from_subaccount: None,
                 })
                 .await
             })
@@ -143,12 +272,14 @@ async fn send_fee() {
 async fn burn_fee() {
     use crate::ledger::*;
     test_with_call_fee(
+        FeeOperation::Burn,
         (),
         Box::new(|cycles| {
             Box::pin(async move {
                 burn(BurnArguments {
                     canister_id: mock_principals::xtc(),
                     amount: cycles,
+                    from_subaccount: None,
                 })
                 .await
             })
@@ -167,17 +298,18 @@ async fn transfer_fee() {
 
     reset_ledger(ctx);
 
-    transfer(mock_principals::bob(), Nat::from(5000))
+    transfer(mock_principals::bob(), Nat::from(5000), None, None)
         .await
         .expect("Unexpected error.");
 
     assert_eq!(
-        ctx.get::<Ledger>().balance(&mock_principals::alice()),
-        10_000_000_000_000 - 5_000 - compute_fee(5_000)
+        ctx.get::<Ledger>()
+            .balance(&mock_principals::alice().into()),
+        10_000_000_000_000 - 5_000 - compute_fee(FeeOperation::Transfer, 5_000)
     );
 
     assert_eq!(
-        ctx.get::<Ledger>().balance(&mock_principals::bob()),
+        ctx.get::<Ledger>().balance(&mock_principals::bob().into()),
         10_000_000_000_000 + 5_000
     );
 }
@@ -193,17 +325,18 @@ async fn transfer_fee_zero() {
 
     reset_ledger(ctx);
 
-    transfer(mock_principals::bob(), Nat::from(0))
+    transfer(mock_principals::bob(), Nat::from(0), None, None)
         .await
         .expect("Unexpected error.");
 
     assert_eq!(
-        ctx.get::<Ledger>().balance(&mock_principals::alice()),
-        10_000_000_000_000 - 0 - compute_fee(0)
+        ctx.get::<Ledger>()
+            .balance(&mock_principals::alice().into()),
+        10_000_000_000_000 - 0 - compute_fee(FeeOperation::Transfer, 0)
     );
 
     assert_eq!(
-        ctx.get::<Ledger>().balance(&mock_principals::bob()),
+        ctx.get::<Ledger>().balance(&mock_principals::bob().into()),
         10_000_000_000_000 + 0
     );
 }
@@ -217,12 +350,1673 @@ async fn mint_fee() {
         .with_msg_cycles(50_000_000_000)
         .inject();
 
-    mint(mock_principals::alice(), Nat::from(0))
+    mint(mock_principals::alice(), Nat::from(0), None)
+        .await
+        .expect("Unexpected error.");
+
+    assert_eq!(
+        ctx.get::<Ledger>()
+            .balance(&mock_principals::alice().into()),
+        50_000_000_000 - compute_fee(FeeOperation::Mint, 50_000_000_000)
+    );
+}
+
+#[async_test]
+async fn mint_exact_amount() {
+    use crate::common_types::TxError;
+    use crate::ledger::*;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .with_msg_cycles(50_000_000_000)
+        .inject();
+
+    let fee = compute_fee(FeeOperation::Mint, 10_000_000_000);
+    mint(mock_principals::alice(), Nat::from(10_000_000_000u64), None)
+        .await
+        .expect("Unexpected error.");
+
+    assert_eq!(
+        ctx.get::<Ledger>()
+            .balance(&mock_principals::alice().into()),
+        10_000_000_000
+    );
+    // The excess is not accepted and goes back to the caller.
+    assert_eq!(
+        ctx.msg_cycles_available(),
+        50_000_000_000 - 10_000_000_000 - fee
+    );
+
+    assert_eq!(
+        mint(mock_principals::alice(), Nat::from(50_000_000_000u64), None).await,
+        Err(TxError::InsufficientCycles)
+    );
+    assert_eq!(
+        mint(mock_principals::alice(), Nat::from(u128::MAX), None).await,
+        Err(TxError::InsufficientCycles)
+    );
+}
+
+#[async_test]
+async fn mint_without_enough_cycles() {
+    use crate::common_types::TxError;
+    use crate::ledger::*;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .with_msg_cycles(1_000)
+        .inject();
+
+    assert_eq!(
+        mint(mock_principals::alice(), Nat::from(0), None).await,
+        Err(TxError::InsufficientCycles)
+    );
+    assert_eq!(ctx.msg_cycles_available(), 1_000);
+}
+
+#[async_test]
+async fn icrc1_transfer_fee() {
+    use crate::common_types::Account;
+    use crate::icrc1::*;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+
+    reset_ledger(ctx);
+
+    let to = Account {
+        owner: mock_principals::bob(),
+        subaccount: None,
+    };
+
+    assert_eq!(
+        icrc1_transfer(TransferArg {
+            from_subaccount: None,
+            to,
+            amount: Nat::from(5000),
+            fee: Some(Nat::from(1)),
+            memo: None,
+            created_at_time: None,
+        })
+        .await,
+        Err(TransferError::BadFee {
+            expected_fee: Nat::from(compute_fee(FeeOperation::Transfer, 5_000))
+        })
+    );
+
+    icrc1_transfer(TransferArg {
+        from_subaccount: None,
+        to,
+        amount: Nat::from(5000),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    })
+    .await
+    .expect("Unexpected error.");
+
+    assert_eq!(
+        ctx.get::<Ledger>()
+            .balance(&mock_principals::alice().into()),
+        10_000_000_000_000 - 5_000 - compute_fee(FeeOperation::Transfer, 5_000)
+    );
+
+    assert_eq!(
+        ctx.get::<Ledger>().balance(&mock_principals::bob().into()),
+        10_000_000_000_000 + 5_000
+    );
+}
+
+#[async_test]
+async fn icrc1_transfer_dedup() {
+    use crate::common_types::Account;
+    use crate::icrc1::*;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+
+    reset_ledger(ctx);
+
+    let transfer_arg = |created_at_time: Option<u64>| TransferArg {
+        from_subaccount: None,
+        to: Account::new(mock_principals::bob(), None),
+        amount: Nat::from(5000),
+        fee: None,
+        memo: None,
+        created_at_time,
+    };
+
+    let now = ctx.time();
+    let id = icrc1_transfer(transfer_arg(Some(now)))
+        .await
+        .expect("Unexpected error.");
+    assert_eq!(
+        icrc1_transfer(transfer_arg(Some(now))).await,
+        Err(TransferError::Duplicate { duplicate_of: id })
+    );
+
+    // The requests without a created_at_time are not deduplicated.
+    icrc1_transfer(transfer_arg(None))
+        .await
+        .expect("Unexpected error.");
+    icrc1_transfer(transfer_arg(None))
+        .await
+        .expect("Unexpected error.");
+
+    assert_eq!(
+        ctx.get::<Ledger>().balance(&mock_principals::bob().into()),
+        10_000_000_000_000 + 3 * 5_000
+    );
+
+    assert!(matches!(
+        icrc1_transfer(transfer_arg(Some(u64::MAX))).await,
+        Err(TransferError::CreatedInFuture { .. })
+    ));
+}
+
+#[async_test]
+async fn icrc2_approve_and_transfer_from() {
+    use crate::icrc2::*;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+
+    reset_ledger(ctx);
+
+    let approve_args =
+        |amount: u64, expected_allowance: Option<u64>, expires_at: Option<u64>| ApproveArgs {
+            from_subaccount: None,
+            spender: mock_principals::bob().into(),
+            amount: Nat::from(amount),
+            expected_allowance: expected_allowance.map(Nat::from),
+            expires_at,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        };
+
+    assert_eq!(
+        icrc2_approve(approve_args(10_000, None, Some(0))).await,
+        Err(ApproveError::Expired {
+            ledger_time: ctx.time()
+        })
+    );
+
+    icrc2_approve(approve_args(10_000, Some(0), Some(u64::MAX)))
+        .await
+        .expect("Unexpected error.");
+
+    assert_eq!(
+        icrc2_approve(approve_args(20_000, Some(0), None)).await,
+        Err(ApproveError::AllowanceChanged {
+            current_allowance: Nat::from(10_000)
+        })
+    );
+
+    assert_eq!(
+        ctx.get::<Ledger>().allowance(
+            &mock_principals::alice().into(),
+            &mock_principals::bob().into()
+        ),
+        10_000
+    );
+}
+
+#[async_test]
+async fn icrc2_transfer_from_fee() {
+    use crate::icrc2::*;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::bob())
+        .inject();
+
+    reset_ledger(ctx);
+
+    ctx.get_mut::<Ledger>().set_allowance(
+        &mock_principals::alice().into(),
+        &mock_principals::bob().into(),
+        5_000 + compute_fee(FeeOperation::TransferFrom, 5_000),
+        None,
+    );
+
+    let transfer_from_args = |amount: u64| TransferFromArgs {
+        spender_subaccount: None,
+        from: mock_principals::alice().into(),
+        to: mock_principals::john().into(),
+        amount: Nat::from(amount),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+
+    assert_eq!(
+        icrc2_transfer_from(transfer_from_args(6_000)).await,
+        Err(TransferFromError::InsufficientAllowance {
+            allowance: Nat::from(5_000 + compute_fee(FeeOperation::TransferFrom, 5_000))
+        })
+    );
+
+    icrc2_transfer_from(transfer_from_args(5_000))
+        .await
+        .expect("Unexpected error.");
+
+    assert_eq!(
+        ctx.get::<Ledger>()
+            .balance(&mock_principals::alice().into()),
+        10_000_000_000_000 - 5_000 - compute_fee(FeeOperation::TransferFrom, 5_000)
+    );
+    assert_eq!(
+        ctx.get::<Ledger>().balance(&mock_principals::john().into()),
+        10_000_000_000_000 + 5_000
+    );
+    assert_eq!(
+        ctx.get::<Ledger>().allowance(
+            &mock_principals::alice().into(),
+            &mock_principals::bob().into()
+        ),
+        0
+    );
+}
+
+#[test]
+fn upgrade_keeps_allowances() {
+    use crate::common_types::Account;
+    use crate::management::Controller;
+    use crate::upgrade::{post_upgrade, pre_upgrade};
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+
+    reset_ledger(ctx);
+    Controller::load(mock_principals::alice());
+
+    let alice: Account = mock_principals::alice().into();
+    let bob: Account = mock_principals::bob().into();
+    let john: Account = mock_principals::john().into();
+
+    let ledger = ctx.get_mut::<Ledger>();
+    assert_eq!(ledger.approve(&alice, &bob, 1_000, 10), Ok(()));
+    ledger.set_allowance(&alice, &john, 2_000, Some(u64::MAX));
+
+    pre_upgrade();
+    // Drop the state on the heap, the balances stay in the stable memory.
+    ctx.store(Ledger::restore());
+    post_upgrade();
+
+    let ledger = ctx.get::<Ledger>();
+    assert_eq!(ledger.allowance(&alice, &bob), 1_010);
+    assert_eq!(ledger.allowance_expiration(&alice, &bob), None);
+    assert_eq!(ledger.allowance(&alice, &john), 2_000);
+    assert_eq!(ledger.allowance_expiration(&alice, &john), Some(u64::MAX));
+    assert_eq!(ledger.balance(&alice), 10_000_000_000_000 - 10);
+    assert_eq!(ledger.balance(&bob), 10_000_000_000_000);
+}
+
+#[async_test]
+async fn fee_collector() {
+    use crate::common_types::Account;
+    use crate::fee::*;
+    use crate::history::HistoryBuffer;
+    use crate::ledger::*;
+    use crate::management::Controller;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+
+    reset_ledger(ctx);
+    Controller::load(mock_principals::alice());
+
+    // Without a collector the fee is burned.
+    transfer(mock_principals::bob(), Nat::from(1_000), None, None)
+        .await
+        .expect("Unexpected error.");
+
+    let collector = Account::new(mock_principals::xtc(), None);
+    set_fee_collector(Some(collector));
+    assert_eq!(get_fee_collector(), Some(collector));
+
+    let id = transfer(mock_principals::bob(), Nat::from(1_000), None, None)
         .await
         .expect("Unexpected error.");
 
     assert_eq!(
-        ctx.get::<Ledger>().balance(&mock_principals::alice()),
-        50_000_000_000 - compute_fee(50_000_000_000)
+        ctx.get::<Ledger>().balance(&collector),
+        compute_fee(FeeOperation::Transfer, 1_000)
+    );
+
+    let id = crate::utils::convert_nat_to_u64(id).unwrap();
+    let event = crate::history::get_transaction(id + 1)
+        .await
+        .expect("Expected the fee credit.");
+    assert_eq!(event.cycles, compute_fee(FeeOperation::Transfer, 1_000));
+    let records = crate::history::get_transactions(Nat::from(id + 1), Nat::from(1));
+    assert!(matches!(
+        records[0].op,
+        crate::common_types::Operation::feeCollected
+    ));
+
+    let report = fee_report();
+    assert_eq!(report.fee_collector, Some(collector));
+    assert_eq!(
+        report.collector_balance,
+        Nat::from(compute_fee(FeeOperation::Transfer, 1_000))
+    );
+    assert_eq!(
+        report.collected,
+        Nat::from(compute_fee(FeeOperation::Transfer, 1_000))
+    );
+    assert_eq!(
+        report.fees,
+        Nat::from(2 * compute_fee(FeeOperation::Transfer, 1_000))
+    );
+    assert_eq!(
+        report.burned,
+        Nat::from(compute_fee(FeeOperation::Transfer, 1_000))
+    );
+
+    // The fee kept by a failed burn is not credited to the collector.
+    ctx.use_handler(RawHandler::raw(Box::new(|_, _, _, _| {
+        Err((RejectionCode::CanisterError, "Rejected.".into()))
+    })));
+    let history_size = ctx.get::<HistoryBuffer>().len();
+    burn(BurnArguments {
+        canister_id: mock_principals::john(),
+        amount: 1_000,
+        from_subaccount: None,
+    })
+    .await
+    .expect_err("Expected the burn to fail.");
+
+    assert_eq!(ctx.get::<HistoryBuffer>().len(), history_size + 1);
+    let report = fee_report();
+    assert_eq!(
+        report.collected,
+        Nat::from(compute_fee(FeeOperation::Transfer, 1_000))
+    );
+    assert_eq!(
+        report.burned,
+        Nat::from(
+            compute_fee(FeeOperation::Transfer, 1_000) + compute_fee(FeeOperation::Burn, 1_000)
+        )
     );
 }
+
+#[test]
+#[should_panic]
+fn set_fee_collector_controller_only() {
+    use crate::fee::*;
+    use crate::management::Controller;
+
+    MockContext::new()
+        .with_caller(mock_principals::bob())
+        .inject();
+
+    Controller::load(mock_principals::alice());
+    set_fee_collector(Some(mock_principals::bob().into()));
+}
+
+#[async_test]
+async fn fee_schedule_per_operation() {
+    use crate::fee::*;
+    use crate::ledger::*;
+    use crate::management::Controller;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+
+    reset_ledger(ctx);
+    Controller::load(mock_principals::alice());
+
+    assert!(set_fee_schedule(FeeSchedule {
+        default: FeeRule::Flat(0),
+        operations: vec![],
+    })
+    .is_err());
+
+    let percentage = FeeRule::Percentage {
+        basis_points: 100,
+        min: 10,
+        max: 500,
+    };
+
+    // The ICRC operations report a single fee.
+    assert!(set_fee_schedule(FeeSchedule {
+        default: FeeRule::Flat(1_000),
+        operations: vec![(FeeOperation::Transfer, percentage.clone())],
+    })
+    .is_err());
+
+    set_fee_schedule(FeeSchedule {
+        default: FeeRule::Flat(1_000),
+        operations: vec![
+            (FeeOperation::Transfer, FeeRule::Flat(100)),
+            (FeeOperation::Mint, percentage),
+        ],
+    })
+    .expect("Unexpected error.");
+
+    assert_eq!(compute_fee(FeeOperation::Approve, 10_000), 1_000);
+    assert_eq!(compute_fee(FeeOperation::Transfer, 10_000), 100);
+    assert_eq!(compute_fee(FeeOperation::Mint, 10_000), 100);
+    assert_eq!(compute_fee(FeeOperation::Mint, 100_000), 500);
+
+    transfer(mock_principals::bob(), Nat::from(10_000), None, None)
+        .await
+        .expect("Unexpected error.");
+
+    assert_eq!(
+        ctx.get::<Ledger>()
+            .balance(&mock_principals::alice().into()),
+        10_000_000_000_000 - 10_000 - 100
+    );
+    assert_eq!(get_fee_schedule().default, FeeRule::Flat(1_000));
+}
+
+#[test]
+fn roles() {
+    use crate::fee::*;
+    use crate::management::*;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+
+    Controller::load(mock_principals::alice());
+    grant_role(Role::FeeManager, mock_principals::bob());
+    grant_role(Role::Admin, mock_principals::john());
+
+    assert!(Roles::has_role(&mock_principals::alice(), Role::Pauser));
+    assert!(Roles::has_role(&mock_principals::bob(), Role::FeeManager));
+    assert!(!Roles::has_role(&mock_principals::bob(), Role::Pauser));
+    assert!(Roles::has_role(&mock_principals::john(), Role::Operator));
+
+    ctx.update_caller(mock_principals::bob());
+    set_fee_collector(Some(mock_principals::bob().into()));
+
+    ctx.update_caller(mock_principals::john());
+    revoke_role(Role::FeeManager, mock_principals::bob());
+    assert!(!Roles::has_role(&mock_principals::bob(), Role::FeeManager));
+    assert_eq!(
+        list_roles(),
+        vec![(Role::Admin, vec![mock_principals::john()])]
+    );
+
+    let log = get_audit_log(0, 10);
+    assert_eq!(log.len(), 4);
+    assert_eq!(log[2].caller, mock_principals::bob());
+    assert_eq!(
+        log[3].action,
+        AdminAction::RevokeRole {
+            role: Role::FeeManager,
+            principal: mock_principals::bob()
+        }
+    );
+    assert_eq!(get_audit_log(3, 10).len(), 1);
+}
+
+#[test]
+#[should_panic]
+fn grant_role_admin_only() {
+    use crate::management::*;
+
+    MockContext::new()
+        .with_caller(mock_principals::bob())
+        .inject();
+
+    Controller::load(mock_principals::alice());
+    grant_role(Role::Admin, mock_principals::bob());
+}
+
+#[async_test]
+async fn pause_transfers() {
+    use crate::ledger::*;
+    use crate::management::*;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+
+    reset_ledger(ctx);
+    Controller::load(mock_principals::alice());
+    pause(vec![PauseTarget::WalletCall], Some("Incident".into()));
+
+    // Pausing the proxy calls does not freeze the transfers.
+    transfer(mock_principals::bob(), Nat::from(1_000), None, None)
+        .await
+        .expect("Unexpected error.");
+
+    let status = pause_status();
+    assert_eq!(status.len(), PauseTarget::ALL.len());
+    assert!(status.contains(&PauseStatus {
+        target: PauseTarget::WalletCall,
+        paused: true,
+        reason: Some("Incident".into()),
+    }));
+    assert!(!PauseFlags::is_paused(PauseTarget::Transfers));
+
+    halt();
+    assert!(PauseFlags::is_paused(PauseTarget::Transfers));
+    unpause(vec![PauseTarget::Transfers]);
+    assert!(!PauseFlags::is_paused(PauseTarget::Transfers));
+    assert!(PauseFlags::is_paused(PauseTarget::Burn));
+}
+
+#[async_test]
+#[should_panic]
+async fn paused_burn() {
+    use crate::ledger::*;
+    use crate::management::*;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+
+    reset_ledger(ctx);
+    Controller::load(mock_principals::alice());
+    pause(vec![PauseTarget::Burn], None);
+
+    burn(BurnArguments {
+        canister_id: mock_principals::xtc(),
+        amount: 1_000,
+        from_subaccount: None,
+    })
+    .await
+    .expect("Unexpected error.");
+}
+
+#[test]
+fn controller_handover() {
+    use crate::management::*;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+
+    Controller::load(mock_principals::alice());
+    propose_controller(Some(mock_principals::bob()));
+    assert_eq!(get_pending_controller(), Some(mock_principals::bob()));
+    assert_eq!(Controller::get_principal(), mock_principals::alice());
+
+    ctx.update_caller(mock_principals::bob());
+    accept_controller();
+    assert_eq!(Controller::get_principal(), mock_principals::bob());
+    assert_eq!(get_pending_controller(), None);
+}
+
+#[test]
+#[should_panic]
+fn accept_controller_not_proposed() {
+    use crate::management::*;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+
+    Controller::load(mock_principals::alice());
+    propose_controller(Some(mock_principals::bob()));
+
+    ctx.update_caller(mock_principals::john());
+    accept_controller();
+}
+
+#[test]
+fn propose_controller_through_proposal() {
+    use crate::management::*;
+    use crate::proposals::*;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+
+    Controller::load(mock_principals::alice());
+    set_proposal_config(ProposalConfig {
+        threshold: 1,
+        timelock: 0,
+    })
+    .expect("Unexpected error.");
+
+    let id = create_proposal(ProposalAction::ProposeController {
+        controller: Some(mock_principals::bob()),
+    })
+    .expect("Unexpected error.");
+    execute_proposal(id).expect("Unexpected error.");
+    assert_eq!(get_pending_controller(), Some(mock_principals::bob()));
+
+    ctx.update_caller(mock_principals::bob());
+    accept_controller();
+    assert_eq!(Controller::get_principal(), mock_principals::bob());
+}
+
+#[test]
+#[should_panic]
+fn propose_controller_requires_proposal() {
+    use crate::management::*;
+    use crate::proposals::*;
+
+    MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+
+    Controller::load(mock_principals::alice());
+    set_proposal_config(ProposalConfig {
+        threshold: 1,
+        timelock: 0,
+    })
+    .expect("Unexpected error.");
+    propose_controller(Some(mock_principals::bob()));
+}
+
+#[test]
+fn admin_proposals() {
+    use crate::common_types::Account;
+    use crate::fee::*;
+    use crate::management::*;
+    use crate::proposals::*;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+
+    Controller::load(mock_principals::alice());
+    grant_role(Role::Admin, mock_principals::bob());
+    grant_role(Role::FeeManager, mock_principals::john());
+
+    // The controller and bob are the only ones able to approve.
+    assert!(set_proposal_config(ProposalConfig {
+        threshold: 3,
+        timelock: 0,
+    })
+    .is_err());
+    set_proposal_config(ProposalConfig {
+        threshold: 2,
+        timelock: 0,
+    })
+    .expect("Unexpected error.");
+
+    let schedule = FeeSchedule {
+        default: FeeRule::Flat(10),
+        operations: vec![],
+    };
+    let id = create_proposal(ProposalAction::SetFeeSchedule {
+        schedule: schedule.clone(),
+    })
+    .expect("Unexpected error.");
+
+    // One approval out of two.
+    assert!(execute_proposal(id).is_err());
+
+    ctx.update_caller(mock_principals::bob());
+    approve_proposal(id).expect("Unexpected error.");
+    execute_proposal(id).expect("Unexpected error.");
+    assert_eq!(get_fee_schedule(), schedule);
+    assert!(execute_proposal(id).is_err());
+    assert!(get_proposals(0, 10).is_empty());
+
+    // Revoking bob would leave a single admin for a threshold of two.
+    assert!(create_proposal(ProposalAction::RevokeRole {
+        role: Role::Admin,
+        principal: mock_principals::bob(),
+    })
+    .is_err());
+
+    // The fee managers propose and execute the fee actions, the admins approve them.
+    let collector = Account::new(mock_principals::john(), None);
+    ctx.update_caller(mock_principals::john());
+    let id = create_proposal(ProposalAction::SetFeeCollector {
+        account: Some(collector),
+    })
+    .expect("Unexpected error.");
+    assert!(execute_proposal(id).is_err());
+    ctx.update_caller(mock_principals::alice());
+    approve_proposal(id).expect("Unexpected error.");
+    ctx.update_caller(mock_principals::bob());
+    approve_proposal(id).expect("Unexpected error.");
+    ctx.update_caller(mock_principals::john());
+    execute_proposal(id).expect("Unexpected error.");
+    assert_eq!(get_fee_collector(), Some(collector));
+    ctx.update_caller(mock_principals::bob());
+
+    // Raise the timelock, the next proposals cannot be executed before it passes.
+    let config = ProposalConfig {
+        threshold: 1,
+        timelock: u64::MAX,
+    };
+    let id =
+        create_proposal(ProposalAction::SetProposalConfig { config }).expect("Unexpected error.");
+    ctx.update_caller(mock_principals::alice());
+    approve_proposal(id).expect("Unexpected error.");
+    execute_proposal(id).expect("Unexpected error.");
+    assert_eq!(get_proposal_config(), config);
+
+    let id = create_proposal(ProposalAction::Pause {
+        targets: vec![PauseTarget::Mint],
+        reason: None,
+    })
+    .expect("Unexpected error.");
+    assert!(execute_proposal(id).is_err());
+    assert!(!PauseFlags::is_paused(PauseTarget::Mint));
+}
+
+#[test]
+#[should_panic]
+fn direct_action_requires_proposal() {
+    use crate::management::*;
+    use crate::proposals::*;
+
+    MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+
+    Controller::load(mock_principals::alice());
+    set_proposal_config(ProposalConfig {
+        threshold: 1,
+        timelock: 0,
+    })
+    .expect("Unexpected error.");
+    unpause(vec![PauseTarget::Mint]);
+}
+
+#[test]
+#[should_panic]
+fn pause_requires_proposal() {
+    use crate::management::*;
+    use crate::proposals::*;
+
+    MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+
+    Controller::load(mock_principals::alice());
+    set_proposal_config(ProposalConfig {
+        threshold: 1,
+        timelock: 0,
+    })
+    .expect("Unexpected error.");
+    pause(vec![PauseTarget::Mint], None);
+}
+
+#[test]
+fn halt_through_proposal() {
+    use crate::management::*;
+    use crate::proposals::*;
+
+    MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+
+    Controller::load(mock_principals::alice());
+    set_proposal_config(ProposalConfig {
+        threshold: 1,
+        timelock: 0,
+    })
+    .expect("Unexpected error.");
+
+    let id = create_proposal(ProposalAction::Halt).expect("Unexpected error.");
+    execute_proposal(id).expect("Unexpected error.");
+    assert!(PauseTarget::ALL
+        .iter()
+        .all(|target| PauseFlags::is_paused(*target)));
+}
+
+#[test]
+fn cancel_proposals() {
+    use crate::management::*;
+    use crate::proposals::*;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+
+    Controller::load(mock_principals::alice());
+    Roles::grant(Role::Pauser, mock_principals::bob());
+    set_proposal_config(ProposalConfig {
+        threshold: 1,
+        timelock: 0,
+    })
+    .expect("Unexpected error.");
+
+    // The list of pending proposals is bounded.
+    for _ in 0..MAX_PROPOSALS {
+        create_proposal(ProposalAction::Halt).expect("Unexpected error.");
+    }
+    assert!(create_proposal(ProposalAction::Halt).is_err());
+
+    // An admin cancels any proposal, which frees a slot.
+    cancel_proposal(0).expect("Unexpected error.");
+    assert!(execute_proposal(0).is_err());
+    assert!(cancel_proposal(0).is_err());
+    let id = create_proposal(ProposalAction::Halt).expect("Unexpected error.");
+    assert_eq!(id, MAX_PROPOSALS as u64);
+    cancel_proposal(id).expect("Unexpected error.");
+
+    // The other role holders only cancel their own proposals.
+    ctx.update_caller(mock_principals::bob());
+    let id = create_proposal(ProposalAction::Halt).expect("Unexpected error.");
+    cancel_proposal(id).expect("Unexpected error.");
+    assert!(get_proposals(id, 1).is_empty());
+}
+
+#[test]
+#[should_panic]
+fn cancel_proposal_of_another_principal() {
+    use crate::management::*;
+    use crate::proposals::*;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+
+    Controller::load(mock_principals::alice());
+    Roles::grant(Role::Pauser, mock_principals::bob());
+    let id = create_proposal(ProposalAction::Halt).expect("Unexpected error.");
+
+    ctx.update_caller(mock_principals::bob());
+    let _ = cancel_proposal(id);
+}
+
+#[test]
+fn icp_config() {
+    use crate::icp_mint::*;
+    use crate::management::Controller;
+
+    MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+
+    Controller::load(mock_principals::alice());
+    assert_eq!(get_icp_config(), IcpConfig::default());
+
+    let config = IcpConfig {
+        ledger: mock_principals::bob(),
+        cycles_minting_canister: mock_principals::john(),
+        icp_fee: 1,
+    };
+    set_icp_config(config);
+    assert_eq!(get_icp_config(), config);
+}
+
+#[test]
+fn icp_rate_max_age() {
+    use crate::icp_mint::*;
+    use crate::management::Controller;
+
+    MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+
+    Controller::load(mock_principals::alice());
+    assert_eq!(get_icp_rate(), IcpRateCache::default());
+
+    set_icp_rate_max_age(60_000_000_000);
+    assert_eq!(get_icp_rate().max_age, 60_000_000_000);
+}
+
+#[test]
+fn used_blocks_pruning() {
+    use crate::ledger::*;
+    use crate::management::Controller;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+    Controller::load(mock_principals::alice());
+
+    let now = ctx.time();
+    let used_blocks = ctx.get_mut::<UsedBlocks>();
+    assert!(used_blocks.insert(5, now));
+    assert!(used_blocks.insert(3, now));
+    assert!(used_blocks.insert(9, now));
+    assert!(!used_blocks.insert(5, now));
+    used_blocks.set_send_block(5, 42);
+
+    let heights = |page: UsedBlocksPage| -> Vec<u64> {
+        page.blocks.iter().map(|block| block.block_height).collect()
+    };
+    assert_eq!(get_used_blocks(0, 2).next, Some(2));
+    assert_eq!(heights(get_used_blocks(0, 2)), vec![5, 3]);
+    assert_eq!(get_used_blocks(2, 10).next, None);
+    assert_eq!(heights(get_used_blocks(2, 10)), vec![9]);
+    assert_eq!(get_used_blocks(0, 1).blocks[0].send_block, Some(42));
+
+    // A released block is used again with a new record.
+    let used_blocks = ctx.get_mut::<UsedBlocks>();
+    assert!(used_blocks.remove(&3));
+    assert_eq!(heights(get_used_blocks(0, 10)), vec![5, 9]);
+    assert!(ctx.get_mut::<UsedBlocks>().insert(3, now));
+    assert_eq!(heights(get_used_blocks(0, 10)), vec![5, 9, 3]);
+
+    // Nothing was created before the transaction window yet.
+    assert_eq!(prune_used_blocks(), 0);
+
+    // The pruned blocks are archived, they stay used but are no longer listed.
+    let two_days_later = now + 2 * TRANSACTION_WINDOW;
+    let used_blocks = ctx.get_mut::<UsedBlocks>();
+    assert_eq!(used_blocks.prune(two_days_later, 2), 1);
+    assert_eq!(heights(get_used_blocks(0, 10)), vec![9, 3]);
+    assert!(used_blocks.contains(&5));
+    assert!(!used_blocks.remove(&5));
+    assert!(!used_blocks.insert(5, now));
+    assert_eq!(used_blocks.send_block(&5), Some(42));
+    assert_eq!(used_blocks.prune(two_days_later, 10), 2);
+    assert_eq!(
+        get_used_blocks(0, 10),
+        UsedBlocksPage {
+            blocks: vec![],
+            next: None
+        }
+    );
+    assert_eq!(get_block_used(), vec![3, 5, 9]);
+
+    // The blocks out of the window are pruned as new blocks are used.
+    let used_blocks = ctx.get_mut::<UsedBlocks>();
+    assert!(used_blocks.insert(20, now - TRANSACTION_WINDOW - 1));
+    assert!(used_blocks.insert(21, now));
+    assert!(used_blocks.contains(&20));
+    assert_eq!(heights(get_used_blocks(0, 10)), vec![21]);
+    assert_eq!(get_block_used(), vec![3, 5, 9, 20, 21]);
+}
+
+#[async_test]
+async fn wallet_receive_mints() {
+    use crate::common_types::{Account, Subaccount};
+    use crate::cycles_wallet::*;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .with_msg_cycles(50_000_000_000)
+        .inject();
+
+    wallet_receive(None).await;
+    let fee = compute_fee(FeeOperation::Mint, 50_000_000_000);
+    assert_eq!(
+        ctx.get::<Ledger>()
+            .balance(&mock_principals::alice().into()),
+        50_000_000_000 - fee
+    );
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .with_msg_cycles(20_000_000_000)
+        .inject();
+
+    let to = Account::new(mock_principals::bob(), Some(Subaccount([1; 32])));
+    wallet_receive(Some(ReceiveOptions { to: Some(to) })).await;
+    let fee = compute_fee(FeeOperation::Mint, 20_000_000_000);
+    assert_eq!(ctx.get::<Ledger>().balance(&to), 20_000_000_000 - fee);
+}
+
+#[async_test]
+#[should_panic]
+async fn wallet_receive_below_fee() {
+    use crate::cycles_wallet::*;
+
+    MockContext::new()
+        .with_caller(mock_principals::alice())
+        .with_msg_cycles(1_000)
+        .inject();
+
+    wallet_receive(None).await;
+}
+
+/// A management canister serving the canister bob with the given controllers and 3T cycles, the
+/// drain module sends the given cycles to XTC or fails, it always claims to have sent all of
+/// them. The called methods are recorded in calls.
+fn mock_management(
+    controllers: Vec<Principal>,
+    status: crate::canisters::CanisterStatus,
+    drained: Result<u64, String>,
+    calls: Rc<RefCell<Vec<String>>>,
+) -> RawHandler {
+    use crate::canisters::*;
+
+    RawHandler::raw(Box::new(move |_, _, _, method| {
+        calls.borrow_mut().push(method.to_string());
+        let reply = match method {
+            "canister_status" => encode_args((CanisterStatusResult {
+                status,
+                settings: DefiniteCanisterSettings {
+                    controllers: controllers.clone(),
+                    compute_allocation: Nat::from(0),
+                    memory_allocation: Nat::from(0),
+                    freezing_threshold: Nat::from(2_592_000),
+                },
+                module_hash: None,
+                memory_size: Nat::from(0),
+                cycles: Nat::from(3_000_000_000_000u64),
+            },)),
+            "drain" => {
+                if let Ok(cycles) = &drained {
+                    Drains::credit(&mock_principals::bob(), *cycles);
+                }
+                encode_args((drained
+                    .clone()
+                    .map(|_| (3_000_000_000_000u64, 3_000_000_000_000u64)),))
+            }
+            _ => encode_args(()),
+        };
+        Ok(reply.unwrap())
+    }))
+}
+
+#[async_test]
+async fn manage_canister() {
+    use crate::canisters::*;
+    use crate::history::HistoryBuffer;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+    reset_ledger(ctx);
+
+    let calls = Rc::new(RefCell::new(vec![]));
+    ctx.use_handler(mock_management(
+        vec![mock_principals::alice(), mock_principals::xtc()],
+        CanisterStatus::Running,
+        Ok(2_999_000_000_000),
+        calls.clone(),
+    ));
+    let args = || ManageCanisterArgs {
+        canister_id: mock_principals::bob(),
+        from_subaccount: None,
+    };
+    let fee = compute_fee(FeeOperation::ManageCanister, 0);
+
+    let status = wallet_canister_status(args()).await.unwrap();
+    assert_eq!(status.status, CanisterStatus::Running);
+    wallet_stop_canister(args()).await.unwrap();
+    assert_eq!(
+        *calls.borrow(),
+        vec!["canister_status", "canister_status", "stop_canister"]
+    );
+    assert_eq!(
+        ctx.get::<Ledger>()
+            .balance(&mock_principals::alice().into()),
+        10_000_000_000_000 - 2 * fee
+    );
+
+    // Only the controllers of the canister can manage it, the others are not charged.
+    let history = ctx.get::<HistoryBuffer>().len();
+    calls.borrow_mut().clear();
+    ctx.clear_handlers();
+    ctx.use_handler(mock_management(
+        vec![mock_principals::bob(), mock_principals::xtc()],
+        CanisterStatus::Running,
+        Ok(2_999_000_000_000),
+        calls.clone(),
+    ));
+    wallet_start_canister(args())
+        .await
+        .err()
+        .expect("Expected Err response.");
+    assert_eq!(*calls.borrow(), vec!["canister_status"]);
+    assert_eq!(
+        ctx.get::<Ledger>()
+            .balance(&mock_principals::alice().into()),
+        10_000_000_000_000 - 2 * fee
+    );
+    assert_eq!(ctx.get::<HistoryBuffer>().len(), history);
+}
+
+#[async_test]
+async fn delete_canister_drains_cycles() {
+    use crate::canisters::*;
+    use crate::history::{
+        get_transaction, CanisterAction, HistoryBuffer, TransactionKind, TransactionStatus,
+    };
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+    reset_ledger(ctx);
+
+    let calls = Rc::new(RefCell::new(vec![]));
+    ctx.use_handler(mock_management(
+        vec![mock_principals::alice(), mock_principals::xtc()],
+        CanisterStatus::Stopped,
+        Ok(2_999_000_000_000),
+        calls.clone(),
+    ));
+    let args = || ManageCanisterArgs {
+        canister_id: mock_principals::bob(),
+        from_subaccount: None,
+    };
+
+    // The drained cycles are credited without the mint fee.
+    let fee = compute_fee(FeeOperation::ManageCanister, 0);
+    let refunded = wallet_delete_canister(args()).await.unwrap();
+    assert_eq!(refunded, 2_999_000_000_000);
+    assert_eq!(
+        ctx.get::<Ledger>()
+            .balance(&mock_principals::alice().into()),
+        10_000_000_000_000 - fee + refunded
+    );
+    assert_eq!(
+        *calls.borrow(),
+        vec![
+            "canister_status",
+            "start_canister",
+            "install_code",
+            "drain",
+            "stop_canister",
+            "delete_canister"
+        ]
+    );
+
+    let id = ctx.get::<HistoryBuffer>().len() - 1;
+    let event = get_transaction(id).await.unwrap();
+    assert_eq!(event.cycles, refunded);
+    assert_eq!(
+        event.kind,
+        TransactionKind::CanisterManaged {
+            from: mock_principals::alice(),
+            canister: mock_principals::bob(),
+            action: CanisterAction::DeleteCanister,
+            from_subaccount: None,
+        }
+    );
+
+    // The canister is kept when the drain fails or leaves more than the reserve behind, the
+    // cycles XTC accepted are still credited.
+    for drained in [
+        Err("Out of cycles.".to_string()),
+        Ok(3_000_000_000_000 - DRAIN_RESERVE - 1),
+    ] {
+        let credited = drained.clone().unwrap_or(0);
+        calls.borrow_mut().clear();
+        ctx.clear_handlers();
+        ctx.use_handler(mock_management(
+            vec![mock_principals::alice(), mock_principals::xtc()],
+            CanisterStatus::Running,
+            drained,
+            calls.clone(),
+        ));
+
+        wallet_delete_canister(args())
+            .await
+            .err()
+            .expect("Expected Err response.");
+        assert_eq!(
+            *calls.borrow(),
+            vec!["canister_status", "install_code", "drain"]
+        );
+        let id = ctx.get::<HistoryBuffer>().len() - 1;
+        let event = get_transaction(id).await.unwrap();
+        assert_eq!(event.status, TransactionStatus::FAILED);
+        assert_eq!(event.cycles, credited);
+    }
+}
+
+#[async_test]
+async fn wallet_receive_from_drained_canister() {
+    use crate::canisters::Drains;
+    use crate::common_types::Account;
+    use crate::cycles_wallet::*;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::bob())
+        .with_msg_cycles(50_000_000_000)
+        .inject();
+
+    // The cycles of a drained canister go to the account of the drain without the fee, whatever
+    // the options of the call.
+    let to = Account::new(mock_principals::alice(), None);
+    Drains::start(mock_principals::bob(), to);
+    wallet_receive(Some(ReceiveOptions {
+        to: Some(mock_principals::john().into()),
+    }))
+    .await;
+    assert_eq!(Drains::finish(&mock_principals::bob()), 50_000_000_000);
+    assert_eq!(ctx.get::<Ledger>().balance(&to), 50_000_000_000);
+    assert_eq!(
+        ctx.get::<Ledger>().balance(&mock_principals::john().into()),
+        0
+    );
+}
+
+#[async_test]
+async fn canister_registry() {
+    use crate::canisters::*;
+    use crate::cycles_wallet::*;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+    reset_ledger(ctx);
+
+    ctx.use_handler(
+        Method::new()
+            .response(WithCanisterId {
+                canister_id: mock_principals::bob(),
+            })
+            .cycles_consume(1_000_000),
+    );
+    create_canister(CreateCanisterArgs {
+        cycles: 1_000_000,
+        controller: None,
+        settings: None,
+        from_subaccount: None,
+    })
+    .await
+    .unwrap();
+    ctx.call_state_reset();
+
+    ctx.clear_handlers();
+    ctx.use_handler(Method::new().response(()).cycles_consume(2_000));
+    wallet_send(SendCyclesArgs {
+        canister: mock_principals::bob(),
+        amount: 5_000,
+        from_subaccount: None,
+    })
+    .await
+    .unwrap();
+    ctx.call_state_reset();
+
+    // The cycles sent to the canisters created elsewhere are not recorded.
+    wallet_send(SendCyclesArgs {
+        canister: mock_principals::john(),
+        amount: 5_000,
+        from_subaccount: None,
+    })
+    .await
+    .unwrap();
+    ctx.call_state_reset();
+
+    let canisters = my_canisters();
+    assert_eq!(canisters.len(), 1);
+    assert_eq!(canisters[0].canister_id, mock_principals::bob());
+    assert_eq!(canisters[0].creator, mock_principals::alice());
+
+    let report = canister_cost_report(mock_principals::bob()).unwrap();
+    assert_eq!(report.initial_cycles, 1_000_000);
+    assert_eq!(report.cycles_sent, 2_000);
+    assert_eq!(report.total_cycles, 1_002_000);
+    assert_eq!(canister_cost_report(mock_principals::john()), None);
+
+    // The deleted canisters are forgotten.
+    ctx.clear_handlers();
+    ctx.use_handler(mock_management(
+        vec![mock_principals::alice(), mock_principals::xtc()],
+        CanisterStatus::Stopped,
+        Ok(2_999_000_000_000),
+        Rc::new(RefCell::new(vec![])),
+    ));
+    wallet_delete_canister(ManageCanisterArgs {
+        canister_id: mock_principals::bob(),
+        from_subaccount: None,
+    })
+    .await
+    .unwrap();
+    assert_eq!(my_canisters(), vec![]);
+    assert_eq!(canister_cost_report(mock_principals::bob()), None);
+}
+
+#[async_test]
+async fn create_canister_settings() {
+    use crate::cycles_wallet::*;
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+    reset_ledger(ctx);
+    ctx.use_handler(
+        Method::new()
+            .response(WithCanisterId {
+                canister_id: mock_principals::xtc(),
+            })
+            .cycles_consume(1_000),
+    );
+
+    let create = |controller, settings| {
+        create_canister(CreateCanisterArgs {
+            cycles: 1_000,
+            controller,
+            settings: Some(settings),
+            from_subaccount: None,
+        })
+    };
+
+    create(
+        None,
+        WalletCanisterSettings {
+            controllers: Some(vec![mock_principals::alice(), mock_principals::bob()]),
+            freezing_threshold: Some(Nat::from(2_592_000)),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    ctx.call_state_reset();
+
+    // The management canister takes what the allocations cost from the cycles and refunds the
+    // rest, only the consumed cycles and their fee are charged.
+    let allocations = || WalletCanisterSettings {
+        compute_allocation: Some(Nat::from(1)),
+        memory_allocation: Some(Nat::from(1 << 30)),
+        freezing_threshold: Some(Nat::from(10)),
+        ..Default::default()
+    };
+    ctx.clear_handlers();
+    ctx.use_handler(RawHandler::raw(Box::new(|ctx, _, _, _| {
+        if ctx.msg_cycles_available() < 101_270_000 {
+            return Err((
+                RejectionCode::CanisterReject,
+                "Insufficient cycles for the allocations.".into(),
+            ));
+        }
+        ctx.msg_cycles_accept(101_270_000);
+        Ok(encode_args((WithCanisterId {
+            canister_id: mock_principals::xtc(),
+        },))
+        .unwrap())
+    })));
+
+    create_canister(CreateCanisterArgs {
+        cycles: 200_000_000,
+        controller: None,
+        settings: Some(allocations()),
+        from_subaccount: None,
+    })
+    .await
+    .unwrap();
+    ctx.call_state_reset();
+    let balance = 10_000_000_000_000
+        - (1_000 + compute_fee(FeeOperation::CreateCanister, 1_000))
+        - (101_270_000 + compute_fee(FeeOperation::CreateCanister, 101_270_000));
+    assert_eq!(
+        ctx.get::<Ledger>()
+            .balance(&mock_principals::alice().into()),
+        balance
+    );
+
+    // The cycles of a rejected creation are refunded.
+    create_canister(CreateCanisterArgs {
+        cycles: 101_269_999,
+        controller: None,
+        settings: Some(allocations()),
+        from_subaccount: None,
+    })
+    .await
+    .err()
+    .expect("Expected Err response.");
+    ctx.call_state_reset();
+    let balance = balance - compute_fee(FeeOperation::CreateCanister, 101_269_999);
+    assert_eq!(
+        ctx.get::<Ledger>()
+            .balance(&mock_principals::alice().into()),
+        balance
+    );
+
+    let invalid = vec![
+        (
+            Some(mock_principals::bob()),
+            WalletCanisterSettings {
+                controller: Some(mock_principals::alice()),
+                ..Default::default()
+            },
+        ),
+        (
+            None,
+            WalletCanisterSettings {
+                controllers: Some(vec![]),
+                ..Default::default()
+            },
+        ),
+        (
+            None,
+            WalletCanisterSettings {
+                controllers: Some(vec![mock_principals::bob(), mock_principals::bob()]),
+                ..Default::default()
+            },
+        ),
+        (
+            None,
+            WalletCanisterSettings {
+                compute_allocation: Some(Nat::from(101)),
+                ..Default::default()
+            },
+        ),
+        (
+            None,
+            WalletCanisterSettings {
+                memory_allocation: Some(Nat::from(u64::MAX)),
+                ..Default::default()
+            },
+        ),
+    ];
+
+    for (controller, settings) in invalid {
+        create(controller, settings)
+            .await
+            .err()
+            .expect("Expected Err response.");
+    }
+
+    // The invalid settings are rejected before anything is charged.
+    assert_eq!(
+        ctx.get::<Ledger>()
+            .balance(&mock_principals::alice().into()),
+        balance
+    );
+}
+
+/// A management canister creating the canister bob, installing the code fails if install_fails
+/// is set and draining it fails if drain_fails is set. The called methods are recorded in calls.
+fn mock_deploy(
+    install_fails: bool,
+    drain_fails: bool,
+    calls: Rc<RefCell<Vec<String>>>,
+) -> RawHandler {
+    RawHandler::raw(Box::new(move |ctx, _, _, method| {
+        let first_install = !calls.borrow().iter().any(|m| m == "install_code");
+        calls.borrow_mut().push(method.to_string());
+        let reply = match method {
+            "create_canister" => {
+                ctx.msg_cycles_accept(u64::MAX);
+                encode_args((WithCanisterId {
+                    canister_id: mock_principals::bob(),
+                },))
+            }
+            "install_code" if install_fails && first_install => {
+                return Err((
+                    RejectionCode::CanisterError,
+                    "Canister trapped during init.".into(),
+                ))
+            }
+            "drain" if drain_fails => encode_args((Err::<(u64, u64), _>("Out of cycles."),)),
+            "drain" => {
+                crate::canisters::Drains::credit(&mock_principals::bob(), 900_000_000_000);
+                encode_args((Ok::<_, String>((901_000_000_000u64, 900_000_000_000u64)),))
+            }
+            "remove_controller" => {
+                #[derive(CandidType)]
+                enum WalletResult {
+                    Ok(()),
+                }
+                encode_args((WalletResult::Ok(()),))
+            }
+            _ => encode_args(()),
+        };
+        Ok(reply.unwrap())
+    }))
+}
+
+#[async_test]
+async fn create_and_install() {
+    use crate::canisters::*;
+    use crate::history::{get_transaction, HistoryBuffer, TransactionKind, TransactionStatus};
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+    reset_ledger(ctx);
+
+    let args = || CreateAndInstallArgs {
+        cycles: 1_000_000_000_000,
+        settings: None,
+        wasm_module: b"\0asm".to_vec(),
+        arg: vec![],
+        from_subaccount: None,
+    };
+    let fee = compute_fee(FeeOperation::CreateCanister, 1_000_000_000_000);
+    let module_hash = [
+        0xcd, 0x5d, 0x49, 0x35, 0xa4, 0x8c, 0x06, 0x72, 0xcb, 0x06, 0x40, 0x7b, 0xb4, 0x43, 0xbc,
+        0x00, 0x87, 0xaf, 0xf9, 0x47, 0xc6, 0xb8, 0x64, 0xba, 0xc8, 0x86, 0x98, 0x2c, 0x73, 0xb3,
+        0x02, 0x7f,
+    ];
+
+    let calls = Rc::new(RefCell::new(vec![]));
+    ctx.use_handler(mock_deploy(false, false, calls.clone()));
+    let canister_id = wallet_create_and_install(args()).await.unwrap().canister_id;
+    ctx.call_state_reset();
+
+    assert_eq!(canister_id, mock_principals::bob());
+    assert_eq!(
+        *calls.borrow(),
+        vec!["create_canister", "install_code", "update_settings"]
+    );
+    assert_eq!(
+        ctx.get::<Ledger>()
+            .balance(&mock_principals::alice().into()),
+        10_000_000_000_000 - 1_000_000_000_000 - fee
+    );
+
+    let id = ctx.get::<HistoryBuffer>().len() - 1;
+    let event = get_transaction(id).await.unwrap();
+    assert_eq!(event.status, TransactionStatus::SUCCEEDED);
+    assert_eq!(
+        event.kind,
+        TransactionKind::CanisterDeployed {
+            from: mock_principals::alice(),
+            canister: mock_principals::bob(),
+            module_hash,
+            from_subaccount: None,
+        }
+    );
+
+    // A failed installation deletes the canister, its cycles come back through wallet_receive.
+    reset_ledger(ctx);
+    let calls = Rc::new(RefCell::new(vec![]));
+    ctx.clear_handlers();
+    ctx.use_handler(mock_deploy(true, false, calls.clone()));
+    wallet_create_and_install(args())
+        .await
+        .err()
+        .expect("Expected Err response.");
+
+    assert_eq!(
+        *calls.borrow(),
+        vec![
+            "create_canister",
+            "install_code",
+            "install_code",
+            "drain",
+            "stop_canister",
+            "delete_canister"
+        ]
+    );
+    let id = ctx.get::<HistoryBuffer>().len() - 1;
+    let event = get_transaction(id).await.unwrap();
+    assert_eq!(event.status, TransactionStatus::FAILED);
+
+    // Only the cycles that were not drained back are charged, along with their fee.
+    let consumed = 1_000_000_000_000 - 900_000_000_000;
+    let consumed_fee = compute_fee(FeeOperation::CreateCanister, consumed);
+    assert_eq!(event.cycles, consumed);
+    assert_eq!(event.fee, consumed_fee);
+    assert_eq!(
+        ctx.get::<Ledger>()
+            .balance(&mock_principals::alice().into()),
+        10_000_000_000_000 - consumed - consumed_fee
+    );
+
+    // A canister that can not be drained is handed over with its cycles instead of deleted.
+    reset_ledger(ctx);
+    let calls = Rc::new(RefCell::new(vec![]));
+    ctx.clear_handlers();
+    ctx.use_handler(mock_deploy(true, true, calls.clone()));
+    let error = wallet_create_and_install(args())
+        .await
+        .err()
+        .expect("Expected Err response.");
+
+    assert!(error.contains(&mock_principals::bob().to_text()));
+    assert_eq!(
+        *calls.borrow(),
+        vec![
+            "create_canister",
+            "install_code",
+            "install_code",
+            "drain",
+            "update_settings"
+        ]
+    );
+    assert_eq!(
+        ctx.get::<Ledger>()
+            .balance(&mock_principals::alice().into()),
+        10_000_000_000_000 - 1_000_000_000_000 - fee
+    );
+    let report = canister_cost_report(mock_principals::bob()).unwrap();
+    assert_eq!(report.initial_cycles, 1_000_000_000_000);
+
+    let id = ctx.get::<HistoryBuffer>().len() - 1;
+    let event = get_transaction(id).await.unwrap();
+    assert_eq!(event.status, TransactionStatus::FAILED);
+    assert_eq!(event.cycles, 1_000_000_000_000);
+    assert_eq!(event.fee, fee);
+}
+
+#[async_test]
+async fn create_wallet() {
+    use crate::cycles_wallet::*;
+    use crate::history::{get_transaction, HistoryBuffer, TransactionKind};
+
+    let ctx = MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+    reset_ledger(ctx);
+
+    let calls = Rc::new(RefCell::new(vec![]));
+    ctx.use_handler(mock_deploy(false, false, calls.clone()));
+    let canister_id = wallet_create_wallet(CreateCanisterArgs {
+        cycles: 1_000_000_000_000,
+        controller: None,
+        settings: None,
+        from_subaccount: None,
+    })
+    .await
+    .unwrap()
+    .canister_id;
+
+    assert_eq!(canister_id, mock_principals::bob());
+    assert_eq!(
+        *calls.borrow(),
+        vec![
+            "create_canister",
+            "install_code",
+            "add_controller",
+            "remove_controller",
+            "update_settings"
+        ]
+    );
+
+    let id = ctx.get::<HistoryBuffer>().len() - 1;
+    match get_transaction(id).await.unwrap().kind {
+        TransactionKind::CanisterDeployed {
+            from,
+            canister,
+            module_hash,
+            ..
+        } => {
+            assert_eq!(from, mock_principals::alice());
+            assert_eq!(canister, mock_principals::bob());
+            assert_eq!(module_hash.to_vec(), wallet_module_hash().into_vec());
+        }
+        _ => panic!("Expected CanisterDeployed."),
+    }
+}
@@ -1,12 +1,20 @@
+use crate::canisters::CanisterRegistry;
+use crate::fee::{FeeCollector, FeeSchedule};
 use crate::history::HistoryBuffer;
-use crate::ledger::{Ledger, UsedBlocks, UsedMapBlocks};
-use crate::management;
+use crate::icp_mint::{IcpConfig, IcpMints, IcpRateCache};
+use crate::icrc1::{RecentTransaction, RecentTransactions};
+use crate::ledger::{AllowanceEntry, Ledger, UsedBlocks};
+use crate::management::{self, AuditLog, PauseFlags, PendingController, Roles};
+use crate::memory;
+use crate::proposals::Proposals;
 use crate::stats::{StatsData, StatsDataV0};
-use ic_kit::candid::CandidType;
+use ic_kit::candid::{decode_one, encode_one, CandidType};
 use ic_kit::macros::*;
 use ic_kit::{ic, Context, Principal};
 use serde::Deserialize;
-use xtc_history::data::{HistoryArchive, HistoryArchiveBorrowed, HistoryArchiveV0};
+use std::collections::{HashMap, HashSet};
+use xtc_history::data::{HistoryArchiveV0, HistoryState};
+use xtc_stable::StableCell;
 
 #[derive(CandidType, Deserialize)]
 struct StableStorageV0 {
@@ -14,48 +22,172 @@ struct StableStorageV0 {
     history: HistoryArchiveV0,
     controller: Principal,
     stats: StatsDataV0,
-    used_blocks: UsedBlocks,
-    used_map_blocks: UsedMapBlocks,
+    used_blocks: HashSet<u64>,
+    used_map_blocks: HashMap<u64, u64>,
 }
 
-#[derive(CandidType)]
-struct StableStorageBorrowed<'h> {
-    ledger: Vec<(Principal, u64)>,
-    history: HistoryArchiveBorrowed<'h, 'h>,
+/// The heap state of the ledger.
+#[derive(CandidType, Deserialize)]
+struct LedgerState {
+    allowances: Vec<AllowanceEntry>,
+    history: HistoryState,
     controller: Principal,
     stats: StatsData,
-    used_blocks: &'static UsedBlocks,
-    used_map_blocks: &'static UsedMapBlocks,
 }
 
+/// The fee settings.
 #[derive(CandidType, Deserialize)]
-struct StableStorage {
-    ledger: Vec<(Principal, u64)>,
-    history: HistoryArchive,
-    controller: Principal,
-    stats: StatsData,
+struct FeeState {
+    collector: FeeCollector,
+    schedule: FeeSchedule,
+}
+
+/// The privileges and the safeguards of the administration.
+#[derive(CandidType, Deserialize)]
+struct AdminState {
+    pending_controller: Option<Principal>,
+    roles: Roles,
+    pause_flags: PauseFlags,
+}
+
+/// The state of the mints paid with ICP.
+#[derive(CandidType, Deserialize)]
+struct IcpState {
+    mints: IcpMints,
+    config: IcpConfig,
+    rate: IcpRateCache,
+}
+
+/// Since V1 the balances, the used blocks, the history events, the audit trail, the proposals
+/// and the created canisters live in their own regions of the stable memory, and only the state
+/// kept on the heap is serialized on upgrades.
+#[derive(CandidType, Deserialize)]
+struct StableStorageV1 {
+    ledger: LedgerState,
+    fees: FeeState,
+    admin: AdminState,
+    icp: IcpState,
+    recent_transactions: Vec<RecentTransaction>,
+}
+
+impl StableStorageV0 {
+    /// Move the data of the whole-state archive into the regions of the stable memory, this
+    /// overwrites the archive itself.
+    fn into_stable_memory(self) -> StableStorageV1 {
+        memory::format();
+
+        // The balances are keyed by account since V1, the ones of V0 belong to the default
+        // accounts.
+        Ledger::default().load(
+            self.ledger
+                .into_iter()
+                .map(|(principal, balance)| (principal.into(), balance))
+                .collect(),
+        );
+
+        UsedBlocks::default().load_v0(self.used_blocks, self.used_map_blocks);
+
+        let mut history = HistoryBuffer::default();
+        history.load(self.history.into());
+
+        StableStorageV1 {
+            ledger: LedgerState {
+                // The allowances were not persisted by V0.
+                allowances: Vec::new(),
+                history: history.state(),
+                controller: self.controller,
+                stats: self.stats.into(),
+            },
+            // The fees were burned at a hardcoded rate by V0.
+            fees: FeeState {
+                collector: FeeCollector::default(),
+                schedule: FeeSchedule::default(),
+            },
+            // Only the controller had privileges in V0, and halting was cleared by upgrades.
+            admin: AdminState {
+                pending_controller: None,
+                roles: Roles::default(),
+                pause_flags: PauseFlags::default(),
+            },
+            // V0 did not keep the state of the mints paid with ICP.
+            icp: IcpState {
+                mints: IcpMints::default(),
+                config: IcpConfig::default(),
+                rate: IcpRateCache::default(),
+            },
+            recent_transactions: Vec::new(),
+        }
+    }
+}
+
+/// The stable storage tagged with the version of its layout, so post_upgrade can always tell
+/// which layout it is reading. V0 is the untagged whole-state archive written to the start of
+/// the stable memory, the later versions are written to the STATE region.
+///
+/// To change the layout, add a new StableStorageVn struct together with a From migration
+/// from the previous version, add its variant here and make the previous variant migrate to
+/// it.
+#[derive(CandidType, Deserialize)]
+enum VersionedStableStorage {
+    V0(StableStorageV0),
+    V1(StableStorageV1),
+}
+
+impl VersionedStableStorage {
+    /// Read the stable storage, a stable memory that is not formatted still holds the archive
+    /// of V0.
+    fn restore() -> Self {
+        if memory::is_formatted() {
+            let data = StableCell::new(memory::region(memory::STATE))
+                .get()
+                .expect("Failed to read from stable storage.");
+            return decode_one(&data).expect("Failed to read from stable storage.");
+        }
+
+        let (stable,) = ic::stable_restore::<(StableStorageV0,)>()
+            .expect("Failed to read from stable storage.");
+        VersionedStableStorage::V0(stable)
+    }
+
+    /// Run the chain of migrations up to the latest version.
+    fn migrate(self) -> StableStorageV1 {
+        match self {
+            VersionedStableStorage::V0(stable) => {
+                VersionedStableStorage::V1(stable.into_stable_memory()).migrate()
+            }
+            VersionedStableStorage::V1(stable) => stable,
+        }
+    }
 }
 
 #[pre_upgrade]
 pub fn pre_upgrade() {
-    let ledger = ic::get_mut::<Ledger>().archive();
-    let history = ic::get_mut::<HistoryBuffer>().archive();
-    let controller = management::Controller::get_principal();
-
-    let used_blocks = ic::get_mut::<UsedBlocks>();
-    let used_map_blocks = ic::get_mut::<UsedMapBlocks>();
-
-    let stable = StableStorageBorrowed {
-        ledger,
-        history,
-        controller,
-        stats: StatsData::get(),
-        used_blocks,
-        used_map_blocks,
+    let stable = StableStorageV1 {
+        ledger: LedgerState {
+            allowances: ic::get_mut::<Ledger>().archive_allowances(),
+            history: ic::get::<HistoryBuffer>().state(),
+            controller: management::Controller::get_principal(),
+            stats: StatsData::get(),
+        },
+        fees: FeeState {
+            collector: FeeCollector::get(),
+            schedule: FeeSchedule::get(),
+        },
+        admin: AdminState {
+            pending_controller: PendingController::get(),
+            roles: Roles::get(),
+            pause_flags: PauseFlags::get(),
+        },
+        icp: IcpState {
+            mints: IcpMints::get(),
+            config: IcpConfig::get(),
+            rate: IcpRateCache::get(),
+        },
+        recent_transactions: RecentTransactions::archive(),
     };
 
-    match ic::stable_store((stable,)) {
-        Ok(_) => (),
+    match encode_one(VersionedStableStorage::V1(stable)) {
+        Ok(data) => StableCell::new(memory::region(memory::STATE)).set(&data),
         Err(candid_err) => {
             panic!(
                 "An error occurred when saving to stable memory (pre_upgrade): {:?}",
@@ -67,12 +199,171 @@ pub fn pre_upgrade() {
 
 #[post_upgrade]
 pub fn post_upgrade() {
-    let (stable,) =
-        ic::stable_restore::<(StableStorageV0,)>().expect("Failed to read from stable storage.");
-    ic::get_mut::<Ledger>().load(stable.ledger);
-    ic::get_mut::<HistoryBuffer>().load(stable.history.into());
-    management::Controller::load(stable.controller);
-    StatsData::load(stable.stats.into());
-    ic::store::<UsedBlocks>(stable.used_blocks);
-    ic::store::<UsedMapBlocks>(stable.used_map_blocks);
+    let stable = VersionedStableStorage::restore().migrate();
+
+    // The data in the stable memory has to be loaded before anything touches the defaults,
+    // which would start over with empty regions.
+    ic::store(Ledger::restore());
+    ic::store(HistoryBuffer::restore(stable.ledger.history));
+    ic::store(UsedBlocks::restore());
+    ic::store(AuditLog::restore());
+    ic::store(Proposals::restore());
+    ic::store(CanisterRegistry::restore());
+
+    ic::get_mut::<Ledger>().load_allowances(stable.ledger.allowances);
+    management::Controller::load(stable.ledger.controller);
+    StatsData::load(stable.ledger.stats);
+    FeeCollector::load(stable.fees.collector);
+    FeeSchedule::load(stable.fees.schedule);
+    PendingController::load(stable.admin.pending_controller);
+    Roles::load(stable.admin.roles);
+    PauseFlags::load(stable.admin.pause_flags);
+    IcpMints::load(stable.icp.mints);
+    IcpConfig::load(stable.icp.config);
+    IcpRateCache::load(stable.icp.rate);
+    RecentTransactions::load(stable.recent_transactions);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common_types::{Account, Subaccount};
+    use ic_kit::{mock_principals, MockContext};
+
+    #[test]
+    fn migrate_untagged_v0() {
+        let ctx = MockContext::new().inject();
+
+        let stable = StableStorageV0 {
+            ledger: vec![(mock_principals::alice(), 1_000)],
+            history: HistoryArchiveV0 {
+                offset: 0,
+                events: vec![],
+                buckets: vec![],
+            },
+            controller: mock_principals::bob(),
+            stats: StatsDataV0::default(),
+            used_blocks: vec![3, 64, 130].into_iter().collect(),
+            used_map_blocks: vec![(3, 4)].into_iter().collect(),
+        };
+        ctx.stable_store((stable,)).unwrap();
+
+        post_upgrade();
+
+        let ledger = ctx.get::<Ledger>();
+        assert_eq!(ledger.balance(&mock_principals::alice().into()), 1_000);
+
+        // The blocks used by V0 are archived.
+        let used_blocks = ctx.get::<UsedBlocks>();
+        assert!(used_blocks.contains(&3));
+        assert!(!used_blocks.contains(&4));
+        assert_eq!(used_blocks.send_block(&3), Some(4));
+        assert_eq!(used_blocks.heights(), vec![3, 64, 130]);
+        assert_eq!(
+            management::Controller::get_principal(),
+            mock_principals::bob()
+        );
+
+        // The next upgrade only writes the heap state, the balances stay in the stable memory.
+        pre_upgrade();
+        match VersionedStableStorage::restore() {
+            VersionedStableStorage::V1(stable) => {
+                assert_eq!(stable.ledger.controller, mock_principals::bob());
+            }
+            _ => panic!("Expected the latest version."),
+        }
+        assert_eq!(
+            Ledger::restore().balance(&mock_principals::alice().into()),
+            1_000
+        );
+    }
+
+    #[test]
+    fn allowances_survive_upgrades_from_v0() {
+        let ctx = MockContext::new().inject();
+
+        let stable = StableStorageV0 {
+            ledger: vec![(mock_principals::alice(), 1_000)],
+            history: HistoryArchiveV0 {
+                offset: 0,
+                events: vec![],
+                buckets: vec![],
+            },
+            controller: mock_principals::bob(),
+            stats: StatsDataV0::default(),
+            used_blocks: HashSet::new(),
+            used_map_blocks: HashMap::new(),
+        };
+        ctx.stable_store((stable,)).unwrap();
+        post_upgrade();
+
+        let alice: Account = mock_principals::alice().into();
+        let bob: Account = mock_principals::bob().into();
+        let john = Account::new(mock_principals::john(), Some(Subaccount([1; 32])));
+        let expires_at = ic::time() + 1_000;
+        {
+            let ledger = ctx.get_mut::<Ledger>();
+            ledger.set_allowance(&alice, &bob, 500, None);
+            ledger.set_allowance(&alice, &john, 200, Some(expires_at));
+        }
+
+        pre_upgrade();
+        // Start over with an empty heap, like the new module would.
+        ctx.store(Ledger::restore());
+        post_upgrade();
+
+        let ledger = ctx.get::<Ledger>();
+        assert_eq!(ledger.balance(&alice), 1_000);
+        assert_eq!(ledger.allowance(&alice, &bob), 500);
+        assert_eq!(ledger.allowance_expiration(&alice, &bob), None);
+        assert_eq!(ledger.allowance(&alice, &john), 200);
+        assert_eq!(ledger.allowance_expiration(&alice, &john), Some(expires_at));
+    }
+
+    #[test]
+    fn stable_regions_survive_upgrades() {
+        let ctx = MockContext::new()
+            .with_caller(mock_principals::bob())
+            .inject();
+
+        let stable = StableStorageV0 {
+            ledger: vec![],
+            history: HistoryArchiveV0 {
+                offset: 0,
+                events: vec![],
+                buckets: vec![],
+            },
+            controller: mock_principals::bob(),
+            stats: StatsDataV0::default(),
+            used_blocks: HashSet::new(),
+            used_map_blocks: HashMap::new(),
+        };
+        ctx.stable_store((stable,)).unwrap();
+        post_upgrade();
+
+        AuditLog::record(management::AdminAction::Halt);
+        ctx.get_mut::<CanisterRegistry>().insert(
+            mock_principals::bob(),
+            mock_principals::xtc(),
+            1_000,
+            0,
+        );
+        crate::proposals::create_proposal(crate::proposals::ProposalAction::SetIcpRateMaxAge {
+            max_age: 1,
+        })
+        .unwrap();
+
+        pre_upgrade();
+        post_upgrade();
+
+        let audit_log = management::get_audit_log(0, 10);
+        assert_eq!(audit_log.len(), 1);
+        assert_eq!(audit_log[0].action, management::AdminAction::Halt);
+        let canisters = ctx
+            .get::<CanisterRegistry>()
+            .created_by(&mock_principals::bob());
+        assert_eq!(canisters.len(), 1);
+        assert_eq!(canisters[0].initial_cycles, 1_000);
+        assert_eq!(crate::proposals::get_proposals(0, 10).len(), 1);
+    }
 }